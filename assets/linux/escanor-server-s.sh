@@ -1,2 +0,0 @@
-#!/bin/sh
-pkexec /usr/bin/escanor-server
\ No newline at end of file
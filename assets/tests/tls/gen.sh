@@ -0,0 +1,18 @@
+#!/bin/sh
+# Regenerates the certificates used by the TLS tests, run from this directory
+set -e
+
+openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out ca.key
+openssl req -x509 -new -key ca.key -sha256 -days 36500 -subj "/CN=Escanor Test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out ca.crt
+
+for name in server client; do
+    if [ $name = server ]; then eku=serverAuth; else eku=clientAuth; fi
+    openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out $name.key
+    openssl req -new -key $name.key -subj "/CN=escanor-$name" -out $name.csr
+    printf "basicConstraints=CA:FALSE\nkeyUsage=critical,digitalSignature,keyEncipherment\nextendedKeyUsage=%s\nsubjectAltName=DNS:localhost,IP:127.0.0.1\n" $eku > $name.ext
+    openssl x509 -req -in $name.csr -CA ca.crt -CAkey ca.key -CAcreateserial -days 36500 -sha256 -extfile $name.ext -out $name.crt
+    rm $name.csr $name.ext
+done
+
+rm -f ca.srl ca.key
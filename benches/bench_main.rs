@@ -1,6 +1,48 @@
+#[macro_use]
+extern crate log;
+// `json!` for the tests of the server modules, when they're compiled with cfg(test)
+#[allow(unused_imports)]
+#[macro_use]
+extern crate serde_json;
+
 use criterion::criterion_main;
 mod benchmarks;
 
+// server modules compiled in as is, the server is a binary crate
+#[allow(dead_code, unused_imports)]
+#[path = "../src/bin/server/compact.rs"]
+mod compact;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/bin/server/snapshot.rs"]
+mod snapshot;
+
+// stand-ins for the other server types the snapshot records name, the benchmarks only write JSON
+#[allow(dead_code)]
+mod db {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub enum ESValue {
+        String(String),
+        Int(i64),
+    }
+}
+
+mod geo {
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash)]
+    pub struct GeoPoint2D;
+}
+
+mod index {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub struct IndexDef;
+}
+
+mod schema {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub struct SchemaDef;
+}
+
 criterion_main! {
-    benchmarks::util::benches
-}
\ No newline at end of file
+    benchmarks::util::benches,
+    benchmarks::snapshot::benches,
+    benchmarks::compact::benches
+}
@@ -0,0 +1,56 @@
+use std::sync::atomic::Ordering;
+
+use criterion::{criterion_group, Criterion};
+use json_dotpath::DotPaths;
+use serde_json::Value;
+
+use super::snapshot::{dataset_size, ALLOCATED};
+use crate::compact::CompactValue;
+
+// a small document, the kind the store holds millions of
+fn document(i: usize) -> Value {
+    serde_json::json!({
+        "name" : format!("user {}", i),
+        "email" : format!("user{}@example.com", i),
+        "age" : i % 90,
+        "active" : i % 2 == 0,
+        "address" : { "city" : "Accra", "zip" : format!("{:05}", i % 100_000) },
+        "tags" : ["one", "two", "three"]
+    })
+}
+
+// heap bytes still allocated after `build` returned
+fn live_bytes<T, F: FnOnce() -> T>(build: F) -> (T, usize) {
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    let built = build();
+    (built, ALLOCATED.load(Ordering::Relaxed).saturating_sub(base))
+}
+
+fn compact_benchmark(c: &mut Criterion) {
+    let size = dataset_size();
+    let (trees, tree_bytes) = live_bytes(|| (0..size).map(document).collect::<Vec<Value>>());
+    let (tapes, tape_bytes) = live_bytes(|| trees.iter().map(CompactValue::from_value).collect::<Vec<CompactValue>>());
+    println!("{} documents: tree {} MB ({} bytes each), compact {} MB ({} bytes each)",
+             size, tree_bytes / (1024 * 1024), tree_bytes / size.max(1),
+             tape_bytes / (1024 * 1024), tape_bytes / size.max(1));
+
+    let (tree, tape) = (&trees[size / 2], &tapes[size / 2]);
+    let mut group = c.benchmark_group("json_encoding");
+    group.bench_function("tree_get_path", |b| b.iter(|| tree.dot_get::<Value>("address.city").unwrap()));
+    group.bench_function("compact_get_path", |b| b.iter(|| tape.get(&["address", "city"])));
+    group.bench_function("compact_decode", |b| b.iter(|| tape.to_value()));
+    // JSET copies a tree before setting paths, a tape is spliced
+    group.bench_function("tree_set_path", |b| b.iter(|| {
+        let mut doc = tree.clone();
+        doc.dot_set("address.zip", "00233").unwrap();
+        doc
+    }));
+    group.bench_function("compact_set_path", |b| b.iter(|| {
+        let mut doc = tape.clone();
+        doc.set(&["address", "zip"], &Value::from("00233"));
+        doc
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, compact_benchmark);
@@ -1 +1,3 @@
-pub mod util;
\ No newline at end of file
+pub mod util;
+pub mod snapshot;
+pub mod compact;
@@ -0,0 +1,119 @@
+extern crate dashmap;
+extern crate rmp_serde;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, Criterion};
+use dashmap::DashMap;
+
+use crate::compact::JsonDoc;
+use crate::snapshot::{self, RecordRef};
+
+// Tracks live and peak heap usage so both save strategies can be compared.
+struct CountingAlloc;
+
+pub static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// number of json documents, raise it with ESCANOR_BENCH_KEYS for multi-GB runs
+pub fn dataset_size() -> usize {
+    std::env::var("ESCANOR_BENCH_KEYS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100_000)
+}
+
+fn build_dataset(size: usize) -> Arc<DashMap<String, JsonDoc>> {
+    let map = Arc::new(DashMap::new());
+    for i in 0..size {
+        map.insert(format!("user:{}", i), JsonDoc::Tree(serde_json::json!({
+            "name" : format!("user {}", i),
+            "age" : i % 90,
+            "tags" : ["one", "two", "three"],
+            "bio" : "x".repeat(256)
+        })));
+    }
+    map
+}
+
+// what save_db did before: clone every map and encode the copy in one buffer
+fn save_with_clone(map: &DashMap<String, JsonDoc>) -> usize {
+    let mut copy = DashMap::<String, JsonDoc>::new();
+    copy.clone_from(map);
+    rmp_serde::encode::to_vec(&copy).unwrap().len()
+}
+
+// what save_db does now: stream entries shard by shard to a buffered sink
+fn save_streaming(map: &DashMap<String, JsonDoc>) -> usize {
+    let mut writer = std::io::BufWriter::new(std::io::sink());
+    snapshot::write_map(&mut writer, map, |k, v| RecordRef::Json(k, v)).unwrap()
+}
+
+// runs `save` while another thread keeps writing, returns (extra peak bytes, worst write latency)
+fn measure(map: &Arc<DashMap<String, JsonDoc>>, save: fn(&DashMap<String, JsonDoc>) -> usize) -> (usize, Duration) {
+    let running = Arc::new(AtomicBool::new(true));
+    let writer = {
+        let map = map.clone();
+        let running = running.clone();
+        std::thread::spawn(move || {
+            let mut worst = Duration::from_secs(0);
+            let mut i = 0;
+            while running.load(Ordering::Relaxed) {
+                let instant = Instant::now();
+                map.insert(format!("user:{}", i % 1000), JsonDoc::Tree(serde_json::json!({"name" : "writer"})));
+                worst = worst.max(instant.elapsed());
+                i += 1;
+            }
+            worst
+        })
+    };
+
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    save(map);
+    let peak = PEAK.load(Ordering::Relaxed).saturating_sub(base);
+
+    running.store(false, Ordering::Relaxed);
+    (peak, writer.join().unwrap())
+}
+
+fn snapshot_benchmark(c: &mut Criterion) {
+    let size = dataset_size();
+    let map = build_dataset(size);
+    println!("dataset: {} keys, {} MB live", size, ALLOCATED.load(Ordering::Relaxed) / (1024 * 1024));
+
+    for (name, save) in [("clone", save_with_clone as fn(&DashMap<String, JsonDoc>) -> usize),
+        ("streaming", save_streaming as fn(&DashMap<String, JsonDoc>) -> usize)].iter() {
+        let (peak, worst) = measure(&map, *save);
+        println!("{}: peak extra memory {} MB, worst write latency {:?}", name, peak / (1024 * 1024), worst);
+    }
+
+    let mut group = c.benchmark_group("snapshot");
+    group.sample_size(10);
+    group.bench_function("save_with_clone", |b| b.iter(|| save_with_clone(&map)));
+    group.bench_function("save_streaming", |b| b.iter(|| save_streaming(&map)));
+    group.finish();
+}
+
+criterion_group!(benches, snapshot_benchmark);
@@ -1,11 +1,69 @@
+use std::fs::File;
 use std::io::prelude::*;
-use std::io::{BufReader, ErrorKind, Result};
+use std::io::{BufReader, Error, ErrorKind, Result};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientSession, StreamOwned};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use webpki::DNSNameRef;
 
 use super::{Decoder, Value};
 
+/// Anything the connection can talk RESP over, a plain socket or a TLS session
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Options for connecting to a TLS listener, set from `--tls` and friends
+pub struct TlsOptions {
+    /// CA certificate the server certificate is verified against
+    pub ca_cert: String,
+    /// client certificate and key, sent when the server asks for one
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// name the server certificate must be valid for
+    pub server_name: String,
+}
+
+fn open_pem(path: &str) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn tls_config(options: &TlsOptions) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    match config.root_store.add_pem_file(&mut open_pem(&options.ca_cert)?) {
+        Ok((valid, _)) if valid > 0 => {}
+        _ => { return Err(invalid(format!("No CA certificate found in {}", options.ca_cert))); }
+    }
+
+    match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => {
+            let chain = certs(&mut open_pem(cert)?).map_err(|_| invalid(format!("Invalid certificate {}", cert)))?;
+            let mut keys = pkcs8_private_keys(&mut open_pem(key)?).unwrap_or_default();
+            if keys.is_empty() {
+                keys = rsa_private_keys(&mut open_pem(key)?).unwrap_or_default();
+            }
+            if keys.is_empty() {
+                return Err(invalid(format!("No private key found in {}", key)));
+            }
+            config.set_single_client_cert(chain, keys.remove(0))
+                .map_err(|e| invalid(format!("Invalid client certificate: {}", e)))?;
+        }
+        (None, None) => {}
+        _ => { return Err(invalid("--cert and --key must be used together".to_owned())); }
+    }
+    Ok(config)
+}
+
 pub struct Connection {
-    stream: BufReader<TcpStream>,
+    stream: BufReader<Box<dyn Stream>>,
     decoder: Decoder,
 }
 
@@ -13,7 +71,33 @@ impl Connection {
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
         let tcp = TcpStream::connect(addr)?;
         Ok(Connection {
-            stream: BufReader::new(tcp),
+            stream: BufReader::new(Box::new(tcp)),
+            decoder: Decoder::new(),
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn new_unix(path: &str) -> Result<Self> {
+        let socket = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(Connection {
+            stream: BufReader::new(Box::new(socket)),
+            decoder: Decoder::new(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new_unix(_path: &str) -> Result<Self> {
+        Err(Error::new(ErrorKind::Other, "Unix sockets are not supported on this platform"))
+    }
+
+    pub fn new_tls<A: ToSocketAddrs>(addr: A, options: &TlsOptions) -> Result<Self> {
+        let config = Arc::new(tls_config(options)?);
+        let server_name = DNSNameRef::try_from_ascii_str(&options.server_name)
+            .map_err(|_| invalid(format!("Invalid TLS server name {}, set one with --sni", options.server_name)))?;
+        let session = ClientSession::new(&config, server_name);
+        let tcp = TcpStream::connect(addr)?;
+        Ok(Connection {
+            stream: BufReader::new(Box::new(StreamOwned::new(session, tcp))),
             decoder: Decoder::new(),
         })
     }
@@ -79,4 +163,24 @@ mod tests {
             Value::Bulk("test_redis_cli_2".to_string())
         );
     }
+
+    #[test]
+    fn test_tls_config() {
+        let file = |name: &str| format!("{}/assets/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let mut options = TlsOptions {
+            ca_cert: file("ca.crt"),
+            cert: Some(file("client.crt")),
+            key: Some(file("client.key")),
+            server_name: "localhost".to_owned(),
+        };
+        assert!(tls_config(&options).is_ok());
+
+        options.key = None;
+        assert!(tls_config(&options).is_err());
+        options.cert = None;
+        assert!(tls_config(&options).is_ok());
+
+        options.ca_cert = file("missing.crt");
+        assert!(tls_config(&options).is_err());
+    }
 }
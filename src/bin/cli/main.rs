@@ -20,6 +20,8 @@ mod redis;
 mod connection;
 mod command;
 
+use connection::TlsOptions;
+
 use clap::{App, Arg};
 
 use std::str::FromStr;
@@ -72,6 +74,48 @@ fn main() -> io::Result<()> {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("socket")
+                .short("s")
+                .long("socket")
+                .help("Server socket (overrides hostname and port).")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Establish a secure TLS connection.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("cacert")
+                .long("cacert")
+                .help("CA Certificate file to verify the server with, required with --tls.")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .help("Client certificate to authenticate with.")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .help("Private key file to authenticate with.")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sni")
+                .long("sni")
+                .help("Server name the certificate must match (default: hostname, required for IP addresses).")
+                .required(false)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("command")
                 .help("command...")
@@ -98,15 +142,48 @@ fn main() -> io::Result<()> {
         hostname = _hostname;
     }
 
+    let socket = matches.value_of("socket");
+
+    let tls = if matches.is_present("tls") {
+        let ca_cert = match matches.value_of("cacert") {
+            Some(t) => t.to_owned(),
+            None => {
+                eprintln!("--cacert is required with --tls");
+                std::process::exit(1);
+            }
+        };
+        // certificates are issued for names, an IP hostname needs the name to check against
+        let server_name = match matches.value_of("sni") {
+            Some(t) => t.to_owned(),
+            None if hostname.parse::<std::net::IpAddr>().is_ok() => {
+                eprintln!("--sni is required with --tls when the host is an IP address");
+                std::process::exit(1);
+            }
+            None => hostname.to_owned(),
+        };
+        Some(TlsOptions {
+            ca_cert,
+            cert: matches.value_of("cert").map(|c| c.to_owned()),
+            key: matches.value_of("key").map(|k| k.to_owned()),
+            server_name,
+        })
+    } else {
+        None
+    };
+
     let mut interface = Interface::new("Escanor client 0.0.1")?;
     interface.set_report_signal(Signal::Interrupt, true);
 
     println!("escanor cli 1.0");
     println!("Press Ctrl+C or enter \"exit\" to quit. If not connect press enter to retry connection");
     loop {
-        match create_client(hostname, port, password, db) {
+        match create_client(hostname, port, password, db, tls.as_ref(), socket) {
             Ok(mut cli) => {
-                interface.set_prompt(&format!("{}:{}> ", hostname, port)).unwrap();
+                let prompt = match socket {
+                    Some(path) => format!("{}> ", path),
+                    None => format!("{}:{}> ", hostname, port),
+                };
+                interface.set_prompt(&prompt).unwrap();
                 let _ = run_program(&mut cli, &mut interface);
             }
             Err(_err) => {
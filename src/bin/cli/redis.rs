@@ -1,11 +1,15 @@
 use std::io::{Error, ErrorKind, Result};
 use std::net::ToSocketAddrs;
 
-use super::connection::Connection;
+use super::connection::{Connection, TlsOptions};
 use super::{encode_slice, Value};
 
-pub fn create_client(hostname: &str, port: u16, password: &str, db: u16) -> Result<Client> {
-    let mut client = Client::new((hostname, port))?;
+pub fn create_client(hostname: &str, port: u16, password: &str, db: u16, tls: Option<&TlsOptions>, socket: Option<&str>) -> Result<Client> {
+    let mut client = match (socket, tls) {
+        (Some(path), _) => Client::new_unix(path)?,
+        (None, None) => Client::new((hostname, port))?,
+        (None, Some(options)) => Client::new_tls((hostname, port), options)?,
+    };
     client.init(password, db)?;
     Ok(client)
 }
@@ -21,6 +25,18 @@ impl Client {
         })
     }
 
+    pub fn new_tls<A: ToSocketAddrs>(addrs: A, options: &TlsOptions) -> Result<Self> {
+        Ok(Client {
+            conn: Connection::new_tls(addrs, options)?,
+        })
+    }
+
+    pub fn new_unix(path: &str) -> Result<Self> {
+        Ok(Client {
+            conn: Connection::new_unix(path)?,
+        })
+    }
+
     pub fn cmd(&mut self, slice: &[&str]) -> Result<Value> {
         let buf = encode_slice(slice);
         match self.conn.write(&buf){
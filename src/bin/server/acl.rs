@@ -0,0 +1,589 @@
+//! Access control lists: named users with hashed passwords, the commands they may run and
+//! the keys they may touch. Rules use the Redis ACL syntax, e.g `on #<sha256> ~user:* +@read -@json-write`.
+//!
+//! The `default` user is the one clients get before AUTH, they are logged in straight away when
+//! it is on and has `nopass`. `server.require_auth` sets its password, like `requirepass` in Redis.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::RwLock;
+
+use glob::Pattern;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::command::*;
+use crate::network::Context;
+use crate::printer::*;
+
+pub const DEFAULT_USER: &str = "default";
+
+/// Every command with the categories it belongs to, `@all` covers all of them. Commands whose
+/// subcommands differ in what they do are listed per subcommand as `command|subcommand`.
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("ping", &["connection", "fast"]),
+    ("auth", &["connection", "fast"]),
+    ("client|id", &["connection", "fast"]),
+    ("client|getname", &["connection", "fast"]),
+    ("client|setname", &["connection", "fast"]),
+    ("client|info", &["connection"]),
+    ("client|list", &["connection", "admin", "dangerous"]),
+    ("client|kill", &["connection", "admin", "dangerous"]),
+    ("client|pause", &["connection", "admin", "dangerous"]),
+    ("acl", &["admin", "dangerous"]),
+    ("info", &["connection", "admin"]),
+    ("config", &["admin", "dangerous"]),
+    ("lastsave", &["admin", "fast"]),
+    ("save", &["admin", "dangerous"]),
+    ("bgsave", &["admin", "dangerous"]),
+    ("shutdown", &["admin", "dangerous"]),
+    ("replicaof", &["admin", "dangerous"]),
+    ("slaveof", &["admin", "dangerous"]),
+    ("psync", &["admin", "dangerous"]),
+    ("dbsize", &["read", "keyspace", "fast"]),
+    ("randomkey", &["read", "keyspace"]),
+    ("keys", &["read", "keyspace", "dangerous"]),
+    ("exists", &["read", "keyspace", "fast"]),
+    ("ttl", &["read", "keyspace", "fast"]),
+    ("dump", &["read", "keyspace"]),
+    ("del", &["write", "keyspace"]),
+    ("persist", &["write", "keyspace", "fast"]),
+    ("expire", &["write", "keyspace", "fast"]),
+    ("expire_at", &["write", "keyspace", "fast"]),
+    ("restore", &["write", "keyspace", "dangerous"]),
+    ("flushdb", &["write", "keyspace", "dangerous"]),
+    ("get", &["read", "string", "fast"]),
+    ("set", &["write", "string"]),
+    ("getset", &["write", "string", "fast"]),
+    ("geoadd", &["write", "geo"]),
+    ("geodel", &["write", "geo"]),
+    ("georem", &["write", "geo"]),
+    ("geojson", &["read", "geo"]),
+    ("geohash", &["read", "geo"]),
+    ("geopos", &["read", "geo"]),
+    ("geodist", &["read", "geo"]),
+    ("georadius", &["read", "geo"]),
+    ("georadiusbymember", &["read", "geo"]),
+    ("jsetr", &["write", "json", "json-write"]),
+    ("jset", &["write", "json", "json-write"]),
+    ("jmerge", &["write", "json", "json-write"]),
+    ("jdel", &["write", "json", "json-write"]),
+    ("jrem", &["write", "json", "json-write"]),
+    ("jincrby", &["write", "json", "json-write"]),
+    ("jincrbyfloat", &["write", "json", "json-write"]),
+    ("jpatch", &["write", "json", "json-write"]),
+    ("jschema|set", &["write", "json", "json-write"]),
+    ("jschema|del", &["write", "json", "json-write"]),
+    ("jschema|get", &["read", "json", "json-read"]),
+    ("jschema|list", &["read", "json", "json-read"]),
+    ("jschema|validate", &["read", "json", "json-read"]),
+    ("jpathset", &["write", "json", "json-write"]),
+    ("jpathdel", &["write", "json", "json-write"]),
+    ("jpathincrby", &["write", "json", "json-write"]),
+    ("jpathstrappend", &["write", "json", "json-write"]),
+    ("jpatharrappend", &["write", "json", "json-write"]),
+    ("jpatharrinsert", &["write", "json", "json-write"]),
+    ("jpatharrpop", &["write", "json", "json-write"]),
+    ("jpatharrtrim", &["write", "json", "json-write"]),
+    ("jarrappend", &["write", "json", "json-write"]),
+    ("jarrinsert", &["write", "json", "json-write"]),
+    ("jarrpop", &["write", "json", "json-write"]),
+    ("jarrtrim", &["write", "json", "json-write"]),
+    ("jstrappend", &["write", "json", "json-write"]),
+    ("jarrlen", &["read", "json", "json-read"]),
+    ("jstrlen", &["read", "json", "json-read"]),
+    ("jobjkeys", &["read", "json", "json-read"]),
+    ("jobjlen", &["read", "json", "json-read"]),
+    ("jtype", &["read", "json", "json-read"]),
+    ("jget", &["read", "json", "json-read"]),
+    ("jpath", &["read", "json", "json-read"]),
+    ("jindex|create", &["write", "json", "json-write"]),
+    ("jindex|drop", &["write", "json", "json-write"]),
+    ("jindex|list", &["read", "json", "json-read"]),
+    ("jindex|info", &["read", "json", "json-read"]),
+    ("jquery", &["read", "json", "json-read"]),
+    ("jsearch", &["read", "json", "json-read"]),
+    ("jaggregate", &["read", "json", "json-read"]),
+];
+
+#[derive(Debug, Clone)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    /// SHA-256 hex digests of the accepted passwords
+    passwords: Vec<String>,
+    /// command rules in the order they were applied, for GETUSER and LIST
+    command_rules: Vec<String>,
+    commands: HashSet<&'static str>,
+    key_patterns: Vec<(String, Pattern)>,
+}
+
+/// Keys a user may access, for commands that find the keys they read themselves
+pub struct KeyFilter {
+    /// None when every key is allowed
+    patterns: Option<Vec<Pattern>>,
+}
+
+impl KeyFilter {
+    pub fn all() -> KeyFilter {
+        KeyFilter { patterns: None }
+    }
+
+    pub fn allows(&self, key: &str) -> bool {
+        match &self.patterns {
+            None => true,
+            Some(patterns) => patterns.iter().any(|pattern| pattern.matches(key)),
+        }
+    }
+}
+
+lazy_static! {
+    static ref USERS : RwLock<BTreeMap<String, User>> = RwLock::new(default_users());
+    /// `acl` section of the config file as last loaded, reloads only touch users when it changed
+    static ref CONFIG_USERS : RwLock<BTreeMap<String, String>> = RwLock::new(BTreeMap::new());
+    /// hash of `server.require_auth`, kept to set it again when the `acl` section is reloaded
+    static ref REQUIRE_AUTH_HASH : RwLock<Option<String>> = RwLock::new(None);
+}
+
+fn default_users() -> BTreeMap<String, User> {
+    let mut users = BTreeMap::new();
+    users.insert(DEFAULT_USER.to_owned(), User::default_user());
+    users
+}
+
+pub fn hash_password(password: &str) -> String {
+    format!("{:x}", Sha256::digest(password.as_bytes()))
+}
+
+/// Compares two password hashes without returning early, so timing doesn't tell how much matched
+fn hash_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn category_commands(category: &str) -> Option<Vec<&'static str>> {
+    let commands: Vec<&'static str> = COMMANDS.iter()
+        .filter(|(_, categories)| category == "all" || categories.contains(&category))
+        .map(|(name, _)| *name)
+        .collect();
+    if commands.is_empty() { None } else { Some(commands) }
+}
+
+fn command_name(name: &str) -> Option<&'static str> {
+    COMMANDS.iter().map(|(n, _)| *n).find(|n| *n == name)
+}
+
+/// The command itself or, for a command listed per subcommand, all of its subcommands
+fn command_names(name: &str) -> Option<Vec<&'static str>> {
+    if let Some(command) = command_name(name) {
+        return Some(vec![command]);
+    }
+    let prefix = format!("{}|", name);
+    let commands: Vec<&'static str> = COMMANDS.iter()
+        .map(|(n, _)| *n)
+        .filter(|n| n.starts_with(&prefix))
+        .collect();
+    if commands.is_empty() { None } else { Some(commands) }
+}
+
+/// Name to check permissions against, `command|subcommand` when the command is listed per subcommand
+pub fn permission_name(command: &str, subcommand: &str) -> String {
+    let name = format!("{}|{}", command, subcommand.to_lowercase());
+    match command_name(&name) {
+        Some(_) => name,
+        None => command.to_owned()
+    }
+}
+
+impl User {
+    /// A new user is disabled and can't run anything until rules say otherwise
+    pub fn new() -> User {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: vec![],
+            command_rules: vec![],
+            commands: HashSet::new(),
+            key_patterns: vec![],
+        }
+    }
+
+    fn default_user() -> User {
+        let mut user = User::new();
+        for rule in &["on", "nopass", "allcommands", "allkeys"] {
+            let _ = user.apply_rule(rule);
+        }
+        user
+    }
+
+    pub fn apply_rule(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => return self.apply_rule("~*"),
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" => return self.apply_rule("+@all"),
+            "nocommands" => return self.apply_rule("-@all"),
+            "reset" => *self = User::new(),
+            _ => {
+                let (prefix, value) = rule.split_at(rule.char_indices().nth(1).map(|(i, _)| i).unwrap_or(rule.len()));
+                match prefix {
+                    ">" => self.add_password_hash(hash_password(value)),
+                    "<" => {
+                        let hash = hash_password(value);
+                        self.passwords.retain(|p| *p != hash);
+                    }
+                    "#" => {
+                        let hash = value.to_lowercase();
+                        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                            return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_owned());
+                        }
+                        self.add_password_hash(hash);
+                    }
+                    "!" => {
+                        let hash = value.to_lowercase();
+                        self.passwords.retain(|p| *p != hash);
+                    }
+                    "~" => {
+                        let pattern = Pattern::new(value).map_err(|e| format!("Invalid key pattern: {}", e))?;
+                        if !self.key_patterns.iter().any(|(p, _)| p == value) {
+                            self.key_patterns.push((value.to_owned(), pattern));
+                        }
+                    }
+                    "+" | "-" => self.apply_command_rule(prefix == "+", &value.to_lowercase())?,
+                    _ => return Err("Syntax error".to_owned()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_password_hash(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn apply_command_rule(&mut self, allow: bool, name: &str) -> Result<(), String> {
+        let commands = if name.starts_with('@') {
+            category_commands(&name[1..])
+        } else {
+            command_names(name)
+        };
+        let commands = commands.ok_or("Unknown command or category name in ACL")?;
+        for command in commands {
+            if allow {
+                self.commands.insert(command);
+            } else {
+                self.commands.remove(command);
+            }
+        }
+        // +@all and -@all override everything before them
+        if name == "@all" {
+            self.command_rules.clear();
+        }
+        self.command_rules.push(format!("{}{}", if allow { "+" } else { "-" }, name));
+        Ok(())
+    }
+
+    fn key_filter(&self) -> KeyFilter {
+        if self.key_patterns.iter().any(|(p, _)| p == "*") {
+            return KeyFilter::all();
+        }
+        KeyFilter { patterns: Some(self.key_patterns.iter().map(|(_, pattern)| pattern.clone()).collect()) }
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.check_hash(&hash_password(password))
+    }
+
+    /// Every stored hash is compared, the time taken doesn't depend on which one matched
+    fn check_hash(&self, hash: &str) -> bool {
+        self.nopass || self.passwords.iter().fold(false, |found, p| hash_eq(p, hash) | found)
+    }
+
+    /// `server.require_auth` replaces the passwords, an empty one leaves the user without any
+    fn set_require_auth(&mut self, hash: Option<&str>) {
+        self.passwords.clear();
+        match hash {
+            Some(hash) => self.add_password_hash(hash.to_owned()),
+            None => self.nopass = true,
+        }
+    }
+
+    fn commands_description(&self) -> String {
+        if self.command_rules.is_empty() {
+            "-@all".to_owned()
+        } else {
+            self.command_rules.join(" ")
+        }
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.key_patterns.iter().any(|(p, _)| p == "*") {
+            flags.push("allkeys");
+        }
+        if self.commands.len() == COMMANDS.len() {
+            flags.push("allcommands");
+        }
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    /// The user as an ACL LIST line, without the leading `user <name>`
+    fn describe(&self) -> String {
+        let mut parts: Vec<String> = vec![if self.enabled { "on" } else { "off" }.to_owned()];
+        if self.nopass {
+            parts.push("nopass".to_owned());
+        }
+        parts.extend(self.passwords.iter().map(|p| format!("#{}", p)));
+        parts.extend(self.key_patterns.iter().map(|(p, _)| format!("~{}", p)));
+        parts.push(self.commands_description());
+        parts.join(" ")
+    }
+}
+
+/// Parses the `acl` config section, plaintext passwords are refused so the file only holds hashes
+fn parse_config_users(section: &BTreeMap<String, String>) -> Result<BTreeMap<String, User>, String> {
+    let mut users = BTreeMap::new();
+    for (name, rules) in section {
+        let mut user = if name == DEFAULT_USER { User::default_user() } else { User::new() };
+        for rule in rules.split_whitespace() {
+            if rule.starts_with('>') || rule.starts_with('<') {
+                return Err(format!("acl.{}: plaintext passwords are not allowed in the config file, use #<sha256 of the password>", name));
+            }
+            if let Err(e) = user.apply_rule(rule) {
+                return Err(format!("acl.{}: invalid rule '{}': {}", name, rule, e));
+            }
+        }
+        users.insert(name.to_owned(), user);
+    }
+    Ok(users)
+}
+
+#[derive(Deserialize)]
+struct AclSection {
+    #[serde(default)]
+    acl: Option<BTreeMap<String, String>>,
+}
+
+/// Loads the users of the `acl` section in the config file `contents`. Users created with
+/// ACL SETUSER are kept, users removed from the section are deleted
+pub fn load_from_yaml(contents: &[u8]) -> Result<(), String> {
+    let section: AclSection = match serde_yaml::from_slice(contents) {
+        Ok(t) => t,
+        Err(e) => { return Err(format!("Invalid acl section: {}", e)); }
+    };
+    let section = section.acl.unwrap_or_default();
+
+    let mut config_users = CONFIG_USERS.write().unwrap();
+    if *config_users == section {
+        return Ok(());
+    }
+    let loaded = parse_config_users(&section)?;
+
+    let mut users = USERS.write().unwrap();
+    for name in config_users.keys() {
+        if !section.contains_key(name) {
+            users.remove(name);
+        }
+    }
+    users.extend(loaded);
+    let default = users.entry(DEFAULT_USER.to_owned()).or_insert_with(User::default_user);
+    if let Some(hash) = REQUIRE_AUTH_HASH.read().unwrap().as_deref() {
+        default.set_require_auth(Some(hash));
+    }
+    info!("Loaded {} ACL users from config", section.len());
+    *config_users = section;
+    Ok(())
+}
+
+/// Sets the password of the `default` user from `server.require_auth`, empty for no password
+pub fn set_require_auth(password: &str) {
+    let hash = if password.is_empty() { None } else { Some(hash_password(password)) };
+    if let Some(default) = USERS.write().unwrap().get_mut(DEFAULT_USER) {
+        default.set_require_auth(hash.as_deref());
+    }
+    *REQUIRE_AUTH_HASH.write().unwrap() = hash;
+}
+
+/// Whether a connection of the `default` user may run commands, `password_hash` being the hash
+/// of the password it gave with AUTH. Read on every command so password changes apply at once.
+pub fn default_user_allowed(password_hash: Option<&str>) -> bool {
+    let users = USERS.read().unwrap();
+    match users.get(DEFAULT_USER) {
+        Some(user) if user.enabled => user.nopass || password_hash.map(|h| user.check_hash(h)).unwrap_or(false),
+        _ => false
+    }
+}
+
+/// Checks the password of a user, disabled users can't authenticate
+pub fn authenticate(name: &str, password: &str) -> bool {
+    let users = USERS.read().unwrap();
+    match users.get(name) {
+        Some(user) => user.enabled && user.check_password(password),
+        None => false
+    }
+}
+
+/// Checks that `name` may run `command` on `keys`, the error is the message to send back
+pub fn check_permission(name: &str, command: &str, keys: &[&str]) -> Result<(), String> {
+    let users = USERS.read().unwrap();
+    let user = match users.get(name) {
+        Some(user) if user.enabled => user,
+        _ => { return Err(format!("NOPERM user '{}' is disabled or no longer exists", name)); }
+    };
+    if !command_name(command).map(|c| user.commands.contains(c)).unwrap_or(false) {
+        return Err(format!("NOPERM this user has no permissions to run the '{}' command", command));
+    }
+    for key in keys {
+        if !user.key_patterns.iter().any(|(_, pattern)| pattern.matches(key)) {
+            return Err("NOPERM this user has no permissions to access one of the keys used as arguments".to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Keys `name` may access, none when the user is disabled or no longer exists
+pub fn key_filter(name: &str) -> KeyFilter {
+    let users = USERS.read().unwrap();
+    match users.get(name) {
+        Some(user) if user.enabled => user.key_filter(),
+        _ => KeyFilter { patterns: Some(vec![]) }
+    }
+}
+
+pub fn acl_setuser(cmd: &AclSetUserCmd) -> String {
+    let mut users = USERS.write().unwrap();
+    let mut user = users.get(&cmd.arg_user).cloned().unwrap_or_else(User::new);
+    for rule in &cmd.arg_rules {
+        if let Err(e) = user.apply_rule(rule) {
+            return print_err(&format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, e));
+        }
+    }
+    users.insert(cmd.arg_user.to_owned(), user);
+    print_ok()
+}
+
+pub fn acl_getuser(cmd: &AclGetUserCmd) -> String {
+    let users = USERS.read().unwrap();
+    let user = match users.get(&cmd.arg_user) {
+        Some(user) => user,
+        None => { return print_nil(); }
+    };
+    let mut reply = String::from("*8\r\n");
+    reply += &print_str("flags");
+    reply += &print_arr(user.flags());
+    reply += &print_str("passwords");
+    reply += &print_arr(user.passwords.clone());
+    reply += &print_str("commands");
+    reply += &print_string(&user.commands_description());
+    reply += &print_str("keys");
+    reply += &print_arr(user.key_patterns.iter().map(|(p, _)| p.to_owned()).collect());
+    reply
+}
+
+pub fn acl_deluser(cmd: &AclDelUserCmd) -> String {
+    if cmd.arg_users.iter().any(|u| u == DEFAULT_USER) {
+        return print_err("ERR The 'default' user cannot be removed");
+    }
+    let mut users = USERS.write().unwrap();
+    let deleted = cmd.arg_users.iter().filter(|u| users.remove(*u).is_some()).count();
+    print_integer(deleted as i64)
+}
+
+pub fn acl_list(_cmd: &AclListCmd) -> String {
+    let users = USERS.read().unwrap();
+    print_arr(users.iter().map(|(name, user)| format!("user {} {}", name, user.describe())).collect())
+}
+
+pub fn acl_whoami(context: &mut Context, _cmd: &AclWhoAmICmd) -> String {
+    print_string(&context.user.clone().unwrap_or_else(|| DEFAULT_USER.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(rules: &str) -> User {
+        let mut user = User::new();
+        for rule in rules.split_whitespace() {
+            user.apply_rule(rule).unwrap();
+        }
+        user
+    }
+
+    #[test]
+    fn test_rules() {
+        let alice = user("on >secret ~user:* +@read -@json-read +jset");
+        assert!(alice.check_password("secret"));
+        assert!(!alice.check_password("wrong"));
+        assert!(alice.commands.contains("get"));
+        assert!(alice.commands.contains("jset"));
+        assert!(!alice.commands.contains("jget"));
+        assert!(!alice.commands.contains("set"));
+        assert_eq!(alice.describe(), format!("on #{} ~user:* +@read -@json-read +jset", hash_password("secret")));
+        assert!(alice.key_filter().allows("user:1"));
+        assert!(!alice.key_filter().allows("order:1"));
+
+        let default = User::default_user();
+        assert_eq!(default.flags(), vec!["on", "allkeys", "allcommands", "nopass"]);
+        assert!(default.key_filter().allows("order:1"));
+        assert!(!User::new().key_filter().allows("order:1"));
+        assert!(User::new().apply_rule("+@nothing").is_err());
+
+        let bob = user("on nopass +@json-read +client");
+        assert!(bob.commands.contains("jschema|get"));
+        assert!(!bob.commands.contains("jschema|set"));
+        assert!(bob.commands.contains("client|id"));
+        assert!(bob.commands.contains("client|kill"));
+        assert_eq!(permission_name("jschema", "GET"), "jschema|get");
+        assert_eq!(permission_name("jget", "doc"), "jget");
+        assert!(User::new().apply_rule("#abc").is_err());
+    }
+
+    #[test]
+    fn test_config_users() {
+        let mut section = BTreeMap::new();
+        section.insert("alice".to_owned(), format!("on #{} ~user:* +@all -@json-write", hash_password("secret")));
+        section.insert("default".to_owned(), "-@dangerous".to_owned());
+        let users = parse_config_users(&section).unwrap();
+        assert!(users["alice"].check_password("secret"));
+        assert!(!users["alice"].commands.contains("jset"));
+        assert!(users["default"].commands.contains("get"));
+        assert!(!users["default"].commands.contains("keys"));
+        assert!(users["default"].commands.contains("info"));
+        assert!(users["default"].commands.contains("client|setname"));
+        assert!(!users["default"].commands.contains("client|kill"));
+
+        section.insert("bob".to_owned(), "on >plaintext +@all".to_owned());
+        assert!(parse_config_users(&section).is_err());
+    }
+
+    #[test]
+    fn test_require_auth() {
+        let mut default = User::default_user();
+        default.set_require_auth(Some(&hash_password("secret")));
+        assert!(default.check_password("secret"));
+        assert!(!default.check_password("wrong"));
+        assert!(!default.flags().contains(&"nopass"));
+        default.set_require_auth(None);
+        assert!(default.check_password("anything"));
+
+        assert!(hash_eq(&hash_password("a"), &hash_password("a")));
+        assert!(!hash_eq(&hash_password("a"), &hash_password("b")));
+        assert!(!hash_eq("abc", "ab"));
+    }
+}
@@ -0,0 +1,371 @@
+//! Aggregation pipelines over JSON documents, the JAGGREGATE command.
+//!
+//! Documents are read from a key pattern or an index and flow through the stages in order.
+//! Every stage runs on the rayon pool, off the tokio worker: filters and projections work on one
+//! row at a time, groups are folded per thread and merged. Filters placed before any other stage
+//! are applied while reading the store, so documents they reject are never copied.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use glob::Pattern;
+use rayon::prelude::*;
+use serde_json::{Map, Value};
+
+use crate::command::*;
+use crate::{acl, db, index};
+use crate::network::Context;
+use crate::printer::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// every JSON key matching a glob pattern
+    Pattern(String),
+    /// documents of an index matching a query, all of them for an empty query
+    Index(String, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReduceFn {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl ReduceFn {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReduceFn::Count => "count",
+            ReduceFn::Sum => "sum",
+            ReduceFn::Avg => "avg",
+            ReduceFn::Min => "min",
+            ReduceFn::Max => "max",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reducer {
+    pub function: ReduceFn,
+    /// path of the reduced value, none for COUNT
+    pub path: Option<String>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// keeps the rows selected by a JSONPath filter selector such as `$[?(@.age >= 18)]`
+    Filter(String),
+    /// replaces every row by an object of `(name, path)` fields
+    Project(Vec<(String, String)>),
+    /// one row per distinct value of the `(name, path)` fields, with the reduced values
+    GroupBy(Vec<(String, String)>, Vec<Reducer>),
+    /// path and descending flag
+    SortBy(String, bool),
+    /// offset and count
+    Limit(usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub source: Source,
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    /// key of the document the row comes from, rows built by GROUPBY have none
+    key: Option<String>,
+    value: Value,
+}
+
+/// First value at `path` in `row`, dotted paths of plain names are walked without the JSONPath engine
+fn value_at<'a>(row: &'a Value, path: &str) -> Option<&'a Value> {
+    if path == "$" {
+        return Some(row);
+    }
+    if path.starts_with("$.") && path[2..].split('.').all(|p| !p.is_empty() && p.chars().all(|c| c.is_alphanumeric() || c == '_')) {
+        return path[2..].split('.').try_fold(row, |v, name| v.get(name));
+    }
+    jsonpath_lib::select(row, path).ok().and_then(|v| v.first().cloned())
+}
+
+/// The row passes when the selector selects it. A filter on an object tests the object itself, so
+/// documents are read in place, other rows are put in a one element array for the filter to test them.
+fn matches_filter(row: &Value, selector: &str) -> bool {
+    let selects = |value: &Value| jsonpath_lib::select(value, selector).map(|v| !v.is_empty()).unwrap_or(false);
+    match row {
+        Value::Object(_) => selects(row),
+        _ => selects(&Value::Array(vec![row.clone()])),
+    }
+}
+
+/// Whole numbers are given back as integers so sums and extremes of integers stay integers
+fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 {
+        Value::from(n as i64)
+    } else {
+        serde_json::Number::from_f64(n).map(Value::Number).unwrap_or(Value::Null)
+    }
+}
+
+/// Orders numbers before strings before anything else, missing values go last whatever the order
+fn compare(a: Option<&Value>, b: Option<&Value>, descending: bool) -> Ordering {
+    let rank = |v: &Value| match v {
+        Value::Number(_) => 0,
+        Value::String(_) => 1,
+        _ => 2,
+    };
+    let ordering = match (a, b) {
+        (Some(Value::Number(x)), Some(Value::Number(y))) => {
+            x.as_f64().partial_cmp(&y.as_f64()).unwrap_or(Ordering::Equal)
+        }
+        (Some(Value::String(x)), Some(Value::String(y))) => x.cmp(y),
+        (Some(x), Some(y)) => rank(x).cmp(&rank(y)),
+        (Some(_), None) => { return Ordering::Less; }
+        (None, Some(_)) => { return Ordering::Greater; }
+        (None, None) => Ordering::Equal,
+    };
+    if descending { ordering.reverse() } else { ordering }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, n: f64) {
+        self.count += 1;
+        self.sum += n;
+        self.min = Some(self.min.map_or(n, |m| m.min(n)));
+        self.max = Some(self.max.map_or(n, |m| m.max(n)));
+    }
+
+    fn merge(&mut self, other: &Accumulator) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = match (self.max, other.max) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// COUNT counts rows, the other functions only look at numeric values
+    fn result(&self, function: ReduceFn, rows: u64) -> Value {
+        match function {
+            ReduceFn::Count => Value::from(rows),
+            ReduceFn::Sum => number(self.sum),
+            ReduceFn::Avg if self.count > 0 => number(self.sum / self.count as f64),
+            ReduceFn::Avg => Value::Null,
+            ReduceFn::Min => self.min.map(number).unwrap_or(Value::Null),
+            ReduceFn::Max => self.max.map(number).unwrap_or(Value::Null),
+        }
+    }
+}
+
+struct Group {
+    values: Vec<Value>,
+    rows: u64,
+    accumulators: Vec<Accumulator>,
+}
+
+impl Group {
+    fn merge(&mut self, other: Group) {
+        self.rows += other.rows;
+        for (a, b) in self.accumulators.iter_mut().zip(other.accumulators.iter()) {
+            a.merge(b);
+        }
+    }
+}
+
+fn group_by(rows: Vec<Row>, fields: &[(String, String)], reducers: &[Reducer]) -> Vec<Row> {
+    let groups: HashMap<String, Group> = rows.par_iter()
+        .fold(HashMap::new, |mut groups: HashMap<String, Group>, row| {
+            let values: Vec<Value> = fields.iter()
+                .map(|(_, path)| value_at(&row.value, path).cloned().unwrap_or(Value::Null))
+                .collect();
+            let id = serde_json::to_string(&values).unwrap_or_default();
+            let group = groups.entry(id).or_insert_with(|| Group {
+                values,
+                rows: 0,
+                accumulators: vec![Accumulator::default(); reducers.len()],
+            });
+            group.rows += 1;
+            for (accumulator, reducer) in group.accumulators.iter_mut().zip(reducers) {
+                let n = reducer.path.as_ref()
+                    .and_then(|path| value_at(&row.value, path))
+                    .and_then(|v| v.as_f64());
+                if let Some(n) = n {
+                    accumulator.add(n);
+                }
+            }
+            groups
+        })
+        .reduce(HashMap::new, |mut a, b| {
+            for (id, group) in b {
+                match a.entry(id) {
+                    Entry::Occupied(e) => e.into_mut().merge(group),
+                    Entry::Vacant(e) => { e.insert(group); }
+                }
+            }
+            a
+        });
+
+    let mut groups: Vec<(String, Group)> = groups.into_iter().collect();
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    groups.into_iter().map(|(_, group)| {
+        let mut object = Map::new();
+        for ((name, _), value) in fields.iter().zip(group.values) {
+            object.insert(name.to_owned(), value);
+        }
+        for (reducer, accumulator) in reducers.iter().zip(group.accumulators.iter()) {
+            object.insert(reducer.name.to_owned(), accumulator.result(reducer.function, group.rows));
+        }
+        Row { key: None, value: Value::Object(object) }
+    }).collect()
+}
+
+fn run_stage(rows: Vec<Row>, stage: &Stage) -> Vec<Row> {
+    match stage {
+        Stage::Filter(selector) => rows.into_par_iter().filter(|row| matches_filter(&row.value, selector)).collect(),
+        Stage::Project(fields) => rows.into_par_iter().map(|row| {
+            let mut object = Map::new();
+            for (name, path) in fields {
+                object.insert(name.to_owned(), value_at(&row.value, path).cloned().unwrap_or(Value::Null));
+            }
+            Row { key: row.key, value: Value::Object(object) }
+        }).collect(),
+        Stage::GroupBy(fields, reducers) => group_by(rows, fields, reducers),
+        Stage::SortBy(path, descending) => {
+            let mut rows = rows;
+            // the sort is stable, rows keep the order of their keys on ties
+            rows.par_sort_by(|a, b| compare(value_at(&a.value, path), value_at(&b.value, path), *descending));
+            rows
+        }
+        Stage::Limit(offset, count) => rows.into_iter().skip(*offset).take(*count).collect(),
+    }
+}
+
+/// Reads the documents of `source` the user may access and passing every filter of `filters`
+fn load(source: &Source, filters: &[&String], key_filter: &acl::KeyFilter) -> Result<Vec<Row>, String> {
+    let keys = match source {
+        Source::Pattern(pattern) => {
+            let pattern = Pattern::new(pattern).map_err(|e| format!("ERR invalid key pattern: {}", e))?;
+            db::json_keys(&pattern)
+        }
+        Source::Index(name, query) => index::query_keys(name, query)?,
+    };
+    Ok(keys.into_par_iter().filter(|key| key_filter.allows(key)).filter_map(|key| {
+        let value = db::with_json_document(&key, |doc| {
+            if filters.iter().all(|selector| matches_filter(doc, selector)) { Some(doc.clone()) } else { None }
+        })??;
+        Some(Row { key: Some(key), value })
+    }).collect())
+}
+
+fn run(pipeline: &Pipeline, key_filter: &acl::KeyFilter) -> Result<Vec<Row>, String> {
+    for stage in &pipeline.stages {
+        if let Stage::Filter(selector) = stage {
+            jsonpath_lib::select(&Value::Array(vec![]), selector)
+                .map_err(|_| format!("ERR invalid filter '{}'", selector))?;
+        }
+    }
+    let mut stages = pipeline.stages.iter().peekable();
+    let mut filters: Vec<&String> = vec![];
+    while let Some(Stage::Filter(selector)) = stages.peek() {
+        filters.push(selector);
+        stages.next();
+    }
+    let rows = load(&pipeline.source, &filters, key_filter)?;
+    Ok(stages.fold(rows, run_stage))
+}
+
+/// `[key, document]` pairs while rows still come from a single document, JSON rows after a GROUPBY
+pub fn jaggregate(context: &mut Context, cmd: &JAggregateCmd) -> String {
+    let key_filter = key_filter(context);
+    // the command can't await, block_in_place hands this worker's other tasks to another thread
+    // while the pipeline runs on the rayon pool
+    let rows = match tokio::task::block_in_place(|| run(&cmd.arg_pipeline, &key_filter)) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    if rows.iter().all(|row| row.key.is_some()) {
+        let rows: Vec<Vec<String>> = rows.into_iter()
+            .map(|row| vec![row.key.unwrap_or_default(), row.value.to_string()])
+            .collect();
+        return print_nested_arr(rows);
+    }
+    print_arr(rows.into_iter().map(|row| row.value.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<Row> {
+        vec![
+            json!({"name": "ama", "age": 31, "country": "GH", "orders": {"total": 120}}),
+            json!({"name": "kofi", "age": 17, "country": "GH", "orders": {"total": 30}}),
+            json!({"name": "ada", "age": 45, "country": "NG", "orders": {"total": 80.5}}),
+            json!({"name": "tunde", "age": 22, "country": "NG"}),
+            json!({"name": "lena", "age": 38, "country": "DE", "orders": {"total": 200}}),
+        ].into_iter().enumerate().map(|(i, value)| Row { key: Some(format!("user:{}", i)), value }).collect()
+    }
+
+    fn values(rows: Vec<Row>) -> Vec<Value> {
+        rows.into_iter().map(|row| row.value).collect()
+    }
+
+    #[test]
+    fn test_filter_project_sort_limit() {
+        let stages = vec![
+            Stage::Filter("$[?(@.age >= 18)]".to_owned()),
+            Stage::SortBy("$.age".to_owned(), true),
+            Stage::Limit(1, 2),
+            Stage::Project(vec![("name".to_owned(), "$.name".to_owned()), ("total".to_owned(), "$.orders.total".to_owned())]),
+        ];
+        let result = stages.iter().fold(rows(), run_stage);
+        assert_eq!(result.iter().map(|r| r.key.clone().unwrap()).collect::<Vec<String>>(), vec!["user:4", "user:0"]);
+        assert_eq!(values(result), vec![json!({"name": "lena", "total": 200}), json!({"name": "ama", "total": 120})]);
+
+        // nested objects passing the filter don't select the document
+        assert!(!matches_filter(&json!({"age": 5, "parent": {"age": 40}}), "$[?(@.age >= 18)]"));
+    }
+
+    #[test]
+    fn test_group_by() {
+        let reducers = vec![
+            Reducer { function: ReduceFn::Count, path: None, name: "users".to_owned() },
+            Reducer { function: ReduceFn::Sum, path: Some("$.orders.total".to_owned()), name: "sum_total".to_owned() },
+            Reducer { function: ReduceFn::Avg, path: Some("$.age".to_owned()), name: "avg_age".to_owned() },
+            Reducer { function: ReduceFn::Max, path: Some("$.orders.total".to_owned()), name: "max_total".to_owned() },
+        ];
+        let stages = vec![
+            Stage::GroupBy(vec![("country".to_owned(), "$.country".to_owned())], reducers),
+            Stage::SortBy("$.users".to_owned(), true),
+        ];
+        let result = stages.iter().fold(rows(), run_stage);
+        assert!(result.iter().all(|r| r.key.is_none()));
+        assert_eq!(values(result), vec![
+            json!({"country": "GH", "users": 2, "sum_total": 150, "avg_age": 24, "max_total": 120}),
+            json!({"country": "NG", "users": 2, "sum_total": 80.5, "avg_age": 33.5, "max_total": 80.5}),
+            json!({"country": "DE", "users": 1, "sum_total": 200, "avg_age": 38, "max_total": 200}),
+        ]);
+
+        // without fields every row lands in the same group
+        let min = Reducer { function: ReduceFn::Min, path: Some("$.missing".to_owned()), name: "min".to_owned() };
+        let result = group_by(rows(), &[], &[min]);
+        assert_eq!(values(result), vec![json!({"min": null})]);
+    }
+}
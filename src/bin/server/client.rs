@@ -0,0 +1,290 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use chrono::Utc;
+use tokio::sync::Notify;
+use tokio::time;
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+use crate::command::*;
+use crate::network::Context;
+use crate::printer::*;
+
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: String,
+    pub name: Option<String>,
+    pub created_at: i64,
+    pub last_interaction: i64,
+    pub last_cmd: String,
+    pub cmd_count: u64,
+    kill_signal: Arc<Notify>,
+}
+
+lazy_static! {
+    static ref CLIENTS : Arc<DashMap<u64, ClientInfo>> = Arc::new(DashMap::new());
+    static ref NEXT_CLIENT_ID : AtomicU64 = AtomicU64::new(1);
+    static ref TOTAL_CONNECTIONS_RECEIVED : AtomicU64 = AtomicU64::new(0);
+    static ref REJECTED_CONNECTIONS : AtomicU64 = AtomicU64::new(0);
+    //timestamp in millis until which clients are paused
+    static ref PAUSE_UNTIL : AtomicI64 = AtomicI64::new(0);
+}
+
+/// Registers a new connection and returns its id with the signal used to kill it
+pub fn register(addr: String) -> (u64, Arc<Notify>) {
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::SeqCst);
+    let now = Utc::now().timestamp();
+    let kill_signal = Arc::new(Notify::new());
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    clients.insert(id, ClientInfo {
+        id,
+        addr,
+        name: None,
+        created_at: now,
+        last_interaction: now,
+        last_cmd: String::from("NULL"),
+        cmd_count: 0,
+        kill_signal: kill_signal.clone(),
+    });
+    TOTAL_CONNECTIONS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+    (id, kill_signal)
+}
+
+pub fn unregister(id: u64) {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    clients.remove(&id);
+}
+
+pub fn connected_clients() -> usize {
+    CLIENTS.len()
+}
+
+pub fn total_connections_received() -> u64 {
+    TOTAL_CONNECTIONS_RECEIVED.load(Ordering::Relaxed)
+}
+
+pub fn rejected_connections() -> u64 {
+    REJECTED_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+pub fn increment_rejected_connections() {
+    REJECTED_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn reset_stats() {
+    TOTAL_CONNECTIONS_RECEIVED.store(0, Ordering::Relaxed);
+    REJECTED_CONNECTIONS.store(0, Ordering::Relaxed);
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    clients.iter_mut().for_each(|mut entry| {
+        entry.value_mut().cmd_count = 0;
+    });
+}
+
+/// Records the command a client just issued
+pub fn touch(id: u64, cmd_name: &str) {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    if let Some(mut entry) = clients.get_mut(&id) {
+        let info = entry.value_mut();
+        info.last_interaction = Utc::now().timestamp();
+        info.last_cmd = cmd_name.to_lowercase();
+        info.cmd_count += 1;
+    };
+}
+
+/// Waits until a pause set by CLIENT PAUSE has elapsed
+pub async fn wait_if_paused() {
+    loop {
+        let remaining = PAUSE_UNTIL.load(Ordering::SeqCst) - Utc::now().timestamp_millis();
+        if remaining <= 0 {
+            return;
+        }
+        time::delay_for(Duration::from_millis(remaining as u64)).await;
+    }
+}
+
+fn format_client(info: &ClientInfo) -> String {
+    let now = Utc::now().timestamp();
+    format!("id={} addr={} name={} age={} idle={} cmds={} cmd={}",
+            info.id,
+            info.addr,
+            info.name.as_ref().unwrap_or(&String::new()),
+            now - info.created_at,
+            now - info.last_interaction,
+            info.cmd_count,
+            info.last_cmd)
+}
+
+fn kill(id: u64) -> bool {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    return match clients.get(&id) {
+        None => { false }
+        Some(entry) => {
+            entry.value().kill_signal.notify();
+            true
+        }
+    };
+}
+
+pub fn client_id(context: &mut Context, _cmd: &ClientIdCmd) -> String {
+    print_integer(context.client_id as i64)
+}
+
+pub fn client_list(_context: &mut Context, _cmd: &ClientListCmd) -> String {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    let mut infos: Vec<String> = vec![];
+    let mut ids: Vec<u64> = clients.iter().map(|entry| *entry.key()).collect();
+    ids.sort();
+    for id in ids {
+        if let Some(entry) = clients.get(&id) {
+            infos.push(format_client(entry.value()));
+        }
+    }
+    let mut list = infos.join("\n");
+    if !list.is_empty() {
+        list.push('\n');
+    }
+    print_string(&list)
+}
+
+pub fn client_info(context: &mut Context, _cmd: &ClientInfoCmd) -> String {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    return match clients.get(&context.client_id) {
+        None => { print_err("ERR client not found") }
+        Some(entry) => {
+            print_string(&format!("{}\n", format_client(entry.value())))
+        }
+    };
+}
+
+pub fn client_set_name(context: &mut Context, cmd: &ClientSetNameCmd) -> String {
+    // same rule as redis, printable ASCII without spaces
+    if cmd.arg_name.chars().any(|c| !('!'..='~').contains(&c)) {
+        return print_err("ERR Client names cannot contain spaces, newlines or special characters.");
+    }
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    if let Some(mut entry) = clients.get_mut(&context.client_id) {
+        entry.value_mut().name = if cmd.arg_name.is_empty() { None } else { Some(cmd.arg_name.to_owned()) };
+    };
+    print_ok()
+}
+
+pub fn client_get_name(context: &mut Context, _cmd: &ClientGetNameCmd) -> String {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+    let name = match clients.get(&context.client_id) {
+        None => { None }
+        Some(entry) => { entry.value().name.to_owned() }
+    };
+    return match name {
+        None => { print_nil() }
+        Some(name) => { print_string(&name) }
+    };
+}
+
+pub fn client_kill(_context: &mut Context, cmd: &ClientKillCmd) -> String {
+    let clients: Arc<DashMap<u64, ClientInfo>> = CLIENTS.clone();
+
+    let mut targets: Vec<u64> = vec![];
+    for entry in clients.iter() {
+        let info = entry.value();
+        let id_matches = match cmd.arg_id {
+            None => { true }
+            Some(id) => { id == info.id }
+        };
+        let addr_matches = match &cmd.arg_addr {
+            None => { true }
+            Some(addr) => { addr == &info.addr }
+        };
+        if id_matches && addr_matches {
+            targets.push(info.id);
+        }
+    }
+
+    let killed = targets.into_iter().filter(|id| kill(*id)).count();
+
+    if cmd.legacy_syntax {
+        return if killed == 0 {
+            print_err("ERR No such client")
+        } else {
+            print_ok()
+        };
+    }
+    print_integer(killed as i64)
+}
+
+pub fn client_pause(_context: &mut Context, cmd: &ClientPauseCmd) -> String {
+    let until = Utc::now().timestamp_millis() + cmd.arg_timeout as i64;
+    PAUSE_UNTIL.fetch_max(until, Ordering::SeqCst);
+    print_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(client_id: u64) -> Context {
+        Context {
+            client_id,
+            client_addr: String::new(),
+            client_authenticated: false,
+            client_auth_key: None,
+            user: None,
+            command_name: String::new(),
+            trusted: true,
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let (id, _) = register("10.0.0.1:5000".to_owned());
+        let mut context = context(id);
+        touch(id, "PING");
+        assert_eq!(client_id(&mut context, &ClientIdCmd), print_integer(id as i64));
+        assert!(client_info(&mut context, &ClientInfoCmd).contains(&format!("id={} addr=10.0.0.1:5000 name= ", id)));
+        assert!(client_info(&mut context, &ClientInfoCmd).contains("cmds=1 cmd=ping"));
+
+        assert_eq!(client_set_name(&mut context, &ClientSetNameCmd { arg_name: "worker-1".to_owned() }), print_ok());
+        assert_eq!(client_get_name(&mut context, &ClientGetNameCmd), print_string(&"worker-1".to_owned()));
+        for name in &["a b", "a\nb", "caf\u{e9}"] {
+            assert!(client_set_name(&mut context, &ClientSetNameCmd { arg_name: name.to_string() }).starts_with("-ERR"));
+        }
+        assert_eq!(client_set_name(&mut context, &ClientSetNameCmd { arg_name: String::new() }), print_ok());
+        assert_eq!(client_get_name(&mut context, &ClientGetNameCmd), print_nil());
+
+        assert!(client_list(&mut context, &ClientListCmd).contains(&format!("id={} ", id)));
+        unregister(id);
+        assert!(!client_list(&mut context, &ClientListCmd).contains(&format!("id={} ", id)));
+    }
+
+    #[tokio::test]
+    async fn test_client_kill() {
+        let (id, kill_signal) = register("10.0.0.2:5000".to_owned());
+        let (other, _) = register("10.0.0.2:5001".to_owned());
+        let mut context = context(id);
+
+        let cmd = ClientKillCmd { arg_id: None, arg_addr: Some("10.0.0.2:5000".to_owned()), legacy_syntax: false };
+        assert_eq!(client_kill(&mut context, &cmd), print_integer(1));
+        time::timeout(Duration::from_secs(1), kill_signal.notified()).await.unwrap();
+
+        let cmd = ClientKillCmd { arg_id: Some(other), arg_addr: Some("10.0.0.2:5000".to_owned()), legacy_syntax: false };
+        assert_eq!(client_kill(&mut context, &cmd), print_integer(0));
+        let cmd = ClientKillCmd { arg_id: None, arg_addr: Some("10.0.0.2:6000".to_owned()), legacy_syntax: true };
+        assert_eq!(client_kill(&mut context, &cmd), print_err("ERR No such client"));
+        unregister(id);
+        unregister(other);
+    }
+
+    #[tokio::test]
+    async fn test_client_pause() {
+        let mut context = context(0);
+        assert_eq!(client_pause(&mut context, &ClientPauseCmd { arg_timeout: 100 }), print_ok());
+        let started = std::time::Instant::now();
+        wait_if_paused().await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        wait_if_paused().await;
+    }
+}
+
@@ -6,7 +6,20 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use redis_protocol::prelude::*;
 
-pub struct RespCodec;
+const BYTES_IN_MB: usize = 1024 * 1024;
+
+pub struct RespCodec {
+    /// Maximum size in bytes of a single request frame, 0 means no limit
+    max_frame_size: usize,
+}
+
+impl RespCodec {
+    pub fn new(max_packet_mb: usize) -> RespCodec {
+        RespCodec {
+            max_frame_size: max_packet_mb * BYTES_IN_MB
+        }
+    }
+}
 
 impl Decoder for RespCodec {
     // ...
@@ -24,8 +37,14 @@ impl Decoder for RespCodec {
         };
 
         return if let Some(frame) = frame {
+            if self.max_frame_size > 0 && consumed > self.max_frame_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "max packet size exceeded"));
+            }
             buf.split_to(consumed);
             Ok(Some(frame))
+        } else if self.max_frame_size > 0 && buf.len() > self.max_frame_size {
+            // the frame is still incomplete but already bigger than allowed
+            Err(io::Error::new(io::ErrorKind::InvalidData, "max packet size exceeded"))
         } else {
             Ok(None)
         };
@@ -38,4 +57,20 @@ impl Encoder<Frame> for RespCodec {
         encode_bytes( dst, &item);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_frame_over_max_packet() {
+        let mut codec = RespCodec::new(1);
+        let big_value = "a".repeat(BYTES_IN_MB + 1);
+        let mut buf = BytesMut::from(format!("*1\r\n${}\r\n{}\r\n", big_value.len(), big_value).as_bytes());
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from("*1\r\n$4\r\nPING\r\n".as_bytes());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+}
@@ -1,6 +1,7 @@
 extern crate regex;
 
-use crate::{db, printer};
+use crate::{db, printer, client, config, shutdown, replication, acl, index, schema, aggregate, json};
+use crate::shutdown::ShutdownMode;
 use crate::error;
 
 use crate::error::SyntaxError;
@@ -63,49 +64,112 @@ pub fn compile_resp(buf: &[u8]) -> Result<Box<dyn Command>, error::SyntaxError>
 
 
 use crate::network::Context;
+use std::any::Any;
 
 pub trait Command {
     //fn execute(&self, db: &db::DB);
     fn execute(&self, context: &mut Context) -> String;
+
+    /// Keys the command works on, see `command_keys`
+    fn keys(&self) -> Vec<&str> {
+        vec![]
+    }
 }
 
-pub fn auth_context<T>(context: &mut Context, fn_args: T, f: fn(T) -> String) -> String {
-    if !context.auth_is_required {
-        return f(fn_args);
+/// Checks that a connection of the `default` user may run commands, against the user's current
+/// state and passwords. Users authenticated with AUTH <user> <pass> are checked against the ACL
+/// instead, see `check_permission`.
+pub fn check_auth(context: &mut Context) -> Result<(), String> {
+    if context.trusted || context.user.is_some() {
+        return Ok(());
+    }
+    context.client_authenticated = acl::default_user_allowed(context.client_auth_key.as_deref());
+    if context.client_authenticated {
+        Ok(())
+    } else {
+        Err(print_err("ERR auth"))
     }
+}
 
-    let auth_key = match &context.auth_key {
-        Some(k) => k.to_owned(),
-        None => {
-            return print_err("ERR auth");
-        }
-    };
+/// Checks the ACL of the connection's user for the command being executed and the keys it uses
+pub fn check_permission(context: &Context, keys: &[&str]) -> Result<(), String> {
+    if context.trusted {
+        return Ok(());
+    }
+    let user = context.user.as_deref().unwrap_or(acl::DEFAULT_USER);
+    acl::check_permission(user, &context.command_name, keys).map_err(|e| print_err(&e))
+}
+
+/// Keys the connection's user may access, for commands that find their keys themselves
+pub fn key_filter(context: &Context) -> acl::KeyFilter {
+    if context.trusted {
+        return acl::KeyFilter::all();
+    }
+    acl::key_filter(context.user.as_deref().unwrap_or(acl::DEFAULT_USER))
+}
 
-    let client_auth_key = match &context.client_auth_key {
-        Some(k) => k.to_owned(),
-        None => {
-            return print_err("ERR auth");
+/// Picks the keys a command works on out of its fields, `arg_key` and `keys` by convention
+pub fn command_keys<'a>(fields: &[(&str, &'a dyn Any)]) -> Vec<&'a str> {
+    let mut keys = vec![];
+    for (name, value) in fields {
+        match *name {
+            "arg_key" => {
+                if let Some(key) = value.downcast_ref::<String>() {
+                    keys.push(key.as_str());
+                }
+            }
+            "keys" => {
+                if let Some(k) = value.downcast_ref::<Vec<String>>() {
+                    keys.extend(k.iter().map(|key| key.as_str()));
+                }
+            }
+            _ => {}
         }
-    };
+    }
+    keys
+}
 
-    if auth_key == client_auth_key {
-        context.client_authenticated = true
-    } else {
-        context.client_authenticated = false
+pub fn auth_context<T>(context: &mut Context, fn_args: T, keys: &[&str], f: fn(T) -> String) -> String {
+    match check_auth(context).and_then(|_| check_permission(context, keys)) {
+        Ok(_) => f(fn_args),
+        Err(e) => e
+    }
+}
+
+/// Like `auth_context` but hands the connection context to the command function
+pub fn auth_client_context<T>(context: &mut Context, fn_args: T, keys: &[&str], f: fn(&mut Context, T) -> String) -> String {
+    match check_auth(context).and_then(|_| check_permission(context, keys)) {
+        Ok(_) => f(context, fn_args),
+        Err(e) => e
     }
-    return if context.client_authenticated {
-        f(fn_args)
-    } else {
-        print_err("ERR auth failed")
-    };
 }
 
 /// Creates an implementation for Command for a type with in a auth context
 macro_rules! cmd_with_context_impl {
-    ($type : ty => $func : path) => {
+    ($type : ty => $func : path; $($arg : ident),*) => {
         impl Command for $type {
             fn execute(&self, context: &mut Context) -> String {
-                auth_context(context,self,$func)
+                let keys = self.keys();
+                auth_context(context,self,&keys,$func)
+            }
+
+            fn keys(&self) -> Vec<&str> {
+                command_keys(&[$((stringify!($arg), &self.$arg as &dyn Any)),*])
+            }
+        }
+    };
+}
+/// Creates an implementation for Command for a type whose function also needs the client context
+macro_rules! cmd_with_client_context_impl {
+    ($type : ty => $func : path; $($arg : ident),*) => {
+        impl Command for $type {
+            fn execute(&self, context: &mut Context) -> String {
+                let keys = self.keys();
+                auth_client_context(context,self,&keys,$func)
+            }
+
+            fn keys(&self) -> Vec<&str> {
+                command_keys(&[$((stringify!($arg), &self.$arg as &dyn Any)),*])
             }
         }
     };
@@ -117,12 +181,24 @@ macro_rules! make_command {
         pub struct $name {
             $(pub $arg : $arg_type),+
         }
-        cmd_with_context_impl!{$name => $func}
+        cmd_with_context_impl!{$name => $func; $($arg),+}
     };
     ($name : ident; -> $func : path) => {
         #[derive(Debug)]
         pub struct $name;
-        cmd_with_context_impl!{$name => $func}
+        cmd_with_context_impl!{$name => $func;}
+    };
+    ($name : ident {$($arg : ident : $arg_type : ty ),+} => $func : path) => {
+        #[derive(Debug)]
+        pub struct $name {
+            $(pub $arg : $arg_type),+
+        }
+        cmd_with_client_context_impl!{$name => $func; $($arg),+}
+    };
+    ($name : ident; => $func : path) => {
+        #[derive(Debug)]
+        pub struct $name;
+        cmd_with_client_context_impl!{$name => $func;}
     };
     ($name : ident {$($arg : ident : $arg_type : ty ),+}) => {
         #[derive(Debug)]
@@ -147,13 +223,16 @@ pub type CmdGeoItem = (f64, f64, String);
 pub type JSetArgItem = (String, Value);
 
 make_command!(PingCmd;);
-make_command!(AuthCmd {arg_password : String});
+make_command!(AuthCmd {arg_user : Option<String>, arg_password : String});
 make_command!(LastSaveCmd; -> db::last_save);
+make_command!(SaveCmd; -> db::save);
 make_command!(BGSaveCmd; -> db::bg_save );
 make_command!(FlushDBCmd; -> db::flush_db);
 make_command!(RandomKeyCmd; -> db::random_key);
 make_command!(InfoCmd; -> db::info);
 make_command!(DBSizeCmd; -> db::db_size);
+make_command!(ShutdownCmd{arg_mode : ShutdownMode} -> shutdown::shutdown);
+make_command!(ReplicaOfCmd{arg_master : Option<(String, u16)>} -> replication::replica_of);
 
 impl Command for PingCmd {
     fn execute(&self, _: &mut Context) -> String {
@@ -172,10 +251,12 @@ make_command!(GetSetCmd{arg_key : String, arg_value : ESValue} -> db::get_set);
 make_command!(GetCmd{arg_key : String} -> db::get);
 make_command!(DelCmd{arg_key : String} -> db::del);
 make_command!(PersistCmd{arg_key : String} -> db::persist);
+make_command!(DumpCmd{arg_key : String} -> db::dump);
+make_command!(RestoreCmd{arg_key : String, arg_ttl : u64, arg_value : String, arg_replace : bool} -> db::restore);
 make_command!(TTLCmd{arg_key : String} -> db::ttl);
 make_command!(ExpireCmd{arg_key: String, arg_value : i64} -> db::expire);
 make_command!(ExpireAtCmd{arg_key: String, arg_value : i64} -> db::expire_at);
-make_command!(KeysCmd{pattern : String} -> db::keys);
+make_command!(KeysCmd{pattern : String} => db::keys);
 make_command!(ExistsCmd{keys : Vec<String>} -> db::exists);
 // Geo Spatial Commands
 make_command!(GeoAddCmd{arg_key : String, items : Vec<CmdGeoItem>} -> db::geo_add);
@@ -191,9 +272,70 @@ make_command!(GeoJsonCmd{arg_key : String,items : Vec<String>} -> db::geo_json);
 make_command!(JSetRawCmd{arg_key : String, arg_value: String} -> db::jset_raw);
 make_command!(JSetCmd{arg_key : String, arg_set_items : Vec<JSetArgItem>} -> db::jset);
 make_command!(JMergeCmd{arg_key : String,  arg_value : String} -> db::jmerge);
-make_command!(JGetCmd{arg_key : String, arg_dot_path : Option<String>} -> db::jget);
+make_command!(JGetCmd{arg_key : String, arg_paths : Vec<String>, arg_format : json::JsonFormat} -> db::jget);
 make_command!(JPathCmd{arg_key : String, arg_selector : String} -> db::jpath);
 make_command!(JDelCmd{arg_key :String} -> db::jdel);
 make_command!(JRemCmd{arg_key : String, arg_paths : Vec<String>} -> db::jrem);
 make_command!(JIncrByCmd{arg_key: String, arg_path: String,arg_increment_value: i64} -> db::jincr_by);
 make_command!(JIncrByFloatCmd{arg_key: String,arg_path: String,arg_increment_value: f64} -> db::jincr_by_float);
+make_command!(JSchemaSetCmd{arg_def : schema::SchemaDef} -> schema::jschema_set);
+make_command!(JSchemaDelCmd{arg_name : String} -> schema::jschema_del);
+make_command!(JSchemaGetCmd{arg_name : String} -> schema::jschema_get);
+make_command!(JSchemaListCmd; -> schema::jschema_list);
+make_command!(JSchemaValidateCmd{arg_key : String} -> schema::jschema_validate);
+make_command!(JPatchCmd{arg_key : String, arg_patch : String, arg_merge : bool} -> db::jpatch);
+make_command!(JPathSetCmd{arg_key : String, arg_selector : String, arg_value : String} -> db::jpath_set);
+make_command!(JPathDelCmd{arg_key : String, arg_selector : String} -> db::jpath_del);
+make_command!(JPathIncrByCmd{arg_key : String, arg_selector : String, arg_increment_value : f64} -> db::jpath_incr_by);
+make_command!(JPathStrAppendCmd{arg_key : String, arg_selector : String, arg_value : String} -> db::jpath_str_append);
+make_command!(JPathArrAppendCmd{arg_key : String, arg_selector : String, arg_values : Vec<String>} -> db::jpath_arr_append);
+make_command!(JPathArrInsertCmd{arg_key : String, arg_selector : String, arg_index : i64, arg_values : Vec<String>} -> db::jpath_arr_insert);
+make_command!(JPathArrPopCmd{arg_key : String, arg_selector : String, arg_index : i64} -> db::jpath_arr_pop);
+make_command!(JPathArrTrimCmd{arg_key : String, arg_selector : String, arg_start : i64, arg_stop : i64} -> db::jpath_arr_trim);
+make_command!(JArrAppendCmd{arg_key : String, arg_path : String, arg_values : Vec<String>} -> db::jarr_append);
+make_command!(JArrInsertCmd{arg_key : String, arg_path : String, arg_index : i64, arg_values : Vec<String>} -> db::jarr_insert);
+make_command!(JArrPopCmd{arg_key : String, arg_path : String, arg_index : i64} -> db::jarr_pop);
+make_command!(JArrLenCmd{arg_key : String, arg_path : String} -> db::jarr_len);
+make_command!(JArrTrimCmd{arg_key : String, arg_path : String, arg_start : i64, arg_stop : i64} -> db::jarr_trim);
+make_command!(JStrAppendCmd{arg_key : String, arg_path : String, arg_value : String} -> db::jstr_append);
+make_command!(JStrLenCmd{arg_key : String, arg_path : String} -> db::jstr_len);
+make_command!(JObjKeysCmd{arg_key : String, arg_path : String} -> db::jobj_keys);
+make_command!(JObjLenCmd{arg_key : String, arg_path : String} -> db::jobj_len);
+make_command!(JTypeCmd{arg_key : String, arg_path : String} -> db::jtype);
+// json index commands
+make_command!(JIndexCreateCmd{arg_def : index::IndexDef} -> index::jindex_create);
+make_command!(JIndexDropCmd{arg_name : String} -> index::jindex_drop);
+make_command!(JIndexListCmd; -> index::jindex_list);
+make_command!(JIndexInfoCmd{arg_name : String} -> index::jindex_info);
+make_command!(JQueryCmd{arg_index : String, arg_query : String, arg_offset : usize, arg_count : usize, arg_sort_by : Option<(String, ArgOrder)>, arg_return : Vec<String>} => index::jquery);
+make_command!(JSearchCmd{arg_index : String, arg_query : String, arg_offset : usize, arg_count : usize} => index::jsearch);
+make_command!(JAggregateCmd{arg_pipeline : aggregate::Pipeline} => aggregate::jaggregate);
+// client commands
+make_command!(ClientListCmd; => client::client_list);
+make_command!(ClientIdCmd; => client::client_id);
+make_command!(ClientInfoCmd; => client::client_info);
+make_command!(ClientSetNameCmd{arg_name : String} => client::client_set_name);
+make_command!(ClientGetNameCmd; => client::client_get_name);
+make_command!(ClientKillCmd{arg_id : Option<u64>, arg_addr : Option<String>, legacy_syntax : bool} => client::client_kill);
+make_command!(ClientPauseCmd{arg_timeout : u64} => client::client_pause);
+// config commands
+make_command!(ConfigGetCmd{arg_pattern : String} -> config::config_get);
+make_command!(ConfigSetCmd{arg_param : String, arg_value : String} -> config::config_set);
+make_command!(ConfigResetStatCmd; -> config::config_reset_stat);
+make_command!(ConfigRewriteCmd; -> config::config_rewrite);
+// acl commands
+make_command!(AclSetUserCmd{arg_user : String, arg_rules : Vec<String>} -> acl::acl_setuser);
+make_command!(AclGetUserCmd{arg_user : String} -> acl::acl_getuser);
+make_command!(AclDelUserCmd{arg_users : Vec<String>} -> acl::acl_deluser);
+make_command!(AclListCmd; -> acl::acl_list);
+make_command!(AclWhoAmICmd;);
+
+// any authenticated user may ask who they are
+impl Command for AclWhoAmICmd {
+    fn execute(&self, context: &mut Context) -> String {
+        match check_auth(context) {
+            Ok(_) => acl::acl_whoami(context, self),
+            Err(e) => e
+        }
+    }
+}
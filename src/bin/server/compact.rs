@@ -0,0 +1,615 @@
+//! Compact binary storage for JSON documents, enabled with `database.json_encoding: compact`.
+//!
+//! A compact document is one byte buffer, a tape, instead of a tree of `Value` nodes. Object keys
+//! are interned once for the whole process and stored as ids, and containers start with the
+//! length of their body so a path read skips every subtree it does not walk into. Writing to a
+//! path splices the new bytes in and only re-encodes the headers of the enclosing containers.
+//!
+//! Tape layout, lengths and ids are LEB128 varints:
+//!
+//! ```text
+//! null | false | true
+//! int <zigzag varint> | uint <u64 le> | float <f64 le>
+//! string <len> <utf-8 bytes>
+//! array <body len> <count> <value>*
+//! object <body len> <count> (<key id> <value>)*   entries in document order
+//! ```
+//!
+//! Interned keys are never released, they are shared by every document using them.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde_json::{Map, Number, Value};
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INT: u8 = 3;
+const UINT: u8 = 4;
+const FLOAT: u8 = 5;
+const STRING: u8 = 6;
+const ARRAY: u8 = 7;
+const OBJECT: u8 = 8;
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<Arc<str>, u32>,
+    names: Vec<Arc<str>>,
+}
+
+lazy_static! {
+    static ref KEYS : RwLock<Interner> = RwLock::new(Interner::default());
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Chooses the encoding of documents written from now on, stored documents keep theirs until rewritten
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Number of distinct object keys interned so far
+pub fn interned_keys() -> usize {
+    KEYS.read().unwrap().names.len()
+}
+
+fn intern(name: &str) -> u32 {
+    if let Some(id) = KEYS.read().unwrap().ids.get(name) {
+        return *id;
+    }
+    let mut keys = KEYS.write().unwrap();
+    if let Some(id) = keys.ids.get(name) {
+        return *id;
+    }
+    let id = keys.names.len() as u32;
+    let name: Arc<str> = Arc::from(name);
+    keys.names.push(name.clone());
+    keys.ids.insert(name, id);
+    id
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_varint(tape: &[u8], pos: &mut usize) -> u64 {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = tape[*pos];
+        *pos += 1;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+fn read_u64(tape: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&tape[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+fn encode(value: &Value, buf: &mut Vec<u8>) {
+    match value {
+        Value::Null => buf.push(NULL),
+        Value::Bool(false) => buf.push(FALSE),
+        Value::Bool(true) => buf.push(TRUE),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                buf.push(INT);
+                write_varint(buf, zigzag(i));
+            } else if let Some(u) = n.as_u64() {
+                buf.push(UINT);
+                buf.extend_from_slice(&u.to_le_bytes());
+            } else {
+                buf.push(FLOAT);
+                buf.extend_from_slice(&n.as_f64().unwrap_or_default().to_bits().to_le_bytes());
+            }
+        }
+        Value::String(s) => {
+            buf.push(STRING);
+            write_varint(buf, s.len() as u64);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            let mut body = vec![];
+            write_varint(&mut body, items.len() as u64);
+            items.iter().for_each(|item| encode(item, &mut body));
+            write_container(buf, ARRAY, &body);
+        }
+        Value::Object(object) => {
+            // entries are kept in the order of the map, the order the tree serializes them in
+            let mut body = vec![];
+            write_varint(&mut body, object.len() as u64);
+            for (name, item) in object {
+                write_varint(&mut body, intern(name) as u64);
+                encode(item, &mut body);
+            }
+            write_container(buf, OBJECT, &body);
+        }
+    }
+}
+
+fn write_container(buf: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    buf.push(tag);
+    write_varint(buf, body.len() as u64);
+    buf.extend_from_slice(body);
+}
+
+/// Position right after the value starting at `pos`
+fn skip(tape: &[u8], pos: usize) -> usize {
+    let mut next = pos + 1;
+    match tape[pos] {
+        INT => { read_varint(tape, &mut next); }
+        UINT | FLOAT => { next += 8; }
+        STRING | ARRAY | OBJECT => {
+            let len = read_varint(tape, &mut next) as usize;
+            next += len;
+        }
+        _ => {}
+    }
+    next
+}
+
+/// Start of the body and the item count of the container at `pos`, which must be an array or an object
+fn container(tape: &[u8], pos: usize) -> (usize, usize) {
+    let mut next = pos + 1;
+    read_varint(tape, &mut next);
+    let body = next;
+    let count = read_varint(tape, &mut next) as usize;
+    (body, count)
+}
+
+fn decode(tape: &[u8], pos: usize, keys: &Interner) -> Value {
+    let mut next = pos + 1;
+    match tape[pos] {
+        FALSE => Value::Bool(false),
+        TRUE => Value::Bool(true),
+        INT => Value::from(unzigzag(read_varint(tape, &mut next))),
+        UINT => Value::from(read_u64(tape, next)),
+        FLOAT => Number::from_f64(f64::from_bits(read_u64(tape, next))).map(Value::Number).unwrap_or(Value::Null),
+        STRING => Value::String(read_str(tape, pos).to_owned()),
+        ARRAY => {
+            let (body, count) = container(tape, pos);
+            let mut next = body;
+            read_varint(tape, &mut next);
+            let mut items = Vec::with_capacity(count);
+            for _ in 0..count {
+                items.push(decode(tape, next, keys));
+                next = skip(tape, next);
+            }
+            Value::Array(items)
+        }
+        OBJECT => {
+            let (body, count) = container(tape, pos);
+            let mut next = body;
+            read_varint(tape, &mut next);
+            let mut object = Map::new();
+            for _ in 0..count {
+                let id = read_varint(tape, &mut next) as usize;
+                object.insert(keys.names[id].to_string(), decode(tape, next, keys));
+                next = skip(tape, next);
+            }
+            Value::Object(object)
+        }
+        _ => Value::Null,
+    }
+}
+
+fn read_str(tape: &[u8], pos: usize) -> &str {
+    let mut next = pos + 1;
+    let len = read_varint(tape, &mut next) as usize;
+    std::str::from_utf8(&tape[next..next + len]).unwrap_or_default()
+}
+
+/// Position addressed by an array segment: an index, `<` for the first or `>` for the last item
+fn array_index(segment: &str, len: usize) -> Option<usize> {
+    let index = match segment {
+        "<" => 0,
+        ">" => len.checked_sub(1)?,
+        _ => segment.parse().ok()?,
+    };
+    if index < len { Some(index) } else { None }
+}
+
+/// Start of the member `segment` of the container at `pos`, array members are addressed by position
+fn child(tape: &[u8], pos: usize, segment: &str, keys: &Interner) -> Option<usize> {
+    if tape[pos] != ARRAY && tape[pos] != OBJECT {
+        return None;
+    }
+    let (body, count) = container(tape, pos);
+    let mut next = body;
+    read_varint(tape, &mut next);
+    match tape[pos] {
+        ARRAY => {
+            let index = array_index(segment, count)?;
+            for _ in 0..index {
+                next = skip(tape, next);
+            }
+            Some(next)
+        }
+        OBJECT => {
+            let id = *keys.ids.get(segment)? as u64;
+            for _ in 0..count {
+                if read_varint(tape, &mut next) == id {
+                    return Some(next);
+                }
+                next = skip(tape, next);
+            }
+            None
+        }
+        _ => None
+    }
+}
+
+fn find(tape: &[u8], path: &[&str], keys: &Interner) -> Option<usize> {
+    path.iter().try_fold(0, |pos, segment| child(tape, pos, segment, keys))
+}
+
+/// Encoding of the container at `pos` with the value at `path` replaced by `new`, or added when only
+/// the last member of an object is missing
+fn splice(tape: &[u8], pos: usize, path: &[&str], new: &[u8], keys: &Interner) -> Option<Vec<u8>> {
+    if path.is_empty() {
+        return Some(new.to_vec());
+    }
+    // scalars have no members to replace or add
+    if tape[pos] != ARRAY && tape[pos] != OBJECT {
+        return None;
+    }
+    let (body, count) = container(tape, pos);
+    let end = skip(tape, pos);
+    let mut body_out = vec![];
+    match child(tape, pos, path[0], keys) {
+        Some(start) => {
+            let replaced = splice(tape, start, &path[1..], new, keys)?;
+            body_out.extend_from_slice(&tape[body..start]);
+            body_out.extend_from_slice(&replaced);
+            body_out.extend_from_slice(&tape[skip(tape, start)..end]);
+        }
+        None if path.len() == 1 && tape[pos] == OBJECT => {
+            // a new member goes last, like an insert into the tree's map
+            let id = *keys.ids.get(path[0])?;
+            let mut entries = body;
+            read_varint(tape, &mut entries);
+            write_varint(&mut body_out, count as u64 + 1);
+            body_out.extend_from_slice(&tape[entries..end]);
+            write_varint(&mut body_out, id as u64);
+            body_out.extend_from_slice(new);
+        }
+        None => { return None; }
+    }
+    let mut out = vec![];
+    write_container(&mut out, tape[pos], &body_out);
+    Some(out)
+}
+
+/// Splits a dot path into its segments, None when it uses escapes or the `<`, `>`, `+` and `-`
+/// array positions that only the tree representation resolves
+fn plain_segments(path: &str) -> Option<Vec<&str>> {
+    if path.contains('\\') {
+        return None;
+    }
+    let segments: Vec<&str> = path.split('.').collect();
+    let plain = segments.iter().all(|s| !s.is_empty() && *s != "-" && !s.starts_with(&['<', '>', '+'][..]));
+    if plain { Some(segments) } else { None }
+}
+
+/// Splits a dot path into its segments, `\.` escapes a dot inside an object key
+fn dot_segments(path: &str) -> Vec<String> {
+    let mut segments = vec![String::new()];
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => segments.last_mut().unwrap().extend(chars.next()),
+            '.' => segments.push(String::new()),
+            c => segments.last_mut().unwrap().push(c),
+        }
+    }
+    segments
+}
+
+fn tree_find<'a>(value: &'a Value, segments: &[String]) -> Option<&'a Value> {
+    segments.iter().try_fold(value, |node, segment| match node {
+        Value::Object(map) => map.get(segment),
+        Value::Array(items) => items.get(array_index(segment, items.len())?),
+        _ => None,
+    })
+}
+
+/// A JSON document encoded on a single tape
+#[derive(Clone, PartialEq)]
+pub struct CompactValue {
+    tape: Box<[u8]>,
+}
+
+impl CompactValue {
+    pub fn from_value(value: &Value) -> CompactValue {
+        let mut tape = vec![];
+        encode(value, &mut tape);
+        CompactValue { tape: tape.into_boxed_slice() }
+    }
+
+    pub fn to_value(&self) -> Value {
+        decode(&self.tape, 0, &KEYS.read().unwrap())
+    }
+
+    /// Size of the encoded document in bytes
+    pub fn encoded_len(&self) -> usize {
+        self.tape.len()
+    }
+
+    /// Decodes only the value at `path`, None when the path does not lead to a value
+    pub fn get(&self, path: &[&str]) -> Option<Value> {
+        let keys = KEYS.read().unwrap();
+        find(&self.tape, path, &keys).map(|pos| decode(&self.tape, pos, &keys))
+    }
+
+    /// Replaces the value at `path`, or adds it when only its last segment is missing from an object.
+    /// Returns false, leaving the document untouched, when the path can not be reached.
+    pub fn set(&mut self, path: &[&str], value: &Value) -> bool {
+        let mut new = vec![];
+        encode(value, &mut new);
+        if let Some(last) = path.last() {
+            // a missing member needs an id, taken before the keys are locked for reading
+            let parent_is_object = {
+                let keys = KEYS.read().unwrap();
+                find(&self.tape, &path[..path.len() - 1], &keys).map(|pos| self.tape[pos] == OBJECT)
+            };
+            if parent_is_object == Some(true) {
+                intern(last);
+            }
+        }
+        let keys = KEYS.read().unwrap();
+        match splice(&self.tape, 0, path, &new, &keys) {
+            Some(tape) => {
+                self.tape = tape.into_boxed_slice();
+                true
+            }
+            None => false
+        }
+    }
+}
+
+impl fmt::Debug for CompactValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CompactValue({})", self.to_value())
+    }
+}
+
+/// A value of a tape serialized in place, without building the `Value` first
+struct TapeNode<'a> {
+    tape: &'a [u8],
+    pos: usize,
+    keys: &'a Interner,
+}
+
+impl<'a> Serialize for TapeNode<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (tape, pos) = (self.tape, self.pos);
+        let node = |pos| TapeNode { tape, pos, keys: self.keys };
+        match tape[pos] {
+            ARRAY | OBJECT => {
+                let (body, count) = container(tape, pos);
+                let mut next = body;
+                read_varint(tape, &mut next);
+                if tape[pos] == ARRAY {
+                    let mut seq = serializer.serialize_seq(Some(count))?;
+                    for _ in 0..count {
+                        seq.serialize_element(&node(next))?;
+                        next = skip(tape, next);
+                    }
+                    seq.end()
+                } else {
+                    let mut map = serializer.serialize_map(Some(count))?;
+                    for _ in 0..count {
+                        let id = read_varint(tape, &mut next) as usize;
+                        map.serialize_entry(&*self.keys.names[id], &node(next))?;
+                        next = skip(tape, next);
+                    }
+                    map.end()
+                }
+            }
+            STRING => serializer.serialize_str(read_str(tape, pos)),
+            _ => decode(tape, pos, self.keys).serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for CompactValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let keys = KEYS.read().unwrap();
+        TapeNode { tape: &self.tape, pos: 0, keys: &keys }.serialize(serializer)
+    }
+}
+
+/// A document of the JSON store, kept as a `Value` tree or on a compact tape
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonDoc {
+    Tree(Value),
+    Compact(CompactValue),
+}
+
+impl JsonDoc {
+    /// Stores `value` with the configured encoding
+    pub fn new(value: Value) -> JsonDoc {
+        if is_enabled() {
+            JsonDoc::Compact(CompactValue::from_value(&value))
+        } else {
+            JsonDoc::Tree(value)
+        }
+    }
+
+    /// The document as a tree, compact documents are decoded
+    pub fn value(&self) -> Cow<'_, Value> {
+        match self {
+            JsonDoc::Tree(value) => Cow::Borrowed(value),
+            JsonDoc::Compact(compact) => Cow::Owned(compact.to_value()),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        self.value().into_owned()
+    }
+
+    pub fn is_null(&self) -> bool {
+        match self {
+            JsonDoc::Tree(value) => value.is_null(),
+            JsonDoc::Compact(compact) => compact.tape[0] == NULL,
+        }
+    }
+
+    /// Value at a dot path, None when the path leads nowhere and the whole document for an empty
+    /// path. Compact documents only decode the selected value.
+    pub fn dot_find(&self, path: &str) -> Option<Value> {
+        if path.is_empty() {
+            return Some(self.to_value());
+        }
+        let segments = dot_segments(path);
+        match self {
+            JsonDoc::Tree(value) => tree_find(value, &segments).cloned(),
+            JsonDoc::Compact(compact) => compact.get(&segments.iter().map(String::as_str).collect::<Vec<&str>>()),
+        }
+    }
+
+    /// Sets every `(dot path, value)` pair in place when the document is compact and each path
+    /// already leads to a value or to a missing object member. All or none of the pairs are applied.
+    pub fn set_in_place(&mut self, items: &[(String, Value)]) -> bool {
+        let compact = match self {
+            JsonDoc::Compact(compact) => compact,
+            JsonDoc::Tree(_) => { return false; }
+        };
+        let mut updated = compact.clone();
+        for (path, value) in items {
+            let done = plain_segments(path).map(|segments| updated.set(&segments, value)).unwrap_or(false);
+            if !done {
+                return false;
+            }
+        }
+        *compact = updated;
+        true
+    }
+}
+
+impl Serialize for JsonDoc {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            JsonDoc::Tree(value) => value.serialize(serializer),
+            JsonDoc::Compact(compact) => compact.serialize(serializer),
+        }
+    }
+}
+
+/// Documents are read back as plain JSON, in the encoding configured at load time
+impl<'de> Deserialize<'de> for JsonDoc {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(JsonDoc::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sample() -> Value {
+        json!({
+            "name": "escanor",
+            "age": 31,
+            "balance": -12.5,
+            "big": 18446744073709551615u64,
+            "active": true,
+            "nickname": null,
+            "todos": [
+                {"item": "Wash", "completed": false},
+                {"item": "Code", "completed": true}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = sample();
+        let compact = CompactValue::from_value(&value);
+        assert_eq!(compact.to_value(), value);
+        assert_eq!(serde_json::to_string(&compact).unwrap(), value.to_string());
+        assert!(compact.encoded_len() < value.to_string().len());
+        assert_eq!(compact.get(&["todos", "1", "item"]), Some(json!("Code")));
+        assert_eq!(compact.get(&["todos", "2"]), None);
+        assert_eq!(compact.get(&["name", "first"]), None);
+    }
+
+    #[test]
+    fn test_scalar_paths() {
+        // `todos` is the last member, its scalar bytes end the tape
+        let mut compact = CompactValue::from_value(&json!({"a": 5, "todos": true}));
+        assert_eq!(compact.get(&["a", "b"]), None);
+        assert_eq!(compact.get(&["todos", "0"]), None);
+        assert!(!compact.set(&["a", "b"], &json!(1)));
+        assert!(!compact.set(&["todos", "item"], &json!(1)));
+        assert_eq!(compact.to_value(), json!({"a": 5, "todos": true}));
+
+        let mut root = CompactValue::from_value(&json!(7));
+        assert_eq!(root.get(&["a"]), None);
+        assert!(!root.set(&["a"], &json!(1)));
+        let mut doc = JsonDoc::Compact(root);
+        assert_eq!(doc.dot_find("a.b"), None);
+        assert!(!doc.set_in_place(&[("a".to_owned(), json!(1))]));
+        assert_eq!(doc.to_value(), json!(7));
+    }
+
+    #[test]
+    fn test_set_in_place() {
+        let mut compact = CompactValue::from_value(&sample());
+        assert!(compact.set(&["todos", "0", "completed"], &json!({"at": "today"})));
+        assert!(compact.set(&["address"], &json!("Accra")));
+        assert!(compact.set(&["todos", "1", "due"], &json!(3)));
+        assert!(!compact.set(&["todos", "5", "item"], &json!("Sleep")));
+        assert!(!compact.set(&["missing", "item"], &json!(1)));
+
+        let mut expected = sample();
+        expected["todos"][0]["completed"] = json!({"at": "today"});
+        expected["address"] = json!("Accra");
+        expected["todos"][1]["due"] = json!(3);
+        assert_eq!(compact.to_value(), expected);
+        assert_eq!(serde_json::to_string(&compact).unwrap(), expected.to_string());
+
+        // a failing pair leaves the document as it was
+        let mut doc = JsonDoc::Compact(compact);
+        let items = vec![("age".to_owned(), json!(32)), ("todos.>.item".to_owned(), json!("Rest"))];
+        assert!(!doc.set_in_place(&items));
+        assert_eq!(doc.dot_find("age"), Some(json!(31)));
+        assert_eq!(doc.dot_find("todos.1.item"), Some(json!("Code")));
+        assert_eq!(doc.dot_find("todos.>.item"), Some(json!("Code")));
+        assert_eq!(doc.dot_find("todos.2"), None);
+    }
+}
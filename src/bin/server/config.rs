@@ -8,6 +8,8 @@ use std::process;
 
 lazy_static! {
     static ref CONFIG_HASH_MAP : Arc<RwLock<HashMap<String, String>>> = Arc::new(RwLock::new(HashMap::new()));
+    /// values from the command line and `ESCANOR_*` variables, they win over the file on reloads too
+    static ref CONF_OVERRIDES : RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
 }
 
 
@@ -16,6 +18,9 @@ lazy_static! {
 pub struct DatabaseConf {
     pub save_after: usize,
     pub mutations: usize,
+    pub dir: Option<String>,
+    pub dbfilename: Option<String>,
+    pub json_encoding: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -29,6 +34,29 @@ pub struct NetConf {
     pub bind: String,
     pub max_packet: usize,
     pub max_connections: usize,
+    pub unixsocket: Option<String>,
+    pub unixsocketperm: Option<usize>,
+}
+
+impl NetConf {
+    /// `bind` holds one or more space separated addresses, IPv6 included
+    pub fn bind_addresses(&self) -> Vec<String> {
+        self.bind.split_whitespace().map(|s| s.to_owned()).collect()
+    }
+
+    /// `unixsocketperm` is written like a chmod mode, e.g `770`
+    pub fn unix_socket_mode(&self) -> Option<u32> {
+        self.unixsocketperm.and_then(|p| u32::from_str_radix(&p.to_string(), 8).ok())
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TlsConf {
+    pub port: usize,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub ca_cert_file: Option<String>,
+    pub auth_clients: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -36,6 +64,7 @@ pub struct Conf {
     pub database: DatabaseConf,
     pub network: NetConf,
     pub server: Option<ServerConf>,
+    pub tls: Option<TlsConf>,
 }
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -43,10 +72,16 @@ use tokio::fs::{OpenOptions, File};
 
 use serde_yaml;
 use crate::file_dirs;
+use std::path::PathBuf;
 
 use nom::AsBytes;
 use serde_yaml::{Value, Mapping};
 
+use glob::Pattern;
+use crate::command::*;
+use crate::printer::*;
+use crate::{db, client, acl, compact};
+
 pub async fn load_conf(force_rewrite: bool) -> Result<(), String> {
     debug!("Opening config...");
     let path = match file_dirs::config_file_path() {
@@ -62,34 +97,20 @@ pub async fn load_conf(force_rewrite: bool) -> Result<(), String> {
             Ok(_) => {}
             Err(e) => { return Err(e); }
         };
-        load_conf(false);
     } else if !path.exists() && !force_rewrite {
-        panic!("Config file not found");
-        return Err("Config file not found".to_string());
+        return Err(format!("Config file not found: {}", path.display()));
     }
     //path.join("");
     //rewrite()
-    let mut file: File = match OpenOptions::new().read(true).open(path.clone()).await {
-        Err(_e) => {
-            panic!("Configuration file not loaded");
-            return Err("Configuration file not loaded".to_owned());
+    let contents = read_conf_file(&path).await?;
+    let conf: Conf = match serde_yaml::from_slice(&contents) {
+        Ok(t) => t,
+        Err(e) => {
+            return Err(format!("Error loading config file {}: {}, run with --reset to reset config file.", path.display(), e));
         }
-        Ok(file) => file,
     };
 
-    let mut contents: Vec<u8> = vec![];
-    let _n: usize = match file.read_to_end(&mut contents).await {
-        Ok(n) => {
-            n
-        }
-        Err(_e) => {
-            return Err("Error".to_owned());
-        }
-    };
-    let conf: Conf = serde_yaml::from_slice(&contents).unwrap_or_else(|err| {
-        eprintln!("Error loading config file, run with --reset to reset config file.");
-        process::exit(1);
-    });
+    acl::load_from_yaml(&contents)?;
 
     let conf_map = conf.to_map();
 
@@ -100,12 +121,142 @@ pub async fn load_conf(force_rewrite: bool) -> Result<(), String> {
     conf_map.iter().for_each(|(k, v)| {
         config_map.insert(k.to_owned(), v.to_owned());
     });
+    acl::set_require_auth(config_map.get("server.require_auth").map(|s| s.as_str()).unwrap_or(""));
     info!("Configuration loaded from:{}", path.as_os_str().to_str().unwrap());
     Ok(())
 }
 
+async fn read_conf_file(path: &PathBuf) -> Result<Vec<u8>, String> {
+    let mut file: File = match OpenOptions::new().read(true).open(path.clone()).await {
+        Err(e) => {
+            return Err(format!("Configuration file not loaded: {}", e));
+        }
+        Ok(file) => file,
+    };
+
+    let mut contents: Vec<u8> = vec![];
+    return match file.read_to_end(&mut contents).await {
+        Ok(_n) => {
+            Ok(contents)
+        }
+        Err(e) => {
+            Err(format!("Configuration file not read: {}", e))
+        }
+    };
+}
+
+/// Re-reads the config file and applies the mutable keys that changed, keys that can't be
+/// changed without a restart are left untouched and reported
+pub async fn reload_conf() -> Result<(), String> {
+    let path = match file_dirs::config_file_path() {
+        None => { return Err("Config file path not found".to_owned()); }
+        Some(p) => { p }
+    };
+    let contents = read_conf_file(&path).await?;
+    let conf: Conf = match serde_yaml::from_slice(&contents) {
+        Ok(t) => t,
+        Err(e) => {
+            return Err(format!("Invalid config file, keeping current configuration: {}", e));
+        }
+    };
+    let mut new_map = conf.to_map();
+    new_map.extend(CONF_OVERRIDES.read().unwrap().clone());
+    for (key, value) in &new_map {
+        if let Err(e) = validate_conf_value(key, value) {
+            return Err(format!("Invalid config file, keeping current configuration: {}", e));
+        }
+    }
+    if let Err(e) = acl::load_from_yaml(&contents) {
+        return Err(format!("Invalid config file, keeping current configuration: {}", e));
+    }
+
+    let changes = {
+        let config_map: RwLockReadGuard<HashMap<String, String>> = CONFIG_HASH_MAP.read().unwrap();
+        diff_conf(&config_map, &new_map)
+    };
+    if changes.is_empty() {
+        info!("Configuration reloaded, nothing changed");
+        return Ok(());
+    }
+
+    for (key, value) in changes {
+        if !is_mutable_conf_key(&key) {
+            warn!("Config {} changed in file but can not be changed at runtime, restart to apply", key);
+            continue;
+        }
+        set_conf_by_key(&key, &value);
+        on_conf_changed(&key);
+        info!("Config {} reloaded", key);
+    }
+    Ok(())
+}
+
+/// Lists the keys whose values differ between two config maps, keys missing from `new`
+/// are reported with an empty value
+fn diff_conf(current: &HashMap<String, String>, new: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut changes: Vec<(String, String)> = vec![];
+    for (key, value) in new {
+        if current.get(key) != Some(value) {
+            changes.push((key.to_owned(), value.to_owned()));
+        }
+    }
+    for (key, value) in current {
+        if !new.contains_key(key) && !value.is_empty() {
+            changes.push((key.to_owned(), String::new()));
+        }
+    }
+    changes.sort();
+    changes
+}
+
+/// Polls the config file modification time and reloads it when it changes.
+///
+/// Polling is used rather than inotify on purpose: editors save by renaming a new file over the
+/// old one and Kubernetes swaps a symlink to update a mounted ConfigMap, both of which drop an
+/// inotify watch on the file. A check every two seconds is cheap and works on every platform.
+pub async fn watch_conf_file() {
+    let path = match file_dirs::config_file_path() {
+        None => { return; }
+        Some(p) => { p }
+    };
+    let modified_time = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let mut last_modified = modified_time(&path);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(2));
+    info!("Watching config file:{}", path.as_os_str().to_str().unwrap());
+    loop {
+        interval.tick().await;
+        let modified = modified_time(&path);
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        info!("Config file changed, reloading");
+        if let Err(e) = reload_conf().await {
+            error!("{}", e);
+        }
+    }
+}
 
-const MUTABLE_CONF_KEYS: [&str; 2] = ["database.save_after", "database.mutations"];
+const MUTABLE_CONF_KEYS: [&str; 6] = [
+    "database.save_after",
+    "database.mutations",
+    "database.json_encoding",
+    "network.max_packet",
+    "network.max_connections",
+    "server.require_auth",
+];
+
+/// Config keys that hold numbers and must parse as such before being stored
+const NUMERIC_CONF_KEYS: [&str; 7] = [
+    "database.save_after",
+    "database.mutations",
+    "network.port",
+    "network.max_packet",
+    "network.max_connections",
+    "network.unixsocketperm",
+    "tls.port",
+];
 
 impl Conf {
     fn to_map(&self) -> HashMap<String, String> {
@@ -113,10 +264,25 @@ impl Conf {
 
         map.insert("database.save_after".to_owned(), self.database.save_after.to_string());
         map.insert("database.mutations".to_owned(), self.database.mutations.to_string());
-        map.insert("network.port".to_owned(), self.database.save_after.to_string());
-        map.insert("network.bind".to_owned(), self.database.save_after.to_string());
-        map.insert("network.max_packet".to_owned(), self.database.save_after.to_string());
-        map.insert("network.max_connections".to_owned(), self.database.save_after.to_string());
+        if let Some(dir) = &self.database.dir {
+            map.insert("database.dir".to_owned(), dir.to_owned());
+        }
+        if let Some(dbfilename) = &self.database.dbfilename {
+            map.insert("database.dbfilename".to_owned(), dbfilename.to_owned());
+        }
+        if let Some(json_encoding) = &self.database.json_encoding {
+            map.insert("database.json_encoding".to_owned(), json_encoding.to_owned());
+        }
+        map.insert("network.port".to_owned(), self.network.port.to_string());
+        map.insert("network.bind".to_owned(), self.network.bind.to_owned());
+        map.insert("network.max_packet".to_owned(), self.network.max_packet.to_string());
+        map.insert("network.max_connections".to_owned(), self.network.max_connections.to_string());
+        if let Some(unixsocket) = &self.network.unixsocket {
+            map.insert("network.unixsocket".to_owned(), unixsocket.to_owned());
+        }
+        if let Some(unixsocketperm) = self.network.unixsocketperm {
+            map.insert("network.unixsocketperm".to_owned(), unixsocketperm.to_string());
+        }
 
 
         let null_value = Value::Null;
@@ -133,6 +299,21 @@ impl Conf {
             }
         };
 
+        if let Some(tls_conf) = &self.tls {
+            map.insert("tls.port".to_owned(), tls_conf.port.to_string());
+            let files = [
+                ("tls.cert_file", &tls_conf.cert_file),
+                ("tls.key_file", &tls_conf.key_file),
+                ("tls.ca_cert_file", &tls_conf.ca_cert_file),
+                ("tls.auth_clients", &tls_conf.auth_clients),
+            ];
+            for (key, value) in files.iter() {
+                if let Some(value) = value {
+                    map.insert(key.to_string(), value.to_owned());
+                }
+            }
+        }
+
         map
     }
     fn from_rw(map: &RwLockReadGuard<HashMap<String, String>>) -> Conf {
@@ -150,11 +331,16 @@ impl Conf {
             bind: map.get("network.bind").unwrap_or(&default_n_bind).to_owned(),
             max_packet: map.get("network.max_packet").unwrap_or(&default_n_packet).parse::<usize>().unwrap(),
             max_connections: map.get("network.max_connections").unwrap_or(&default_n_conns).parse::<usize>().unwrap(),
+            unixsocket: map.get("network.unixsocket").cloned(),
+            unixsocketperm: map.get("network.unixsocketperm").and_then(|p| p.parse::<usize>().ok()),
         };
 
         let db_conf = DatabaseConf {
             save_after: map.get("database.save_after").unwrap_or(&default_d_save_after).parse::<usize>().unwrap(),
             mutations: map.get("database.mutations").unwrap_or(&default_d_muts).parse::<usize>().unwrap(),
+            dir: map.get("database.dir").cloned(),
+            dbfilename: map.get("database.dbfilename").cloned(),
+            json_encoding: map.get("database.json_encoding").cloned(),
         };
 
 
@@ -169,10 +355,24 @@ impl Conf {
             }
         };
 
+        // TLS stays off unless one of its keys is set
+        let tls_conf = if map.keys().any(|k| k.starts_with("tls.")) {
+            Some(TlsConf {
+                port: map.get("tls.port").and_then(|p| p.parse::<usize>().ok()).unwrap_or(0),
+                cert_file: map.get("tls.cert_file").cloned(),
+                key_file: map.get("tls.key_file").cloned(),
+                ca_cert_file: map.get("tls.ca_cert_file").cloned(),
+                auth_clients: map.get("tls.auth_clients").cloned(),
+            })
+        } else {
+            None
+        };
+
         Conf {
             database: db_conf,
             network: net_conf,
             server: Some(server_conf),
+            tls: tls_conf,
         }
     }
 }
@@ -194,6 +394,21 @@ pub fn get_conf_by_key(key: &String) -> Option<String> {
     return Some(value.to_owned());
 }
 
+/// Sets a key from the command line or the environment, it keeps that value when the file is reloaded
+pub fn set_conf_override(key: &str, value: &str) {
+    CONF_OVERRIDES.write().unwrap().insert(key.to_owned(), value.to_owned());
+    set_conf_by_key(key, value);
+}
+
+pub fn set_conf_by_key(key: &str, value: &str) {
+    let mut config_map: RwLockWriteGuard<HashMap<String, String>> = CONFIG_HASH_MAP.write().unwrap();
+    config_map.insert(key.to_owned(), value.to_owned());
+    // an alias for the password of the ACL default user
+    if key == "server.require_auth" {
+        acl::set_require_auth(value);
+    }
+}
+
 pub async fn write_default_config_file() -> Result<(), String> {
     const DEFAULT_CONFIG_FILE: &str = r#"
 ---
@@ -216,30 +431,58 @@ database:
   # This indicates the number of mutations needed for the sheduler to save to the database
   # on the disk. A mutation is counted as every successful write to the in memory dabase
   mutations: 5
+  # Directory where the database dump is stored, defaults to a system directory
+  #dir: /var/lib/escanor
+  # Name of the database dump file
+  #dbfilename: dump.esdb
+  # How JSON documents are kept in memory: tree, or compact for a smaller binary encoding
+  # with slower whole document reads. Documents take the new encoding when next written
+  #json_encoding: tree
 
 #Network configuation
 network:
   # Server port
   port: 6379
-  # Address which the server should bind to
+  # Addresses which the server should bind to, separated by spaces e.g "127.0.0.1 ::1"
   bind: 127.0.0.1
   # Maximum message the server can recieve
   max_packet: 10 #MB
   # Maximum number of client connection, 0 means not limit
   max_connections: 0
+  # Unix socket to listen on next to the TCP port, and its permissions
+  #unixsocket: /tmp/escanor.sock
+  #unixsocketperm: 700
 # uncomment require_auth to to require authentication for server communication
 server:
   #require_auth: mypassword
+# uncomment the tls section to accept TLS connections on tls.port, next to the plain port
+#tls:
+#  port: 6380
+#  cert_file: /etc/escanor/tls/server.crt
+#  key_file: /etc/escanor/tls/server.key
+#  # CA used to verify client certificates
+#  ca_cert_file: /etc/escanor/tls/ca.crt
+#  # yes requires a client certificate, optional verifies one when sent, no ignores them
+#  auth_clients: yes
+# uncomment the acl section to add users, passwords are given as #<sha256 of the password>
+# e.g `echo -n mypassword | sha256sum`, rules follow the redis ACL SETUSER syntax
+#acl:
+#  reader: "on #89e01536ac207279409d4de1e5253e01f4a1769e696db0d6062ca9b8f56767c8 ~user:* +@read -@json-read"
+#  default: "-@dangerous"
 "#;
     debug!("Resetting configuration file");
     let path = match file_dirs::config_file_path() {
         None => { return Err("Error reading file path".to_owned()); }
         Some(p) => { p }
     };
-    let mut file = match OpenOptions::new().write(true).create(true).truncate(true).open(path).await {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            return Err(format!("Can not create config directory {}: {}", dir.display(), e));
+        }
+    }
+    let mut file = match OpenOptions::new().write(true).create(true).truncate(true).open(path.clone()).await {
         Err(e) => {
-            println!("{}", e);
-            return Err("Creating reading file path".to_owned());
+            return Err(format!("Can not write config file {}: {}", path.display(), e));
         }
         Ok(file) => file,
     };
@@ -249,8 +492,305 @@ server:
         Ok(_) => {
             Ok(())
         }
+        Err(e) => {
+            Err(format!("Can not write config file {}: {}", path.display(), e))
+        }
+    };
+}
+
+/// Every key that can be set from the config file, the command line or the environment
+pub const CONF_KEYS: [&str; 17] = [
+    "database.save_after",
+    "database.mutations",
+    "database.dir",
+    "database.dbfilename",
+    "database.json_encoding",
+    "network.port",
+    "network.bind",
+    "network.max_packet",
+    "network.max_connections",
+    "network.unixsocket",
+    "network.unixsocketperm",
+    "server.require_auth",
+    "tls.port",
+    "tls.cert_file",
+    "tls.key_file",
+    "tls.ca_cert_file",
+    "tls.auth_clients",
+];
+
+/// Environment variable overriding a config key, e.g `ESCANOR_NETWORK_PORT` for `network.port`
+pub fn env_var_name(key: &str) -> String {
+    format!("ESCANOR_{}", key.replace('.', "_").to_uppercase())
+}
+
+/// Applies `ESCANOR_*` environment variables on top of the loaded config file
+pub fn apply_env_overrides() -> Result<(), String> {
+    for key in CONF_KEYS.iter() {
+        let var = env_var_name(key);
+        if let Ok(value) = std::env::var(&var) {
+            if let Err(e) = validate_conf_value(key, &value) {
+                return Err(format!("Invalid {}: {}", var, e));
+            }
+            debug!("Config {} set from {}", key, var);
+            set_conf_override(key, &value);
+        }
+    }
+    Ok(())
+}
+
+pub fn is_mutable_conf_key(key: &str) -> bool {
+    MUTABLE_CONF_KEYS.contains(&key)
+}
+
+/// Checks that a value can be stored under a config key
+pub fn validate_conf_value(key: &str, value: &str) -> Result<(), String> {
+    if NUMERIC_CONF_KEYS.contains(&key) && value.parse::<usize>().is_err() {
+        return Err(format!("invalid value '{}' for '{}', expected a non-negative integer", value, key));
+    }
+    if key == "database.save_after" && value == "0" {
+        return Err("'database.save_after' must be greater than 0".to_owned());
+    }
+    if key == "network.unixsocketperm" && u32::from_str_radix(value, 8).map(|p| p > 0o777).unwrap_or(true) {
+        return Err(format!("invalid value '{}' for 'network.unixsocketperm', expected an octal mode such as 700", value));
+    }
+    if key == "tls.auth_clients" && !["yes", "no", "optional"].contains(&value) {
+        return Err(format!("invalid value '{}' for 'tls.auth_clients', expected yes, no or optional", value));
+    }
+    if key == "database.json_encoding" && !["tree", "compact"].contains(&value) {
+        return Err(format!("invalid value '{}' for 'database.json_encoding', expected tree or compact", value));
+    }
+    Ok(())
+}
+
+/// Applies the side effects of a config key changing at runtime
+pub fn on_conf_changed(key: &str) {
+    match key {
+        "database.save_after" => {
+            db::reschedule_save();
+        }
+        "database.json_encoding" => {
+            compact::set_enabled(conf().database.json_encoding.as_deref() == Some("compact"));
+        }
+        _ => {}
+    }
+}
+
+pub fn config_get(cmd: &ConfigGetCmd) -> String {
+    let pattern_matcher = match Pattern::new(&cmd.arg_pattern) {
+        Ok(t) => t,
         Err(_e) => {
-            Err("".to_owned())
+            return print_err("ERR invalid pattern");
         }
     };
+
+    let config_map: RwLockReadGuard<HashMap<String, String>> = CONFIG_HASH_MAP.read().unwrap();
+    let mut keys: Vec<&String> = config_map.keys().filter(|k| pattern_matcher.matches(k)).collect();
+    keys.sort();
+
+    let mut items: Vec<&String> = vec![];
+    for key in keys {
+        items.push(key);
+        items.push(config_map.get(key).unwrap());
+    }
+    print_string_arr(items)
+}
+
+pub fn config_set(cmd: &ConfigSetCmd) -> String {
+    let key = cmd.arg_param.to_lowercase();
+    if !CONFIG_HASH_MAP.read().unwrap().contains_key(&key) && !is_mutable_conf_key(&key) {
+        return print_err(&format!("ERR unknown config key '{}'", key));
+    }
+    if !is_mutable_conf_key(&key) {
+        return print_err(&format!("ERR config key '{}' can not be changed at runtime", key));
+    }
+    if let Err(e) = validate_conf_value(&key, &cmd.arg_value) {
+        return print_err(&format!("ERR {}", e));
+    }
+    set_conf_by_key(&key, &cmd.arg_value);
+    on_conf_changed(&key);
+    info!("Config {} set at runtime", key);
+    print_ok()
+}
+
+pub fn config_reset_stat(_cmd: &ConfigResetStatCmd) -> String {
+    client::reset_stats();
+    print_ok()
+}
+
+pub fn config_rewrite(_cmd: &ConfigRewriteCmd) -> String {
+    let path = match file_dirs::config_file_path() {
+        None => { return print_err("ERR config file path not found"); }
+        Some(p) => { p }
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(e) => {
+            return print_err(&format!("ERR reading config file: {}", e));
+        }
+    };
+
+    let rewritten = {
+        let config_map: RwLockReadGuard<HashMap<String, String>> = CONFIG_HASH_MAP.read().unwrap();
+        rewrite_yaml(&contents, &config_map)
+    };
+
+    let mut tmp_path = path.clone();
+    tmp_path.set_extension("yaml.tmp");
+    if let Err(e) = std::fs::write(&tmp_path, rewritten.as_bytes()) {
+        return print_err(&format!("ERR writing config file: {}", e));
+    }
+    if let Err(e) = std::fs::rename(&tmp_path, &path) {
+        return print_err(&format!("ERR writing config file: {}", e));
+    }
+    info!("Configuration rewritten to:{}", path.as_os_str().to_str().unwrap());
+    print_ok()
+}
+
+fn yaml_scalar(value: &str) -> String {
+    let is_plain = !value.is_empty()
+        && value.trim() == value
+        && !value.starts_with(|c: char| "!&*-?:,[]{}#|>@`\"'%".contains(c))
+        && !value.contains(": ")
+        && !value.contains(" #");
+    if is_plain {
+        return value.to_owned();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn is_conf_key_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces the values of `section.key` entries in a yaml config file with the values in
+/// `map`, keeping comments and layout. Commented out entries such as `#require_auth: x`
+/// are uncommented when the key has a value and keys missing from the file are appended.
+fn rewrite_yaml(contents: &str, map: &HashMap<String, String>) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut written_keys: Vec<String> = vec![];
+    let mut sections: Vec<String> = vec![];
+    let mut section = String::new();
+
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+
+        let header = trimmed.trim_end().trim_end_matches(':');
+        if indent.is_empty() && trimmed.trim_end().ends_with(':') && is_conf_key_name(header) {
+            // leaving a section, append keys the file did not have
+            append_missing_keys(&mut lines, &section, map, &written_keys);
+            section = header.to_owned();
+            sections.push(section.to_owned());
+            lines.push(line.to_owned());
+            continue;
+        }
+
+        let (is_commented, entry) = if trimmed.starts_with('#') {
+            (true, trimmed[1..].trim_start())
+        } else {
+            (false, trimmed)
+        };
+
+        let key = match entry.find(':') {
+            Some(i) if !section.is_empty() && is_conf_key_name(&entry[..i]) => &entry[..i],
+            _ => {
+                lines.push(line.to_owned());
+                continue;
+            }
+        };
+
+        let full_key = format!("{}.{}", section, key);
+        let value = match map.get(&full_key) {
+            Some(v) if !v.is_empty() && !written_keys.contains(&full_key) => v,
+            _ => {
+                lines.push(line.to_owned());
+                continue;
+            }
+        };
+
+        // keep trailing comments such as `#secs`
+        let rest = &entry[key.len() + 1..];
+        let comment = match rest.find(" #") {
+            Some(i) if !is_commented => format!(" {}", rest[i..].trim()),
+            _ => String::new(),
+        };
+        let indent = if indent.is_empty() { "  " } else { indent };
+        lines.push(format!("{}{}: {}{}", indent, key, yaml_scalar(value), comment));
+        written_keys.push(full_key);
+    }
+    append_missing_keys(&mut lines, &section, map, &written_keys);
+
+    let mut missing_sections: Vec<&str> = map.iter()
+        .filter(|(k, v)| !v.is_empty() && !written_keys.contains(*k))
+        .filter_map(|(k, _)| k.split('.').next())
+        .filter(|s| !sections.iter().any(|section| section == s))
+        .collect();
+    missing_sections.sort();
+    missing_sections.dedup();
+    for missing_section in missing_sections {
+        lines.push(format!("{}:", missing_section));
+        append_missing_keys(&mut lines, missing_section, map, &written_keys);
+    }
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+    out
+}
+
+fn append_missing_keys(lines: &mut Vec<String>, section: &str, map: &HashMap<String, String>, written_keys: &Vec<String>) {
+    if section.is_empty() {
+        return;
+    }
+    let prefix = format!("{}.", section);
+    let mut missing: Vec<(&String, &String)> = map.iter()
+        .filter(|(k, v)| k.starts_with(&prefix) && !written_keys.contains(*k) && !v.is_empty())
+        .collect();
+    missing.sort();
+    for (k, v) in missing {
+        lines.push(format!("  {}: {}", &k[prefix.len()..], yaml_scalar(v)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_conf() {
+        let mut current = HashMap::new();
+        current.insert("database.save_after".to_owned(), "60".to_owned());
+        current.insert("network.port".to_owned(), "6379".to_owned());
+        current.insert("server.require_auth".to_owned(), "secret".to_owned());
+        let mut new = HashMap::new();
+        new.insert("database.save_after".to_owned(), "30".to_owned());
+        new.insert("network.port".to_owned(), "6379".to_owned());
+
+        assert_eq!(diff_conf(&current, &new), vec![
+            ("database.save_after".to_owned(), "30".to_owned()),
+            ("server.require_auth".to_owned(), "".to_owned()),
+        ]);
+
+        // a port given on the command line is not a change of the file
+        current.insert("network.port".to_owned(), "7000".to_owned());
+        let mut overrides = HashMap::new();
+        overrides.insert("network.port".to_owned(), "7000".to_owned());
+        new.extend(overrides);
+        assert_eq!(diff_conf(&current, &new).len(), 2);
+    }
+
+    #[test]
+    fn test_rewrite_yaml_keeps_comments() {
+        let contents = "#Database configuation\ndatabase:\n  # interval\n  save_after: 60 #secs\n  mutations: 5\nnetwork:\n  bind: 127.0.0.1\nserver:\n  #require_auth: mypassword\n";
+        let mut map = HashMap::new();
+        map.insert("database.save_after".to_owned(), "30".to_owned());
+        map.insert("database.mutations".to_owned(), "5".to_owned());
+        map.insert("network.bind".to_owned(), "127.0.0.1 ::1".to_owned());
+        map.insert("network.port".to_owned(), "6380".to_owned());
+        map.insert("server.require_auth".to_owned(), "secret".to_owned());
+
+        let rewritten = rewrite_yaml(contents, &map);
+        assert_eq!(rewritten, "#Database configuation\ndatabase:\n  # interval\n  save_after: 30 #secs\n  mutations: 5\nnetwork:\n  bind: 127.0.0.1 ::1\n  port: 6380\nserver:\n  require_auth: secret\n");
+    }
 }
@@ -6,7 +6,13 @@ use std::sync::atomic::{AtomicBool, Ordering, AtomicUsize, AtomicI64, AtomicU64}
 use std::sync::RwLock;
 
 use rstar::RTree;
-use crate::{util, file_dirs};
+use crate::{util, file_dirs, client, snapshot, rdb, logical, replication, acl, index, json, schema, compact};
+use crate::compact::JsonDoc;
+use crate::logical::{GeoMember, LogicalRecord};
+use crate::rdb::{RdbReader, RdbValue, RdbWriter};
+use crate::snapshot::{ProgressReader, Record, RecordRef, SNAPSHOT_MAGIC};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
 use crate::command::*;
 use lazy_static::lazy_static;
 use crate::printer::*;
@@ -32,9 +38,8 @@ extern crate json_dotpath;
 
 use rmp_serde;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::fs::OpenOptions;
 use tokio::time::Instant;
+use tokio::sync::Notify;
 
 
 use self::dashmap::{DashMap, DashSet};
@@ -96,20 +101,23 @@ lazy_static! {
     //Data
     static ref KEYS_MAP : Arc<DashMap<String, KeyType>> = Arc::new(DashMap::new());
     static ref KV_BTREE : Arc<DashMap<String, ESValue>> = Arc::new(DashMap::new());
-    static ref JSON_BTREE : Arc<DashMap<String, Value>> = Arc::new(DashMap::new());
+    static ref JSON_BTREE : Arc<DashMap<String, JsonDoc>> = Arc::new(DashMap::new());
     static ref GEO_BTREE : Arc<DashMap<String, HashSet<GeoPoint2D>>> = Arc::new(DashMap::new());
     static ref GEO_RTREE : Arc<DashMap<String, RTree<GeoPoint2D>>> = Arc::new(DashMap::new());
     //Progress
     static ref LAST_SAVE_TIME : AtomicI64 = AtomicI64::new(0);
     static ref LAST_SAVE_DURATION : AtomicU64 = AtomicU64::new(0);
+    static ref LAST_SAVE_STATUS_OK : AtomicBool = AtomicBool::new(true);
+    static ref LAST_LOAD_DURATION : AtomicU64 = AtomicU64::new(0);
     static ref MUTATION_COUNT_SINCE_SAVE : AtomicUsize = AtomicUsize::new(0);
+    static ref SAVE_SCHEDULE_CHANGED : Arc<Notify> = Arc::new(Notify::new());
 }
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Database {
     btree: DashMap<String, ESValue>,
-    json_btree: DashMap<String, Value>,
+    json_btree: DashMap<String, JsonDoc>,
     geo_tree: DashMap<String, HashSet<GeoPoint2D>>,
 }
 
@@ -126,6 +134,12 @@ fn reset_mutation_counter() {
 }
 
 
+/// Takes the mutations written by a save off `counter`, without wrapping when RESETSTAT or a
+/// flush reset it while the save was running
+fn mutations_saved(counter: &AtomicUsize, count: usize) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(count)));
+}
+
 fn get_mutation_count() -> usize {
     MUTATION_COUNT_SINCE_SAVE.load(Ordering::Relaxed)
 }
@@ -150,10 +164,18 @@ fn set_save_in_progress(b : bool){
     SAVE_IN_PROCEES.store(b, Ordering::SeqCst)
 }
 
-fn is_save_in_progress() -> bool{
+pub fn is_save_in_progress() -> bool{
     SAVE_IN_PROCEES.load(Ordering::SeqCst)
 }
 
+/// Marks a save as running, fails if one already is
+fn claim_save() -> Result<(), String> {
+    match SAVE_IN_PROCEES.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("ERR Background save already in progress".to_owned())
+    }
+}
+
 
 fn is_key_valid_for_type( key: &str, key_type: KeyType) -> bool {
     let keys_map: Arc<DashMap<String, KeyType>> = KEYS_MAP.clone();
@@ -220,116 +242,261 @@ async fn load_db() {
 
     info!("Loading DB file: {}", path.as_os_str().to_str().unwrap());
 
-    let instant = Instant::now();
-
-    let mut file = match OpenOptions::new().read(true).open(path).await {
-        Ok(t) => t,
-        Err(_) => { return; }
+    let result = match tokio::task::spawn_blocking(move || load_db_blocking(path)).await {
+        Ok(result) => result,
+        Err(e) => Err(format!("Load task failed: {}", e))
     };
-    let mut content: Vec<u8> = vec![];
-    let total_byte_read = match file.read_to_end(&mut content).await {
-        Ok(t) => t,
-        Err(_) => { return; }
-    };
-
-    debug!("Total data read {}", total_byte_read);
+    if let Err(e) = result {
+        error!("{}", e);
+        return;
+    }
+    info!("Database loaded from disk: {} ms", LAST_LOAD_DURATION.load(Ordering::SeqCst));
+}
 
-    let saved_db: Database = match rmp_serde::decode::from_read_ref(&content) {
+/// Loads the dump at `path` and records how long it took for INFO
+fn load_db_blocking(path: PathBuf) -> Result<(), String> {
+    let instant = std::time::Instant::now();
+    let file = match std::fs::File::open(&path) {
         Ok(t) => t,
-        Err(_) => { return; }
+        Err(e) => { return Err(format!("Error opening database file {}: {}", path.display(), e)); }
     };
+    let total_bytes = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let reader = std::io::BufReader::new(ProgressReader::new(file, total_bytes));
+
+    let (index_defs, schema_defs) = read_snapshot(reader)
+        .map_err(|e| format!("Error reading database file {}: {}", path.display(), e))?;
+    for def in schema_defs {
+        let name = def.name.to_owned();
+        if let Err(e) = schema::set_schema(def) {
+            warn!("Could not restore schema {}: {}", name, e);
+        }
+    }
+    // indexes are filled once every document is loaded
+    for def in index_defs {
+        let name = def.name.to_owned();
+        if let Err(e) = index::create_index(def) {
+            warn!("Could not restore index {}: {}", name, e);
+        }
+    }
+    LAST_LOAD_DURATION.store(instant.elapsed().as_millis() as u64, Ordering::SeqCst);
+    Ok(())
+}
 
+/// Loads a dump into the stores and rebuilds the geo indexes, the index and schema definitions
+/// it holds are returned for the caller to restore
+fn read_snapshot<R: BufRead>(mut reader: R) -> Result<(Vec<index::IndexDef>, Vec<schema::SchemaDef>), String> {
     let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
-    let json_btree: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
     let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
-    let r_map: Arc<DashMap<String, RTree<GeoPoint2D>>> = GEO_RTREE.clone();
-
-    // geo_btree.clone_from(&saved_db.geo_tree);
 
-    &saved_db.geo_tree.iter().for_each(|data| {
-        geo_btree.insert(data.key().to_owned(), data.value().to_owned());
-        insert_key_with_deletion(&data.key(), KeyType::GEO);
-    }
-    );
-
-    &saved_db.json_btree.iter().for_each(|data| {
-        json_btree.insert(data.key().to_owned(), data.value().to_owned());
-        insert_key_with_deletion(&data.key(), KeyType::JSON);
-    }
-    );
+    let mut index_defs: Vec<index::IndexDef> = vec![];
+    let mut schema_defs: Vec<schema::SchemaDef> = vec![];
+    let is_stream = match reader.fill_buf() {
+        Ok(buf) => buf.starts_with(SNAPSHOT_MAGIC),
+        Err(e) => { return Err(e.to_string()); }
+    };
 
-    &saved_db.btree.iter().for_each(|data| {
-        btree.insert(data.key().to_owned(), data.value().to_owned());
-        insert_key_with_deletion(&data.key(), KeyType::KV);
+    if is_stream {
+        reader.consume(SNAPSHOT_MAGIC.len());
+        // records go straight into the live maps, so only one entry is decoded at a time
+        snapshot::read_records(reader, |record| {
+            match record {
+                Record::KV(k, v) => {
+                    btree.insert(k.to_owned(), v);
+                    insert_key_with_deletion(&k, KeyType::KV);
+                }
+                Record::Json(k, v) => {
+                    json_btree.insert(k.to_owned(), JsonDoc::new(v));
+                    insert_key_with_deletion(&k, KeyType::JSON);
+                }
+                Record::Geo(k, v) => {
+                    geo_btree.insert(k.to_owned(), v);
+                    insert_key_with_deletion(&k, KeyType::GEO);
+                }
+                Record::Index(def) => index_defs.push(def),
+                Record::Schema(def) => schema_defs.push(def),
+                Record::End => {}
+            }
+        })?;
+    } else {
+        // dumps written before snapshots were streamed hold a single encoded `Database`
+        let saved_db: Database = match rmp_serde::decode::from_read(reader) {
+            Ok(t) => t,
+            Err(e) => { return Err(format!("Error decoding database: {}", e)); }
+        };
+        restore_database(saved_db);
     }
-    );
 
-    geo_btree.iter().for_each(|data| {
-        let mut bulk_geo_hash_load: Vec<GeoPoint2D> = vec![];
+    rebuild_geo_indexes();
+    Ok((index_defs, schema_defs))
+}
 
-        data.value().iter().for_each(|p| {
-            bulk_geo_hash_load.push(p.clone())
-        });
+/// Moves the entries of a decoded `Database` into the stores, the geo indexes are left to the caller
+fn restore_database(saved_db: Database) {
+    let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
 
-        r_map.insert(data.key().to_owned(), RTree::bulk_load(bulk_geo_hash_load));
+    saved_db.geo_tree.into_iter().for_each(|(k, v)| {
+        geo_btree.insert(k.to_owned(), v);
+        insert_key_with_deletion(&k, KeyType::GEO);
+    });
+    saved_db.json_btree.into_iter().for_each(|(k, v)| {
+        json_btree.insert(k.to_owned(), v);
+        insert_key_with_deletion(&k, KeyType::JSON);
     });
+    saved_db.btree.into_iter().for_each(|(k, v)| {
+        btree.insert(k.to_owned(), v);
+        insert_key_with_deletion(&k, KeyType::KV);
+    });
+}
+
+/// Writes a snapshot for a replica full sync next to the dump file and returns its path, entries
+/// are streamed as in a save. The caller removes the file once it was sent.
+pub fn write_sync_file() -> Result<PathBuf, String> {
+    let mut path = match file_dirs::db_file_path() {
+        Some(t) => t,
+        None => { return Err("Database file path not found".to_owned()); }
+    };
+    // every replica syncing at the same time gets its own file
+    path.set_extension(format!("sync-{}.tmp", nanoid!(10, &util::ALPHA_NUMERIC)));
+    let file = match std::fs::File::create(&path) {
+        Ok(f) => f,
+        Err(e) => { return Err(format!("Error writing sync file {}: {}", path.display(), e)); }
+    };
+    let mut writer = std::io::BufWriter::new(file);
+    let result = write_records(&mut writer)
+        .and_then(|_| writer.flush().map_err(|e| format!("Error writing sync file {}: {}", path.display(), e)));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(path)
+}
 
-    let load_elapsed: Duration = instant.elapsed();
-    info!("Database loaded from disk: {} seconds", load_elapsed.as_secs());
+/// Replaces the whole dataset, its indexes and its schemas with a payload written by `write_sync_file`
+pub fn replace_database(content: &[u8]) -> Result<(), String> {
+    clear_db();
+    let (index_defs, schema_defs) = read_snapshot(content)?;
+    schema::replace_all(schema_defs);
+    index::replace_all(index_defs);
+    Ok(())
 }
 
-async fn save_db() {
-    let mut json_btree_copy = DashMap::<String, Value>::new();
-    let mut btree_copy = DashMap::<String, ESValue>::new();
-    let mut geo_btree_copy = DashMap::<String, HashSet<GeoPoint2D>>::new();
+/// Bulk loads the R-tree of every geo key, one rayon task per key
+fn rebuild_geo_indexes() {
+    let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+    let r_map: Arc<DashMap<String, RTree<GeoPoint2D>>> = GEO_RTREE.clone();
 
-    {
-        let json_btree: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
-        let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
-        let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+    let keys: Vec<String> = geo_btree.iter().map(|data| data.key().to_owned()).collect();
+    keys.par_iter().for_each(|key| {
+        let bulk_geo_hash_load: Vec<GeoPoint2D> = match geo_btree.get(key) {
+            None => { return; }
+            Some(data) => { data.value().iter().cloned().collect() }
+        };
+        r_map.insert(key.to_owned(), RTree::bulk_load(bulk_geo_hash_load));
+    });
+}
 
-        json_btree_copy.clone_from(&json_btree);
-        btree_copy.clone_from(&btree);
-        geo_btree_copy.clone_from(&geo_btree);
-    }
+/// Saves the database to disk, blocking the calling thread until the snapshot is written
+fn save_db_blocking() -> Result<(), String> {
+    claim_save()?;
+    write_claimed_snapshot()
+}
 
+/// Writes the snapshot for a save already marked as running by `claim_save`, then clears the mark
+fn write_claimed_snapshot() -> Result<(), String> {
+    let instant = std::time::Instant::now();
+    let mutations_at_start = get_mutation_count();
 
-    let db = Database {
-        btree: btree_copy,
-        geo_tree: geo_btree_copy,
-        json_btree: json_btree_copy,
-    };
+    let result = write_snapshot();
 
-    let content = match rmp_serde::encode::to_vec(&db) {
-        Ok(b) => { b }
+    set_last_save_time_duration(instant.elapsed().as_millis() as u64);
+    LAST_SAVE_STATUS_OK.store(result.is_ok(), Ordering::SeqCst);
+    match &result {
+        Ok(_) => {
+            // keep the mutations made while the snapshot was being written
+            mutations_saved(&MUTATION_COUNT_SINCE_SAVE, mutations_at_start);
+            set_last_save_time(Utc::now().timestamp());
+            info!("DB saved on disk in {} ms", get_last_save_time_duration());
+        }
         Err(e) => {
-            error!("Error saving: {}", e);
-            vec![]
+            error!("Error saving DB on disk: {}", e);
         }
     };
+    set_save_in_progress(false);
+    result
+}
 
-    debug!("total db bytes: {}", content.len());
+/// Writes the snapshot header, every entry, the index and schema definitions and the end record,
+/// returns the number of keys written
+fn write_records<W: Write>(writer: &mut W) -> Result<usize, String> {
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
+    let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+
+    // entries are streamed shard by shard instead of cloning the maps, see snapshot::write_map
+    snapshot::write_header(writer)?;
+    let mut total_keys = snapshot::write_map(writer, &btree, |k, v| RecordRef::KV(k, v))?;
+    total_keys += snapshot::write_map(writer, &json_btree, |k, v| RecordRef::Json(k, v))?;
+    total_keys += snapshot::write_map(writer, &geo_btree, |k, v| RecordRef::Geo(k, v))?;
+    for def in index::definitions() {
+        snapshot::write_record(writer, &RecordRef::Index(&def))?;
+    }
+    for def in schema::definitions() {
+        snapshot::write_record(writer, &RecordRef::Schema(&def))?;
+    }
+    snapshot::write_record(writer, &RecordRef::End)?;
+    Ok(total_keys)
+}
+
+fn write_snapshot() -> Result<(), String> {
     let path = match file_dirs::db_file_path() {
         Some(t) => t,
-        None => { return; }
+        None => { return Err("Database file path not found".to_owned()); }
     };
-    let _instant = Instant::now();
 
-    let mut file = match OpenOptions::new().write(true).create(true).open(path).await {
-        Ok(t) => t,
-        Err(_) => { return; }
-    };
-    match file.write_all(&content).await {
-        Ok(_) => {
-            reset_mutation_counter();
-            set_last_save_time(Utc::now().timestamp());
-            return;
+    // write to a temporary file first so a failed save never corrupts the previous dump
+    let mut tmp_path = path.clone();
+    tmp_path.set_extension("esdb.tmp");
+    let file = match std::fs::File::create(&tmp_path) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(format!("Error writing database file {}: {}", tmp_path.display(), e));
         }
+    };
+    let mut writer = std::io::BufWriter::new(file);
+    let total_keys = write_records(&mut writer)?;
+
+    let file = match writer.into_inner() {
+        Ok(f) => f,
         Err(e) => {
-            debug!("Error : {}", e);
-            return;
+            return Err(format!("Error writing database file {}: {}", tmp_path.display(), e));
         }
     };
+    if let Err(e) = file.sync_all() {
+        return Err(format!("Error writing database file {}: {}", tmp_path.display(), e));
+    }
+    debug!("total keys saved: {}", total_keys);
+
+    if let Err(e) = std::fs::rename(&tmp_path, &path) {
+        return Err(format!("Error replacing database file {}: {}", path.display(), e));
+    }
+    Ok(())
+}
+
+pub async fn save_db() -> Result<(), String> {
+    return match tokio::task::spawn_blocking(save_db_blocking).await {
+        Ok(result) => result,
+        Err(e) => Err(format!("Save task failed: {}", e))
+    };
+}
+
+/// Waits for a running background save to finish
+pub async fn wait_for_save() {
+    while is_save_in_progress() {
+        time::delay_for(Duration::from_millis(50)).await;
+    }
 }
 
 pub async fn init_db() {
@@ -341,6 +508,8 @@ pub async fn init_db() {
     lazy_static::initialize(&KEYS_REM_EX_HASH);
     lazy_static::initialize(&DELETED_KEYS_LIST);
 
+    // documents are loaded straight into the configured encoding
+    compact::set_enabled(crate::config::conf().database.json_encoding.as_deref() == Some("compact"));
     load_db().await;
 
     tokio::spawn(async {
@@ -383,25 +552,33 @@ pub async fn init_db() {
 
 
     tokio::spawn(async {
-        let conf = crate::config::conf();
-        let _save_interval = conf.database.save_after as u64;
-        let save_muts_cout = conf.database.mutations;
-        let mut interval = time::interval(Duration::from_secs(conf.database.save_after as u64));
         loop {
-            interval.tick().await;
-            let mut mutations = 0;
-            {
-                mutations = get_mutation_count();
-            }
-
-            let _current_ts = Utc::now().timestamp();
-            if mutations >= save_muts_cout {
-                save_db().await;
+            let save_after = Duration::from_secs(crate::config::conf().database.save_after as u64);
+            let mut interval = time::interval_at(Instant::now() + save_after, save_after);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = SAVE_SCHEDULE_CHANGED.notified() => {
+                        info!("Save schedule changed, saving every {} secs", crate::config::conf().database.save_after);
+                        break;
+                    }
+                }
+                let save_muts_count = crate::config::conf().database.mutations;
+                if get_mutation_count() >= save_muts_count && !is_save_in_progress() {
+                    if let Err(e) = save_db().await {
+                        error!("{}", e);
+                    }
+                };
             };
         };
     });
 }
 
+/// Restarts the save scheduler so a new `database.save_after` takes effect
+pub fn reschedule_save() {
+    SAVE_SCHEDULE_CHANGED.notify();
+}
+
 fn clear_db() {
     let keys_map: Arc<DashMap<String, KeyType>> = KEYS_MAP.clone();
     let b_map: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
@@ -409,7 +586,7 @@ fn clear_db() {
     let deleted_keys_map: Arc<DashSet<String>> = DELETED_KEYS_LIST.clone();
     let r_map: Arc<DashMap<String, RTree<GeoPoint2D>>> = GEO_RTREE.clone();
     let geo_map: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
-    let json_map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let json_map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
     increment_mutation_counter_by(b_map.len());
     increment_mutation_counter_by(k_map.len());
@@ -425,6 +602,7 @@ fn clear_db() {
     r_map.clear();
     geo_map.clear();
     json_map.clear();
+    index::clear_documents();
 }
 
 fn remove_expired_keys() {
@@ -444,51 +622,348 @@ pub fn last_save(_cmd: &LastSaveCmd) -> String {
 
 use crate::network::Context;
 use self::dashmap::mapref::one::{Ref, RefMut};
+use self::dashmap::mapref::entry::Entry;
 use self::json_dotpath::Error;
 
 pub fn auth(context: &mut Context, cmd: &AuthCmd) -> String {
-    context.client_auth_key = Some(cmd.arg_password.to_owned());
-    if !context.auth_is_required {
-        return print_ok();
+    if let Some(user) = cmd.arg_user.as_ref().filter(|u| *u != acl::DEFAULT_USER) {
+        return if acl::authenticate(user, &cmd.arg_password) {
+            context.user = Some(user.to_owned());
+            context.client_authenticated = true;
+            print_ok()
+        } else {
+            print_err("WRONGPASS invalid username-password pair or user is disabled.")
+        };
     }
+    // the default user's password is checked again on every command, see command::check_auth
+    let hash = acl::hash_password(&cmd.arg_password);
+    context.client_authenticated = acl::default_user_allowed(Some(&hash));
+    if !context.client_authenticated {
+        return print_err("WRONGPASS invalid username-password pair or user is disabled.");
+    }
+    context.user = None;
+    context.client_auth_key = Some(hash);
+    print_ok()
+}
 
-    let auth_key = match &context.auth_key {
-        Some(k) => k.to_owned(),
-        None => {
-            return print_err("ERR internal error");
+pub fn save(_cmd: &SaveCmd) -> String {
+    if let Err(e) = claim_save() {
+        return print_err(&e);
+    }
+    // the command can't await, block_in_place hands this worker's other tasks to another thread
+    // while the snapshot is written
+    return match tokio::task::block_in_place(write_claimed_snapshot) {
+        Ok(_) => print_ok(),
+        Err(e) => print_err(&e)
+    };
+}
+
+pub fn bg_save(_cmd: &BGSaveCmd) -> String {
+    // claimed here so two BGSAVEs can't both start a save
+    if let Err(e) = claim_save() {
+        return print_err(&e);
+    }
+    tokio::task::spawn(async {
+        let result = match tokio::task::spawn_blocking(write_claimed_snapshot).await {
+            Ok(result) => result,
+            Err(e) => {
+                set_save_in_progress(false);
+                Err(format!("Save task failed: {}", e))
+            }
+        };
+        if let Err(e) = result {
+            error!("Background save failed: {}", e);
         }
+    });
+    print_str("Background saving started")
+}
+
+/// Counts of what an RDB import brought in, by the escanor type each key was mapped to
+#[derive(Debug, Default)]
+pub struct RdbImportStats {
+    pub strings: usize,
+    pub json: usize,
+    pub geo: usize,
+    pub skipped: usize,
+}
+
+/// Imports a redis dump into the stores.
+///
+/// Strings go to the key value store, lists and sets become JSON arrays, hashes become JSON
+/// objects and sorted sets whose scores are all geohashes become geo sets. Other sorted sets,
+/// keys outside db 0 and already expired keys are skipped.
+pub fn import_rdb_file(path: &Path) -> Result<RdbImportStats, String> {
+    let file = match std::fs::File::open(path) {
+        Ok(t) => t,
+        Err(e) => { return Err(format!("Error opening RDB file {}: {}", path.display(), e)); }
     };
+    let mut reader = RdbReader::new(std::io::BufReader::new(file))?;
 
-    let client_auth_key = match &context.client_auth_key {
-        Some(k) => k.to_owned(),
-        None => {
-            return print_err("ERR internal error");
+    let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+    let rem_map: Arc<DashMap<String, i64>> = KEYS_REM_EX_HASH.clone();
+
+    let now = Utc::now().timestamp_millis();
+    let to_string = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+    let mut stats = RdbImportStats::default();
+
+    reader.read_entries(|entry| {
+        if entry.db != 0 || entry.expire_at.map_or(false, |t| t <= now) {
+            stats.skipped += 1;
+            return;
+        }
+        let key = to_string(entry.key);
+        match entry.value {
+            RdbValue::String(value) => {
+                let value = to_string(value);
+                let es_val = if util::is_integer(&value) {
+                    ESValue::Int(value.parse::<i64>().unwrap())
+                } else {
+                    ESValue::String(value)
+                };
+                btree.insert(key.to_owned(), es_val);
+                insert_key_with_deletion(&key, KeyType::KV);
+                // only key value entries expire in escanor
+                if let Some(expire_at) = entry.expire_at {
+                    rem_map.insert(key.to_owned(), expire_at / 1000);
+                }
+                stats.strings += 1;
+            }
+            RdbValue::List(items) | RdbValue::Set(items) => {
+                let items: Vec<Value> = items.into_iter().map(|i| Value::String(to_string(i))).collect();
+                json_btree.insert(key.to_owned(), JsonDoc::new(Value::Array(items)));
+                insert_key_with_deletion(&key, KeyType::JSON);
+                stats.json += 1;
+            }
+            RdbValue::Hash(fields) => {
+                let mut object = serde_json::Map::new();
+                for (field, value) in fields {
+                    object.insert(to_string(field), Value::String(to_string(value)));
+                }
+                json_btree.insert(key.to_owned(), JsonDoc::new(Value::Object(object)));
+                insert_key_with_deletion(&key, KeyType::JSON);
+                stats.json += 1;
+            }
+            RdbValue::ZSet(members) => {
+                let mut points: HashSet<GeoPoint2D> = HashSet::new();
+                for (member, score) in members {
+                    match rdb::geohash_decode(score) {
+                        Some((lat, lng)) => { points.insert(GeoPoint2D::with_cord(to_string(member), lat, lng)); }
+                        None => {
+                            warn!("Skipping sorted set {}, its scores are not geohashes", key);
+                            stats.skipped += 1;
+                            return;
+                        }
+                    }
+                }
+                geo_btree.insert(key.to_owned(), points);
+                insert_key_with_deletion(&key, KeyType::GEO);
+                stats.geo += 1;
+            }
+        }
+    })?;
+
+    rebuild_geo_indexes();
+    index::rebuild();
+    increment_mutation_counter_by(stats.strings + stats.json + stats.geo);
+    Ok(stats)
+}
+
+/// Writes the key value and geo stores as a redis loadable dump, JSON keys have no redis
+/// counterpart and are left out. Returns the number of keys written.
+pub fn export_rdb_file(path: &Path) -> Result<usize, String> {
+    let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
+    let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+    let rem_map: Arc<DashMap<String, i64>> = KEYS_REM_EX_HASH.clone();
+
+    let file = match std::fs::File::create(path) {
+        Ok(t) => t,
+        Err(e) => { return Err(format!("Error creating RDB file {}: {}", path.display(), e)); }
+    };
+    let expires = btree.iter().filter(|data| rem_map.contains_key(data.key())).count();
+    let mut writer = RdbWriter::new(std::io::BufWriter::new(file), (btree.len() + geo_btree.len()) as u64, expires as u64)?;
+
+    let mut count = 0;
+    for data in btree.iter() {
+        let value = match data.value() {
+            ESValue::String(s) => s.to_owned(),
+            ESValue::Int(i) => i.to_string(),
+        };
+        let expire_at = rem_map.get(data.key()).map(|t| *t.value() * 1000);
+        writer.write_string_entry(data.key().as_bytes(), value.as_bytes(), expire_at)?;
+        count += 1;
+    }
+    for data in geo_btree.iter() {
+        let mut members: Vec<(Vec<u8>, f64)> = vec![];
+        for point in data.value() {
+            match rdb::geohash_encode(point.x_cord(), point.y_cord()) {
+                Some(score) => members.push((point.tag.as_bytes().to_vec(), score)),
+                None => warn!("Skipping {} in {}, redis can't index it", point.tag, data.key()),
+            }
+        }
+        writer.write_zset_entry(data.key().as_bytes(), &members, None)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+fn dump_record(key: &str, with_ttl: bool) -> Option<LogicalRecord> {
+    let keys_map: Arc<DashMap<String, KeyType>> = KEYS_MAP.clone();
+    let key_type = keys_map.get(key)?.value().to_owned();
+    let record = match key_type {
+        KeyType::KV => {
+            let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
+            let rem_map: Arc<DashMap<String, i64>> = KEYS_REM_EX_HASH.clone();
+            let value = logical::es_value_to_json(btree.get(key)?.value());
+            let ttl = if with_ttl {
+                rem_map.get(key).map(|t| (*t.value() - Utc::now().timestamp()).max(0))
+            } else {
+                None
+            };
+            LogicalRecord::String { key: key.to_owned(), value, ttl }
+        }
+        KeyType::JSON => {
+            let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+            LogicalRecord::Json { key: key.to_owned(), value: json_btree.get(key)?.value().to_value() }
+        }
+        KeyType::GEO => {
+            let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+            let mut members: Vec<GeoMember> = geo_btree.get(key)?.value().iter().map(|p| {
+                GeoMember { member: p.tag.to_owned(), lat: p.x_cord(), lng: p.y_cord() }
+            }).collect();
+            members.sort_by(|a, b| a.member.cmp(&b.member));
+            LogicalRecord::Geo { key: key.to_owned(), value: members }
         }
     };
+    Some(record)
+}
 
-    if auth_key == client_auth_key {
-        context.client_authenticated = true
-    } else {
-        context.client_authenticated = false
+fn restore_record(record: LogicalRecord, replace: bool) -> Result<(), String> {
+    let keys_map: Arc<DashMap<String, KeyType>> = KEYS_MAP.clone();
+    let key = record.key().to_owned();
+    if !replace && keys_map.contains_key(&key) {
+        return Err("BUSYKEY Target key name already exists.".to_owned());
     }
-    return if context.client_authenticated {
-        print_ok()
-    } else {
-        print_err("ERR auth failed")
+    // restored documents go through the same schemas as JSET
+    if let LogicalRecord::Json { value, .. } = &record {
+        schema::validate(&key, value)?;
+    }
+
+    let rem_map: Arc<DashMap<String, i64>> = KEYS_REM_EX_HASH.clone();
+    rem_map.remove(&key);
+    match record {
+        LogicalRecord::String { value, ttl, .. } => {
+            let btree: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
+            btree.insert(key.to_owned(), logical::json_to_es_value(&value)?);
+            insert_key_with_deletion(&key, KeyType::KV);
+            if let Some(ttl) = ttl {
+                rem_map.insert(key.to_owned(), Utc::now().timestamp() + ttl);
+            }
+        }
+        LogicalRecord::Json { value, .. } => {
+            let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+            json_btree.insert(key.to_owned(), JsonDoc::new(value));
+            insert_key_with_deletion(&key, KeyType::JSON);
+            reindex_json(&key);
+        }
+        LogicalRecord::Geo { value, .. } => {
+            let geo_btree: Arc<DashMap<String, HashSet<GeoPoint2D>>> = GEO_BTREE.clone();
+            let r_map: Arc<DashMap<String, RTree<GeoPoint2D>>> = GEO_RTREE.clone();
+            let points: HashSet<GeoPoint2D> = value.into_iter()
+                .map(|m| GeoPoint2D::with_cord(m.member, m.lat, m.lng))
+                .collect();
+            let bulk_geo_hash_load: Vec<GeoPoint2D> = points.iter().cloned().collect();
+            geo_btree.insert(key.to_owned(), points);
+            r_map.insert(key.to_owned(), RTree::bulk_load(bulk_geo_hash_load));
+            insert_key_with_deletion(&key, KeyType::GEO);
+        }
+    }
+    increment_mutation_counter();
+    Ok(())
+}
+
+/// Writes every key as one JSON line, sorted by key so two dumps can be diffed.
+/// Returns the number of keys written.
+pub fn export_ndjson_file(path: &Path) -> Result<usize, String> {
+    let keys_map: Arc<DashMap<String, KeyType>> = KEYS_MAP.clone();
+    let mut keys: Vec<String> = keys_map.iter().map(|data| data.key().to_owned()).collect();
+    keys.sort();
+
+    let file = match std::fs::File::create(path) {
+        Ok(t) => t,
+        Err(e) => { return Err(format!("Error creating dump file {}: {}", path.display(), e)); }
     };
+    let mut writer = std::io::BufWriter::new(file);
+    let mut count = 0;
+    for key in keys {
+        // keys deleted since the listing are skipped
+        if let Some(record) = dump_record(&key, true) {
+            if let Err(e) = writeln!(writer, "{}", record.to_line()) {
+                return Err(format!("Error writing dump file {}: {}", path.display(), e));
+            }
+            count += 1;
+        }
+    }
+    if let Err(e) = writer.flush() {
+        return Err(format!("Error writing dump file {}: {}", path.display(), e));
+    }
+    Ok(count)
 }
 
-pub fn bg_save(_cmd: &BGSaveCmd) -> String {
-    tokio::task::spawn(async {
-        save_db();
-    });
-    print_ok()
+/// Restores every line of an NDJSON dump, replacing existing keys. Returns the number of keys restored.
+pub fn import_ndjson_file(path: &Path) -> Result<usize, String> {
+    let file = match std::fs::File::open(path) {
+        Ok(t) => t,
+        Err(e) => { return Err(format!("Error opening dump file {}: {}", path.display(), e)); }
+    };
+    let mut count = 0;
+    for (n, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(t) => t,
+            Err(e) => { return Err(format!("Error reading dump file {}: {}", path.display(), e)); }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = LogicalRecord::from_line(&line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        restore_record(record, true).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn dump(cmd: &DumpCmd) -> String {
+    return match dump_record(&cmd.arg_key, false) {
+        None => print_nil(),
+        Some(record) => print_string(&record.to_line())
+    };
+}
+
+pub fn restore(cmd: &RestoreCmd) -> String {
+    let mut record = match LogicalRecord::from_line(&cmd.arg_value) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR DUMP payload version or checksum are wrong"); }
+    };
+    record.set_key(cmd.arg_key.to_owned());
+    if cmd.arg_ttl > 0 {
+        match &mut record {
+            // expiries are kept in seconds, round up so a short TTL doesn't expire the key at once
+            LogicalRecord::String { ttl, .. } => *ttl = Some((cmd.arg_ttl.saturating_add(999) / 1000) as i64),
+            _ => { return print_err("ERR TTL is only supported for string keys"); }
+        }
+    }
+    return match restore_record(record, cmd.arg_replace) {
+        Ok(_) => print_ok(),
+        Err(e) => print_err(&e)
+    };
 }
 
 pub fn flush_db(_cmd: &FlushDBCmd) -> String {
-    tokio::task::spawn(async {
-        clear_db();
-    });
+    // cleared before the reply, while the write stripes are held, so a full sync snapshot taken
+    // after the flush is streamed never holds the flushed keys
+    clear_db();
     print_ok()
 }
 
@@ -588,7 +1063,24 @@ pub fn info(_cmd: &InfoCmd) -> String {
     let map: Arc<DashMap<String, ESValue>> = KV_BTREE.clone();
     //let map = map.into_read_only();
     let key_count = map.len();
-    let info = format!("db0:keys={}\r\n", key_count);
+    let mut info = String::new();
+    info += "# Clients\r\n";
+    info += &format!("connected_clients:{}\r\n", client::connected_clients());
+    info += &format!("total_connections_received:{}\r\n", client::total_connections_received());
+    info += &format!("rejected_connections:{}\r\n", client::rejected_connections());
+    info += "# Persistence\r\n";
+    info += &format!("rdb_changes_since_last_save:{}\r\n", get_mutation_count());
+    info += &format!("rdb_bgsave_in_progress:{}\r\n", is_save_in_progress() as u8);
+    info += &format!("rdb_last_save_time:{}\r\n", get_last_save_time());
+    info += &format!("rdb_last_bgsave_status:{}\r\n", if LAST_SAVE_STATUS_OK.load(Ordering::SeqCst) { "ok" } else { "err" });
+    info += &format!("rdb_last_bgsave_time_ms:{}\r\n", get_last_save_time_duration());
+    info += &format!("rdb_last_load_time_ms:{}\r\n", LAST_LOAD_DURATION.load(Ordering::SeqCst));
+    info += &replication::info();
+    info += "# Json\r\n";
+    info += &format!("json_encoding:{}\r\n", if compact::is_enabled() { "compact" } else { "tree" });
+    info += &format!("json_interned_keys:{}\r\n", compact::interned_keys());
+    info += "# Keyspace\r\n";
+    info += &format!("db0:keys={}\r\n", key_count);
     print_string(&info)
 }
 
@@ -748,7 +1240,8 @@ pub fn incr_by(cmd: &ExpireCmd) -> String {
     result
 }
 
-pub fn keys(cmd: &KeysCmd) -> String {
+/// Keys matching the pattern, only those the connection's user may access
+pub fn keys(context: &mut Context, cmd: &KeysCmd) -> String {
     let map: Arc<DashMap<String, KeyType>> = KEYS_MAP.clone();
     //let map = map.into_read_only();
     let pattern_marcher = match Pattern::new(&cmd.pattern) {
@@ -758,12 +1251,13 @@ pub fn keys(cmd: &KeysCmd) -> String {
         }
     };
 
+    let key_filter = key_filter(context);
     let mut keys: Vec<String> = vec![];
 
     for item in map.iter() {
         //let key = .to_owned();
 
-        if pattern_marcher.matches(item.key()) {
+        if pattern_marcher.matches(item.key()) && key_filter.allows(item.key()) {
             keys.push(item.key().clone())
         }
     }
@@ -1089,27 +1583,76 @@ pub fn geo_json(cmd: &GeoJsonCmd) -> String {
     print_string(&build_geo_json(&geo_arr).to_string())
 }
 
+/// Calls `f` with every JSON document, one entry lock at a time
+pub fn for_each_json<F: FnMut(&str, &Value)>(mut f: F) {
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    json_btree.iter().for_each(|data| f(data.key(), &data.value().value()));
+}
+
+pub fn json_document(key: &str) -> Option<Value> {
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    json_btree.get(key).map(|data| data.value().to_value())
+}
+
+/// Runs `f` on the document stored at `key`, without copying it unless it has to be decoded.
+/// The entry stays locked meanwhile.
+pub fn with_json_document<R, F: FnOnce(&Value) -> R>(key: &str, f: F) -> Option<R> {
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    json_btree.get(key).map(|data| f(&data.value().value()))
+}
+
+/// Sorted keys of the JSON documents matching `pattern`
+pub fn json_keys(pattern: &Pattern) -> Vec<String> {
+    let json_btree: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let mut keys: Vec<String> = json_btree.iter()
+        .filter(|data| pattern.matches(data.key()))
+        .map(|data| data.key().to_owned())
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Updates the JSON indexes after `key` was written or removed, no entry of the store may be held
+fn reindex_json(key: &str) {
+    index::update(key, || json_document(key));
+}
+
 // JSET, JGET, JDEL, JPATH, JMERGE
 pub fn jset_raw(cmd: &JSetRawCmd) -> String {
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    if !is_key_valid_for_type(&cmd.arg_key, KeyType::JSON) {
+        return print_wrong_type_err();
+    };
+
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
 
     let json_value: Value = match serde_json::from_str(&cmd.arg_value) {
         Ok(t) => t,
         Err(_) => { return print_err("ERR invalid json"); }
     };
+    if let Err(e) = schema::validate(&cmd.arg_key, &json_value) {
+        return print_err(&e);
+    }
 
-    map.insert(cmd.arg_key.to_owned(), json_value);
+    map.insert(cmd.arg_key.to_owned(), JsonDoc::new(json_value));
+    insert_key(&cmd.arg_key, KeyType::JSON);
+    reindex_json(&cmd.arg_key);
     increment_mutation_counter();
     print_ok()
 }
 
 pub fn jset(cmd: &JSetCmd) -> String {
+    let response = jset_paths(cmd);
+    reindex_json(&cmd.arg_key);
+    response
+}
+
+fn jset_paths(cmd: &JSetCmd) -> String {
     if !is_key_valid_for_type(&cmd.arg_key.to_owned(), KeyType::JSON) {
         return print_wrong_type_err();
     };
 
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
     return match map.get_mut(&cmd.arg_key) {
         None => {
@@ -1126,14 +1669,23 @@ pub fn jset(cmd: &JSetCmd) -> String {
             if !ers.is_empty() {
                 return print_err("Error Saving values");
             }
-            map.insert(cmd.arg_key.to_owned(), json);
+            if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                return print_err(&e);
+            }
+            map.insert(cmd.arg_key.to_owned(), JsonDoc::new(json));
             insert_key(&cmd.arg_key.to_owned(), KeyType::JSON);
             increment_mutation_counter();
             return print_ok();
         }
         Some(mut j) => {
+            // compact documents take plain paths without being decoded
+            if !schema::covers(&cmd.arg_key) && j.value_mut().set_in_place(&cmd.arg_set_items) {
+                increment_mutation_counter();
+                return print_ok();
+            }
             let mut ers: Vec<json_dotpath::Error> = vec![];
-            let json = j.value_mut();
+            // the paths are set on a copy so a rejected write leaves the document untouched
+            let mut json = j.value().to_value();
             for (path, value) in &cmd.arg_set_items {
                 //json.dot_set(&cmd.arg_dot_path, cmd.arg_json_value.clone());
                 match json.dot_set(&path, value.to_owned()) {
@@ -1146,7 +1698,10 @@ pub fn jset(cmd: &JSetCmd) -> String {
             if !ers.is_empty() {
                 return print_err("Error some values");
             }
-            let _string = j.to_string();
+            if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                return print_err(&e);
+            }
+            *j.value_mut() = JsonDoc::new(json);
             increment_mutation_counter();
             print_ok()
         }
@@ -1154,12 +1709,12 @@ pub fn jset(cmd: &JSetCmd) -> String {
 }
 
 pub fn jmerge(cmd: &JMergeCmd) -> String {
-    if !is_key_valid_for_type(&cmd.arg_key.to_owned(), KeyType::GEO) {
+    if !is_key_valid_for_type(&cmd.arg_key.to_owned(), KeyType::JSON) {
         return print_wrong_type_err();
     };
 
     let null_value = Value::Null;
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
 
     let mut value: Value = match serde_json::from_str(&cmd.arg_value) {
@@ -1169,58 +1724,92 @@ pub fn jmerge(cmd: &JMergeCmd) -> String {
 
     let prev_value: Value = match map.get(&cmd.arg_key) {
         None => { null_value }
-        Some(v) => { v.value().to_owned() }
+        Some(v) => { v.value().to_value() }
     };
 
     if prev_value.is_null() {
-        map.insert(cmd.arg_key.to_owned(), value);
+        if let Err(e) = schema::validate(&cmd.arg_key, &value) {
+            return print_err(&e);
+        }
+        map.insert(cmd.arg_key.to_owned(), JsonDoc::new(value));
+        insert_key(&cmd.arg_key.to_owned(), KeyType::JSON);
+        reindex_json(&cmd.arg_key);
         increment_mutation_counter();
         return print_ok();
     }
 
     util::merge(&mut value, &prev_value);
-    map.insert(cmd.arg_key.to_owned(), value);
+    if let Err(e) = schema::validate(&cmd.arg_key, &value) {
+        return print_err(&e);
+    }
+    map.insert(cmd.arg_key.to_owned(), JsonDoc::new(value));
     insert_key(&cmd.arg_key.to_owned(), KeyType::JSON);
+    reindex_json(&cmd.arg_key);
     increment_mutation_counter();
     print_ok()
 }
 
-pub fn jget(cmd: &JGetCmd) -> String {
-    let null_value = Value::Null;
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+/// Value selected by a JGET path: a JSONPath (`$..item`) selects the array of every matching
+/// node, a dot path (`todos.>.item`) or a legacy path (`.todos[0]`) a single value
+fn jget_path(doc: &JsonDoc, path: &str) -> Result<Value, String> {
+    let (selector, legacy) = json::redis_path(path);
+    if !legacy {
+        return jsonpath::select(&doc.value(), &selector)
+            .map(|nodes| Value::Array(nodes.into_iter().cloned().collect()))
+            .map_err(|_| format!("ERR invalid JSONPath selector '{}'", path));
+    }
+    // only the selected part of a compact document is decoded
+    let dot_path = path.trim();
+    if let Some(value) = doc.dot_find(dot_path.strip_prefix('.').unwrap_or(dot_path)) {
+        return Ok(value);
+    }
+    match jsonpath::select(&doc.value(), &selector) {
+        Ok(nodes) if !nodes.is_empty() => Ok(nodes[0].clone()),
+        _ => Err(format!("ERR Path '{}' does not exist", path))
+    }
+}
 
-    let value: Value = match map.get(&cmd.arg_key) {
-        None => { null_value }
-        Some(v) => { v.value().to_owned() }
+pub fn jget(cmd: &JGetCmd) -> String {
+    if !is_key_valid_for_type(&cmd.arg_key, KeyType::JSON) {
+        return print_wrong_type_err();
     };
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
-    if value.is_null() {
-        return print_string(&"".to_owned());
-    }
-    if let Some(t) = &cmd.arg_dot_path {
-        let dot_path_value = value.dot_get::<Value>(t).unwrap_or(Some(Value::Null)).unwrap();
-        return match dot_path_value {
-            Value::String(s) => {
-                print_string(&s)
-            }
-            Value::Number(n) => {
-                print_string(&n.to_string())
-            }
-            v => {
-                print_string(&v.to_string())
+    let doc = match map.get(&cmd.arg_key) {
+        Some(v) if !v.value().is_null() => v,
+        _ => { return print_nil(); }
+    };
+    let reply = match cmd.arg_paths.as_slice() {
+        [] if cmd.arg_format.is_compact() => {
+            return print_string(&serde_json::to_string(doc.value()).unwrap_or_default());
+        }
+        [] => doc.value().to_value(),
+        [path] => match jget_path(doc.value(), path) {
+            Ok(t) => t,
+            Err(e) => { return print_err(&e); }
+        },
+        // several paths answer with an object keyed by path
+        paths => {
+            let mut selected = serde_json::Map::new();
+            for path in paths {
+                match jget_path(doc.value(), path) {
+                    Ok(t) => { selected.insert(path.to_owned(), t); }
+                    Err(e) => { return print_err(&e); }
+                }
             }
-        };
-    }
-    print_string(&value.to_string())
+            Value::Object(selected)
+        }
+    };
+    print_string(&json::format_json(&reply, &cmd.arg_format))
 }
 
 pub fn jpath(cmd: &JPathCmd) -> String {
     let null_value = Value::Null;
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
     let value = match map.get(&cmd.arg_key) {
         None => { null_value }
-        Some(v) => { v.value().to_owned() }
+        Some(v) => { v.value().to_value() }
     };
 
     if value.is_null() {
@@ -1242,48 +1831,67 @@ pub fn jpath(cmd: &JPathCmd) -> String {
 
 pub fn jdel(cmd: &JDelCmd) -> String {
     let _null_value = Value::Null;
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
     map.remove(&cmd.arg_key);
     remove_key(&cmd.arg_key);
+    reindex_json(&cmd.arg_key);
     print_ok()
 }
 
 pub fn jrem(cmd: &JRemCmd) -> String {
     let _null_value = Value::Null;
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
 
     let mut removal_count = 0;
 
     match map.get_mut(&cmd.arg_key) {
         None => {}
         Some(mut entry) => {
+            let mut json = entry.value().to_value();
             &cmd.arg_paths.iter().for_each(|s| {
-                match entry.value_mut().dot_remove(s) {
+                match json.dot_remove(s) {
                     Ok(_) => {
                         removal_count += 1;
                     }
                     Err(_) => {}
                 };
             });
+            if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                return print_err(&e);
+            }
+            *entry.value_mut() = JsonDoc::new(json);
         }
     }
+    if removal_count > 0 {
+        reindex_json(&cmd.arg_key);
+    }
     print_integer(removal_count)
 }
 
 
 pub fn jincr_by(cmd: &JIncrByCmd) -> String {
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let response = jincr_by_int(cmd);
+    reindex_json(&cmd.arg_key);
+    response
+}
+
+fn jincr_by_int(cmd: &JIncrByCmd) -> String {
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
     return match map.get_mut(&cmd.arg_key) {
         None => {
             return print_err("ERR key not found");
         }
         Some(mut j) => {
-            let json = j.value_mut();
+            let mut json = j.value().to_value();
             let path_to_incr = json.dot_get(&cmd.arg_path).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
 
             if path_to_incr.is_null() {
                 let new_value = json!(cmd.arg_increment_value);
                 json.dot_set(&cmd.arg_path.to_owned(), new_value.clone());
+                if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                    return print_err(&e);
+                }
+                *j.value_mut() = JsonDoc::new(json);
                 increment_mutation_counter();
                 return print_integer(new_value.as_i64().unwrap());
             }
@@ -1307,32 +1915,42 @@ pub fn jincr_by(cmd: &JIncrByCmd) -> String {
             if new_value.is_null() {
                 return print_err("ERR value is not a number");
             }
-            return match json.dot_set(&cmd.arg_path, new_value.clone()) {
-                Ok(_) => {
-                    increment_mutation_counter();
-                    print_integer(new_value.as_i64().unwrap())
-                }
-                Err(_e) => {
-                    print_err("ERR value not set")
-                }
-            };
+            if json.dot_set(&cmd.arg_path, new_value.clone()).is_err() {
+                return print_err("ERR value not set");
+            }
+            if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                return print_err(&e);
+            }
+            *j.value_mut() = JsonDoc::new(json);
+            increment_mutation_counter();
+            print_integer(new_value.as_i64().unwrap())
         }
     };
 }
 
 pub fn jincr_by_float(cmd: &JIncrByFloatCmd) -> String {
-    let map: Arc<DashMap<String, Value>> = JSON_BTREE.clone();
+    let response = jincr_by_float_value(cmd);
+    reindex_json(&cmd.arg_key);
+    response
+}
+
+fn jincr_by_float_value(cmd: &JIncrByFloatCmd) -> String {
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
     return match map.get_mut(&cmd.arg_key) {
         None => {
             return print_err("ERR key not found");
         }
         Some(mut j) => {
-            let json = j.value_mut();
+            let mut json = j.value().to_value();
             let path_to_incr = json.dot_get(&cmd.arg_path).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
 
             if path_to_incr.is_null() {
                 let new_value = json!(cmd.arg_increment_value);
                 json.dot_set(&cmd.arg_path.to_owned(), new_value.clone());
+                if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                    return print_err(&e);
+                }
+                *j.value_mut() = JsonDoc::new(json);
                 increment_mutation_counter();
                 return print_str(&new_value.to_string());
             }
@@ -1354,17 +1972,527 @@ pub fn jincr_by_float(cmd: &JIncrByFloatCmd) -> String {
             if new_value.is_null() {
                 return print_err("ERR value is not a number");
             }
-            return match json.dot_set(&cmd.arg_path, new_value.clone()) {
-                Ok(_) => {
-                    increment_mutation_counter();
-                    print_str(&new_value.to_string())
+            if json.dot_set(&cmd.arg_path, new_value.clone()).is_err() {
+                return print_err("ERR value not set");
+            }
+            if let Err(e) = schema::validate(&cmd.arg_key, &json) {
+                return print_err(&e);
+            }
+            *j.value_mut() = JsonDoc::new(json);
+            increment_mutation_counter();
+            print_str(&new_value.to_string())
+        }
+    };
+}
+
+/// Applies a JSON Patch, or a JSON Merge Patch in MERGE mode, to the document at `key`.
+/// The patch is applied under the entry lock and stored only when every operation succeeded.
+pub fn jpatch(cmd: &JPatchCmd) -> String {
+    if !is_key_valid_for_type(&cmd.arg_key, KeyType::JSON) {
+        return print_wrong_type_err();
+    };
+    let patch: Value = match serde_json::from_str(&cmd.arg_patch) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR invalid json"); }
+    };
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let exists = {
+        let entry = map.entry(cmd.arg_key.to_owned());
+        let mut doc = match &entry {
+            Entry::Occupied(e) => e.get().to_value(),
+            Entry::Vacant(_) => Value::Null,
+        };
+        if cmd.arg_merge {
+            json::merge_patch(&mut doc, &patch);
+        } else if let Err(e) = json::apply_patch(&mut doc, &patch) {
+            return print_err(&e);
+        }
+        if !doc.is_null() {
+            if let Err(e) = schema::validate(&cmd.arg_key, &doc) {
+                return print_err(&e);
+            }
+        }
+        // a patch leaving nothing, like a merge patch of null, removes the document
+        match (entry, doc.is_null()) {
+            (Entry::Occupied(e), true) => { e.remove(); false }
+            (Entry::Occupied(mut e), false) => { e.insert(JsonDoc::new(doc)); true }
+            (Entry::Vacant(_), true) => false,
+            (Entry::Vacant(e), false) => { e.insert(JsonDoc::new(doc)); true }
+        }
+    };
+    if exists {
+        insert_key(&cmd.arg_key, KeyType::JSON);
+    } else {
+        remove_key(&cmd.arg_key);
+    }
+    reindex_json(&cmd.arg_key);
+    increment_mutation_counter();
+    print_ok()
+}
+
+// JPATHSET, JPATHDEL, JPATHINCRBY, JPATHSTRAPPEND, JPATHARR*: writes addressed by JSONPath
+
+/// Runs `f` on a copy of the document at `key` and stores it back when it modified nodes,
+/// replies with the number of modified nodes
+fn update_json_path<F: FnOnce(&mut Value) -> Result<usize, String>>(key: &str, f: F) -> String {
+    if !is_key_valid_for_type(key, KeyType::JSON) {
+        return print_wrong_type_err();
+    };
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let modified = {
+        let mut entry = match map.get_mut(key) {
+            Some(t) => t,
+            None => { return print_err("ERR key not found"); }
+        };
+        // a failing node leaves the document as it was
+        let mut doc = entry.value().to_value();
+        match f(&mut doc) {
+            Ok(0) => 0,
+            Ok(n) => {
+                if let Err(e) = schema::validate(key, &doc) {
+                    return print_err(&e);
                 }
-                Err(_e) => {
-                    print_err("ERR value not set")
+                *entry.value_mut() = JsonDoc::new(doc);
+                n
+            }
+            Err(e) => { return print_err(&e); }
+        }
+    };
+    if modified > 0 {
+        reindex_json(key);
+        increment_mutation_counter();
+    }
+    print_integer(modified as i64)
+}
+
+fn parse_json_values(values: &[String]) -> Result<Vec<Value>, String> {
+    values.iter()
+        .map(|v| serde_json::from_str(v).map_err(|_| "ERR invalid json".to_owned()))
+        .collect()
+}
+
+pub fn jpath_set(cmd: &JPathSetCmd) -> String {
+    let value: Value = match serde_json::from_str(&cmd.arg_value) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR invalid json"); }
+    };
+    if !is_key_valid_for_type(&cmd.arg_key, KeyType::JSON) {
+        return print_wrong_type_err();
+    };
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    if !map.contains_key(&cmd.arg_key) {
+        if cmd.arg_selector.trim() != "$" {
+            return print_err("ERR new documents can only be created at the root '$'");
+        }
+        if let Err(e) = schema::validate(&cmd.arg_key, &value) {
+            return print_err(&e);
+        }
+        map.insert(cmd.arg_key.to_owned(), JsonDoc::new(value));
+        insert_key(&cmd.arg_key, KeyType::JSON);
+        reindex_json(&cmd.arg_key);
+        increment_mutation_counter();
+        return print_integer(1);
+    }
+    update_json_path(&cmd.arg_key, |doc| json::set_selected(doc, &cmd.arg_selector, &value))
+}
+
+pub fn jpath_del(cmd: &JPathDelCmd) -> String {
+    if cmd.arg_selector.trim() == "$" {
+        let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+        if !map.contains_key(&cmd.arg_key) {
+            return print_integer(0);
+        }
+        jdel(&JDelCmd { arg_key: cmd.arg_key.to_owned() });
+        increment_mutation_counter();
+        return print_integer(1);
+    }
+    update_json_path(&cmd.arg_key, |doc| json::delete_selected(doc, &cmd.arg_selector))
+}
+
+pub fn jpath_incr_by(cmd: &JPathIncrByCmd) -> String {
+    update_json_path(&cmd.arg_key, |doc| {
+        json::update_selected(doc, &cmd.arg_selector, |node| json::increment_number(node, cmd.arg_increment_value))
+    })
+}
+
+pub fn jpath_str_append(cmd: &JPathStrAppendCmd) -> String {
+    update_json_path(&cmd.arg_key, |doc| {
+        json::update_selected(doc, &cmd.arg_selector, |node| Ok(json::append_string(node, &cmd.arg_value)))
+    })
+}
+
+pub fn jpath_arr_append(cmd: &JPathArrAppendCmd) -> String {
+    let values = match parse_json_values(&cmd.arg_values) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    update_json_path(&cmd.arg_key, |doc| {
+        json::update_selected(doc, &cmd.arg_selector, |node| Ok(json::array_insert(node, None, &values)))
+    })
+}
+
+pub fn jpath_arr_insert(cmd: &JPathArrInsertCmd) -> String {
+    let values = match parse_json_values(&cmd.arg_values) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    update_json_path(&cmd.arg_key, |doc| {
+        json::update_selected(doc, &cmd.arg_selector, |node| Ok(json::array_insert(node, Some(cmd.arg_index), &values)))
+    })
+}
+
+pub fn jpath_arr_pop(cmd: &JPathArrPopCmd) -> String {
+    update_json_path(&cmd.arg_key, |doc| {
+        json::update_selected(doc, &cmd.arg_selector, |node| Ok(json::array_pop(node, cmd.arg_index)))
+    })
+}
+
+pub fn jpath_arr_trim(cmd: &JPathArrTrimCmd) -> String {
+    update_json_path(&cmd.arg_key, |doc| {
+        json::update_selected(doc, &cmd.arg_selector, |node| Ok(json::array_trim(node, cmd.arg_start, cmd.arg_stop)))
+    })
+}
+
+// JARRAPPEND, JARRINSERT, JARRPOP, JARRLEN, JARRTRIM, JSTRAPPEND, JSTRLEN, JOBJKEYS, JOBJLEN, JTYPE:
+// path commands compatible with RedisJSON
+
+/// JSON pointers of the nodes selected by a RedisJSON `path` and whether it is a legacy path,
+/// which answers for its first node only and must select one
+fn redis_path_pointers(doc: &Value, path: &str) -> Result<(Vec<String>, bool), String> {
+    let (selector, legacy) = json::redis_path(path);
+    let mut pointers = json::select_pointers(doc, &selector)?;
+    if legacy {
+        if pointers.is_empty() {
+            return Err(format!("ERR Path '{}' does not exist", path));
+        }
+        pointers.truncate(1);
+    }
+    Ok((pointers, legacy))
+}
+
+fn wrong_path_type(expected: &str, node: &Value) -> String {
+    print_err(&format!("WRONGTYPE wrong type of path value - expected {} but found {}", expected, json::type_name(node)))
+}
+
+/// A JSONPath answers with one reply per node, nil for the nodes of the wrong type, a legacy path
+/// with the reply of its node
+fn redis_path_reply(replies: Vec<Option<String>>, legacy: bool) -> String {
+    if legacy {
+        return replies.into_iter().next().flatten().unwrap_or_else(print_nil);
+    }
+    print_replies(replies.into_iter().map(|r| r.unwrap_or_else(print_nil)).collect())
+}
+
+/// Runs a RedisJSON path write command on the nodes selected by `path`.
+///
+/// `f` returns the encoded reply for a node or None when it isn't of the `expected` type, a
+/// legacy path errors on a type mismatch. The nodes are changed on a copy that is stored only
+/// when every node succeeded and the document still matches its schemas.
+fn redis_json_path<F>(key: &str, path: &str, expected: &str, mut f: F) -> String
+    where F: FnMut(&mut Value) -> Result<Option<String>, String> {
+    if !is_key_valid_for_type(key, KeyType::JSON) {
+        return print_wrong_type_err();
+    };
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let (replies, legacy, modified) = {
+        let mut entry = match map.get_mut(key) {
+            Some(t) => t,
+            None => { return print_err("ERR key not found"); }
+        };
+        let mut doc = entry.value().to_value();
+        let (pointers, legacy) = match redis_path_pointers(&doc, path) {
+            Ok(t) => t,
+            Err(e) => { return print_err(&e); }
+        };
+        // last node first so removing array items doesn't move the nodes left to visit
+        let mut replies = vec![];
+        for pointer in pointers.iter().rev() {
+            let node = match doc.pointer_mut(pointer) {
+                Some(t) => t,
+                None => {
+                    replies.push(None);
+                    continue;
                 }
             };
+            let reply = match f(node) {
+                Ok(t) => t,
+                Err(e) => { return print_err(&e); }
+            };
+            if reply.is_none() && legacy {
+                return wrong_path_type(expected, node);
+            }
+            replies.push(reply);
+        }
+        replies.reverse();
+        let modified = replies.iter().any(|r| r.is_some());
+        if modified {
+            if let Err(e) = schema::validate(key, &doc) {
+                return print_err(&e);
+            }
+            *entry.value_mut() = JsonDoc::new(doc);
+        }
+        (replies, legacy, modified)
+    };
+    if modified {
+        reindex_json(key);
+        increment_mutation_counter();
+    }
+    redis_path_reply(replies, legacy)
+}
+
+/// Runs a RedisJSON path read command, see `redis_json_path`. Tree documents are read in place.
+fn redis_json_path_read<F>(key: &str, path: &str, expected: &str, f: F) -> String
+    where F: Fn(&Value) -> Option<String> {
+    if !is_key_valid_for_type(key, KeyType::JSON) {
+        return print_wrong_type_err();
+    };
+    let map: Arc<DashMap<String, JsonDoc>> = JSON_BTREE.clone();
+    let entry = match map.get(key) {
+        Some(t) => t,
+        None => { return print_nil(); }
+    };
+    let doc = entry.value().value();
+    let (pointers, legacy) = match redis_path_pointers(&doc, path) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    let mut replies = vec![];
+    for pointer in &pointers {
+        let node = match doc.pointer(pointer) {
+            Some(t) => t,
+            None => {
+                replies.push(None);
+                continue;
+            }
+        };
+        let reply = f(node);
+        if reply.is_none() && legacy {
+            return wrong_path_type(expected, node);
+        }
+        replies.push(reply);
+    }
+    redis_path_reply(replies, legacy)
+}
+
+pub fn jarr_append(cmd: &JArrAppendCmd) -> String {
+    let values = match parse_json_values(&cmd.arg_values) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    redis_json_path(&cmd.arg_key, &cmd.arg_path, "array", |node| Ok(match node {
+        Value::Array(items) => {
+            items.extend(values.iter().cloned());
+            Some(print_integer(items.len() as i64))
+        }
+        _ => None
+    }))
+}
+
+pub fn jarr_insert(cmd: &JArrInsertCmd) -> String {
+    let values = match parse_json_values(&cmd.arg_values) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    redis_json_path(&cmd.arg_key, &cmd.arg_path, "array", |node| {
+        if !node.is_array() {
+            return Ok(None);
+        }
+        if !json::array_insert(node, Some(cmd.arg_index), &values) {
+            return Err("ERR index out of bounds".to_owned());
+        }
+        Ok(node.as_array().map(|items| print_integer(items.len() as i64)))
+    })
+}
+
+/// Replies with the removed value, an index past either end of the array pops the item at that end
+pub fn jarr_pop(cmd: &JArrPopCmd) -> String {
+    redis_json_path(&cmd.arg_key, &cmd.arg_path, "array", |node| Ok(match node {
+        Value::Array(items) if items.is_empty() => Some(print_nil()),
+        Value::Array(items) => {
+            let len = items.len() as i64;
+            let index = if cmd.arg_index < 0 { (len + cmd.arg_index).max(0) } else { cmd.arg_index.min(len - 1) };
+            Some(print_string(&items.remove(index as usize).to_string()))
+        }
+        _ => None
+    }))
+}
+
+pub fn jarr_len(cmd: &JArrLenCmd) -> String {
+    redis_json_path_read(&cmd.arg_key, &cmd.arg_path, "array", |node| {
+        node.as_array().map(|items| print_integer(items.len() as i64))
+    })
+}
+
+pub fn jarr_trim(cmd: &JArrTrimCmd) -> String {
+    redis_json_path(&cmd.arg_key, &cmd.arg_path, "array", |node| {
+        if !json::array_trim(node, cmd.arg_start, cmd.arg_stop) {
+            return Ok(None);
         }
+        Ok(node.as_array().map(|items| print_integer(items.len() as i64)))
+    })
+}
+
+/// The value is a JSON string, e.g `'"suffix"'`, the reply is the new length in bytes
+pub fn jstr_append(cmd: &JStrAppendCmd) -> String {
+    let suffix = match serde_json::from_str::<Value>(&cmd.arg_value) {
+        Ok(Value::String(t)) => t,
+        _ => { return print_err("ERR the value to append must be a JSON string"); }
     };
+    redis_json_path(&cmd.arg_key, &cmd.arg_path, "string", |node| Ok(match node {
+        Value::String(s) => {
+            s.push_str(&suffix);
+            Some(print_integer(s.len() as i64))
+        }
+        _ => None
+    }))
+}
+
+pub fn jstr_len(cmd: &JStrLenCmd) -> String {
+    redis_json_path_read(&cmd.arg_key, &cmd.arg_path, "string", |node| {
+        node.as_str().map(|s| print_integer(s.len() as i64))
+    })
+}
+
+pub fn jobj_keys(cmd: &JObjKeysCmd) -> String {
+    redis_json_path_read(&cmd.arg_key, &cmd.arg_path, "object", |node| {
+        node.as_object().map(|o| print_arr(o.keys().collect()))
+    })
+}
+
+pub fn jobj_len(cmd: &JObjLenCmd) -> String {
+    redis_json_path_read(&cmd.arg_key, &cmd.arg_path, "object", |node| {
+        node.as_object().map(|o| print_integer(o.len() as i64))
+    })
 }
 
+pub fn jtype(cmd: &JTypeCmd) -> String {
+    redis_json_path_read(&cmd.arg_key, &cmd.arg_path, "any", |node| {
+        Some(print_str(json::type_name(node)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_save() {
+        assert!(claim_save().is_ok());
+        assert_eq!(claim_save(), Err("ERR Background save already in progress".to_owned()));
+        set_save_in_progress(false);
+        assert!(claim_save().is_ok());
+        set_save_in_progress(false);
+    }
+
+    #[test]
+    fn test_mutations_saved() {
+        let counter = AtomicUsize::new(5);
+        mutations_saved(&counter, 3);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+        // the counter was reset while the save was running
+        counter.store(0, Ordering::Relaxed);
+        mutations_saved(&counter, 3);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+    }
+
+    fn set_json(key: &str, value: Value) {
+        JSON_BTREE.insert(key.to_owned(), JsonDoc::Tree(value));
+        insert_key(&key.to_owned(), KeyType::JSON);
+    }
+
+    fn path_cmd(key: &str, path: &str) -> JArrLenCmd {
+        JArrLenCmd { arg_key: key.to_owned(), arg_path: path.to_owned() }
+    }
+
+    #[test]
+    fn test_redis_path_replies() {
+        let key = "test:redis_path:replies";
+        set_json(key, json!({"a": [1, 2], "b": {"a": "xy"}}));
+
+        // a legacy path answers for its node, a JSONPath for every node with nil for the wrong types
+        assert_eq!(jarr_len(&path_cmd(key, ".a")), ":2\r\n");
+        assert_eq!(jarr_len(&path_cmd(key, "$..a")), "*2\r\n:2\r\n$-1\r\n");
+        let cmd = JStrLenCmd { arg_key: key.to_owned(), arg_path: "$..a".to_owned() };
+        assert_eq!(jstr_len(&cmd), "*2\r\n$-1\r\n:2\r\n");
+
+        assert_eq!(jarr_len(&path_cmd(key, ".b")), "-WRONGTYPE wrong type of path value - expected array but found object\r\n");
+        assert_eq!(jarr_len(&path_cmd(key, ".c")), "-ERR Path '.c' does not exist\r\n");
+        assert_eq!(jarr_len(&path_cmd(key, "$.c")), "*0\r\n");
+        assert_eq!(jarr_len(&path_cmd("test:redis_path:missing", ".a")), print_nil());
+
+        insert_key(&"test:redis_path:kv".to_owned(), KeyType::KV);
+        assert_eq!(jarr_len(&path_cmd("test:redis_path:kv", ".a")), print_wrong_type_err());
+    }
+
+    #[test]
+    fn test_redis_path_writes_all_or_nothing() {
+        let key = "test:redis_path:writes";
+        set_json(key, json!({"a": [1], "b": {"a": [1, 2, 3]}}));
+
+        // the index fits the second array only, so neither is changed
+        let insert = JArrInsertCmd { arg_key: key.to_owned(), arg_path: "$..a".to_owned(), arg_index: 2, arg_values: vec!["5".to_owned()] };
+        assert_eq!(jarr_insert(&insert), "-ERR index out of bounds\r\n");
+        assert_eq!(json_document(key), Some(json!({"a": [1], "b": {"a": [1, 2, 3]}})));
+
+        let append = JArrAppendCmd { arg_key: key.to_owned(), arg_path: "$..a".to_owned(), arg_values: vec!["5".to_owned()] };
+        assert_eq!(jarr_append(&append), "*2\r\n:2\r\n:4\r\n");
+        assert_eq!(json_document(key), Some(json!({"a": [1, 5], "b": {"a": [1, 2, 3, 5]}})));
+    }
+
+    fn kv_value(key: &str) -> Option<String> {
+        KV_BTREE.get(key).map(|v| match v.value() {
+            ESValue::String(s) => s.to_owned(),
+            ESValue::Int(i) => i.to_string(),
+        })
+    }
 
+    #[test]
+    fn test_read_snapshot_streaming() {
+        let value = ESValue::String("v".to_owned());
+        let doc = JsonDoc::Tree(json!({"name": "escanor"}));
+        let index = index::IndexDef { name: "test-load-idx".to_owned(), pattern: "test:load:*".to_owned(), fields: vec![] };
+        let mut buf = vec![];
+        snapshot::write_header(&mut buf).unwrap();
+        snapshot::write_record(&mut buf, &RecordRef::KV("test:load:kv", &value)).unwrap();
+        snapshot::write_record(&mut buf, &RecordRef::Json("test:load:json", &doc)).unwrap();
+        snapshot::write_record(&mut buf, &RecordRef::Index(&index)).unwrap();
+        snapshot::write_record(&mut buf, &RecordRef::End).unwrap();
+
+        let (index_defs, schema_defs) = read_snapshot(&buf[..]).unwrap();
+        assert_eq!(index_defs, vec![index]);
+        assert!(schema_defs.is_empty());
+        assert_eq!(kv_value("test:load:kv"), Some("v".to_owned()));
+        assert_eq!(json_document("test:load:json"), Some(json!({"name": "escanor"})));
+        assert_eq!(KEYS_MAP.get("test:load:json").map(|t| t.value().to_owned()), Some(KeyType::JSON));
+    }
+
+    #[test]
+    fn test_read_snapshot_legacy() {
+        let saved_db = Database { btree: DashMap::new(), json_btree: DashMap::new(), geo_tree: DashMap::new() };
+        saved_db.btree.insert("test:legacy:kv".to_owned(), ESValue::Int(7));
+        saved_db.json_btree.insert("test:legacy:json".to_owned(), JsonDoc::Tree(json!([1, 2])));
+        let buf = rmp_serde::encode::to_vec(&saved_db).unwrap();
+
+        let (index_defs, schema_defs) = read_snapshot(&buf[..]).unwrap();
+        assert!(index_defs.is_empty() && schema_defs.is_empty());
+        assert_eq!(kv_value("test:legacy:kv"), Some("7".to_owned()));
+        assert_eq!(json_document("test:legacy:json"), Some(json!([1, 2])));
+    }
+
+    #[test]
+    fn test_load_time_in_info() {
+        let path = std::env::temp_dir().join(format!("escanor-test-load-{}.esdb", std::process::id()));
+        let mut buf = vec![];
+        snapshot::write_header(&mut buf).unwrap();
+        snapshot::write_record(&mut buf, &RecordRef::KV("test:load-time:kv", &ESValue::Int(1))).unwrap();
+        snapshot::write_record(&mut buf, &RecordRef::End).unwrap();
+        std::fs::write(&path, &buf).unwrap();
+
+        LAST_LOAD_DURATION.store(u64::MAX, Ordering::SeqCst);
+        load_db_blocking(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let load_time = LAST_LOAD_DURATION.load(Ordering::SeqCst);
+        assert!(load_time < u64::MAX);
+        assert!(info(&InfoCmd).contains(&format!("rdb_last_load_time_ms:{}\r\n", load_time)));
+    }
+}
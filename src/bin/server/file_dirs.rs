@@ -1,15 +1,34 @@
 use std::path::{PathBuf};
+use std::sync::RwLock;
 use app_dirs2::*;
+use lazy_static::lazy_static;
 use crate::APP_INFO;
+use crate::config;
 
+const DEFAULT_DB_FILENAME: &str = "dump.esdb";
+
+lazy_static! {
+    static ref CONFIG_FILE_PATH : RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Overrides the config file location, set from `--config`
+pub fn set_config_file_path(path: PathBuf) {
+    *CONFIG_FILE_PATH.write().unwrap() = Some(path);
+}
 
 pub fn config_file_path() -> Option<PathBuf> {
+    if let Some(p) = CONFIG_FILE_PATH.read().unwrap().as_ref() {
+        return Some(p.to_owned());
+    }
 
-    if cfg!(target_os = "linux") {
-        let mut  directory  = PathBuf::from("/usr/.conf/escanor");
-        if !directory.exists() {
-            std::fs::create_dir_all(directory.clone());
+    if let Ok(p) = std::env::var("ESCANOR_CONFIG") {
+        if !p.is_empty() {
+            return Some(PathBuf::from(p));
         }
+    }
+
+    if cfg!(target_os = "linux") {
+        let mut directory = PathBuf::from("/usr/.conf/escanor");
         directory.push("config");
         directory.set_extension("yaml");
         return Some(directory);
@@ -22,23 +41,49 @@ pub fn config_file_path() -> Option<PathBuf> {
     Some(p)
 }
 
-pub fn db_file_path() -> Option<PathBuf> {
+/// Directory holding the database dump, `database.dir` when configured
+pub fn db_dir_path() -> Option<PathBuf> {
+    if let Some(dir) = config::get_conf_by_key(&"database.dir".to_owned()) {
+        return Some(PathBuf::from(dir));
+    }
 
     if cfg!(target_os = "linux") {
-        let mut  directory  = PathBuf::from("/usr/lib/escanor");
-        if !directory.exists() {
-            std::fs::create_dir_all(directory.clone());
+        return Some(PathBuf::from("/usr/lib/escanor"));
+    }
+
+    match app_dir(AppDataType::UserCache, &APP_INFO, "") {
+        Ok(d) => { Some(d) }
+        Err(e) => {
+            println!("{}", e);
+            None
         }
-        directory.push("dump");
-        directory.set_extension("esdb");
-        return Some(directory);
     }
+}
 
-    let p = match create_file_path(AppDataType::UserCache, "dump", "esdb") {
-        None => { return None; }
-        Some(p) => { p }
+pub fn db_file_path() -> Option<PathBuf> {
+    let mut path = db_dir_path()?;
+    let filename = config::get_conf_by_key(&"database.dbfilename".to_owned())
+        .unwrap_or(DEFAULT_DB_FILENAME.to_owned());
+    path.push(filename);
+    Some(path)
+}
+
+/// Creates `dir` when missing and checks that files can be written in it
+pub fn ensure_dir_writable(dir: &PathBuf) -> Result<(), String> {
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        return Err(format!("Can not create directory {}: {}", dir.display(), e));
+    }
+    let mut probe = dir.clone();
+    probe.push(format!(".escanor-write-test-{}", std::process::id()));
+    return match std::fs::write(&probe, b"") {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) => {
+            Err(format!("Directory {} is not writable: {}", dir.display(), e))
+        }
     };
-    Some(p)
 }
 
 fn create_file_path(datatype: AppDataType, filename: &str, ext: &str) -> Option<PathBuf> {
@@ -0,0 +1,443 @@
+//! Full-text search over the FULLTEXT fields of JSON indexes.
+//!
+//! Text is split on non alphanumeric characters, lowercased and stemmed with the English
+//! Snowball stemmer. Every field keeps an inverted index from stems to the documents containing
+//! them, results are ranked with BM25.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use lazy_static::lazy_static;
+use rust_stemmers::{Algorithm, Stemmer};
+
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 document length normalization
+const B: f64 = 0.75;
+/// words shown around the first match of a snippet
+const SNIPPET_BEFORE: usize = 5;
+const SNIPPET_WORDS: usize = 16;
+
+lazy_static! {
+    static ref STEMMER: Stemmer = Stemmer::create(Algorithm::English);
+}
+
+/// Lowercased words of `text` with their byte range in `text`
+pub fn words(text: &str) -> Vec<(String, usize, usize)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_alphanumeric() {
+            if start.is_none() {
+                start = Some(i);
+            }
+        } else if let Some(s) = start.take() {
+            words.push((text[s..i].to_lowercase(), s, i));
+        }
+    }
+    if let Some(s) = start {
+        words.push((text[s..].to_lowercase(), s, text.len()));
+    }
+    words
+}
+
+pub fn stem(word: &str) -> String {
+    STEMMER.stem(word).into_owned()
+}
+
+/// Edit distance between `a` and `b`, or None as soon as it exceeds `max`
+fn levenshtein(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if (a.len() as isize - b.len() as isize).abs() as usize > max {
+        return None;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+        }
+        if current.iter().min().map_or(false, |m| *m > max) {
+            return None;
+        }
+        previous = current;
+    }
+    Some(previous[b.len()]).filter(|d| *d <= max)
+}
+
+struct DocTerms {
+    /// number of words in the document
+    len: u32,
+    words: Vec<String>,
+    stems: Vec<String>,
+}
+
+/// Inverted index of one FULLTEXT field
+#[derive(Default)]
+pub struct FullTextIndex {
+    /// stem -> document key -> term frequency
+    postings: HashMap<String, HashMap<String, u32>>,
+    /// word as written -> (stem, number of documents containing it), for prefix and fuzzy terms
+    vocabulary: BTreeMap<String, (String, u32)>,
+    docs: HashMap<String, DocTerms>,
+    total_len: u64,
+}
+
+impl FullTextIndex {
+    pub fn add(&mut self, key: &str, texts: &[&str]) {
+        self.remove(key);
+        let mut frequencies: HashMap<String, u32> = HashMap::new();
+        let mut unique_words: HashSet<String> = HashSet::new();
+        let mut len = 0;
+        for text in texts {
+            for (word, _, _) in words(text) {
+                *frequencies.entry(stem(&word)).or_default() += 1;
+                unique_words.insert(word);
+                len += 1;
+            }
+        }
+        if len == 0 {
+            return;
+        }
+        for word in &unique_words {
+            let stem = stem(word);
+            self.vocabulary.entry(word.to_owned()).or_insert((stem, 0)).1 += 1;
+        }
+        for (stem, tf) in &frequencies {
+            self.postings.entry(stem.to_owned()).or_default().insert(key.to_owned(), *tf);
+        }
+        self.total_len += len as u64;
+        self.docs.insert(key.to_owned(), DocTerms {
+            len,
+            words: unique_words.into_iter().collect(),
+            stems: frequencies.into_iter().map(|(s, _)| s).collect(),
+        });
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let doc = match self.docs.remove(key) {
+            Some(t) => t,
+            None => { return; }
+        };
+        self.total_len -= doc.len as u64;
+        for stem in &doc.stems {
+            if let Some(keys) = self.postings.get_mut(stem) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(stem);
+                }
+            }
+        }
+        for word in &doc.words {
+            if let Some(entry) = self.vocabulary.get_mut(word) {
+                entry.1 -= 1;
+                if entry.1 == 0 {
+                    self.vocabulary.remove(word);
+                }
+            }
+        }
+    }
+
+    /// Indexed stems matched by `term`
+    pub fn expand(&self, term: &TermQuery) -> Vec<String> {
+        let mut stems: Vec<String> = match term {
+            TermQuery::Word(w) => vec![stem(w)],
+            TermQuery::Prefix(p) => self.vocabulary.range(p.to_owned()..)
+                .take_while(|(word, _)| word.starts_with(p.as_str()))
+                .map(|(_, (stem, _))| stem.to_owned())
+                .collect(),
+            TermQuery::Fuzzy(w, distance) => self.vocabulary.iter()
+                .filter(|(word, _)| levenshtein(word, w, *distance).is_some())
+                .map(|(_, (stem, _))| stem.to_owned())
+                .collect(),
+        };
+        stems.sort();
+        stems.dedup();
+        stems.retain(|s| self.postings.contains_key(s));
+        stems
+    }
+
+    pub fn docs_with<'a>(&'a self, stem: &str) -> impl Iterator<Item=&'a String> + 'a {
+        self.postings.get(stem).into_iter().flat_map(|keys| keys.keys())
+    }
+
+    /// BM25 score of `stem` for the document at `key`
+    pub fn score(&self, stem: &str, key: &str) -> f64 {
+        let keys = match self.postings.get(stem) {
+            Some(t) => t,
+            None => { return 0.0; }
+        };
+        let (tf, doc) = match (keys.get(key), self.docs.get(key)) {
+            (Some(tf), Some(doc)) => (*tf as f64, doc),
+            _ => { return 0.0; }
+        };
+        let n = self.docs.len() as f64;
+        let df = keys.len() as f64;
+        let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+        let avg_len = self.total_len as f64 / n;
+        idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * doc.len as f64 / avg_len))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TermQuery {
+    Word(String),
+    /// `word*`, matched against the words as written
+    Prefix(String),
+    /// `%word%` within one edit, `%%word%%` within two
+    Fuzzy(String, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchNode {
+    Term(TermQuery),
+    And(Vec<SearchNode>),
+    Or(Vec<SearchNode>),
+    Not(Box<SearchNode>),
+}
+
+impl SearchNode {
+    /// Terms a document can be scored on, the ones under a negation are left out
+    pub fn positive_terms(&self) -> Vec<&TermQuery> {
+        match self {
+            SearchNode::Term(t) => vec![t],
+            SearchNode::And(nodes) | SearchNode::Or(nodes) => nodes.iter().flat_map(|n| n.positive_terms()).collect(),
+            SearchNode::Not(_) => vec![],
+        }
+    }
+}
+
+fn term_node(token: &str) -> Result<SearchNode, String> {
+    let token = token.to_lowercase();
+    let fuzzy = if token.len() > 4 && token.starts_with("%%") && token.ends_with("%%") {
+        Some((&token[2..token.len() - 2], 2))
+    } else if token.len() > 2 && token.starts_with('%') && token.ends_with('%') {
+        Some((&token[1..token.len() - 1], 1))
+    } else {
+        None
+    };
+    if let Some((word, distance)) = fuzzy {
+        return Ok(SearchNode::Term(TermQuery::Fuzzy(word.to_owned(), distance)));
+    }
+    if token.len() > 1 && token.ends_with('*') {
+        let prefix = &token[..token.len() - 1];
+        if words(prefix).len() == 1 {
+            return Ok(SearchNode::Term(TermQuery::Prefix(prefix.to_owned())));
+        }
+    }
+    // `e-mail` is indexed as two words, both must be present
+    let mut terms: Vec<SearchNode> = words(&token).into_iter()
+        .map(|(w, _, _)| SearchNode::Term(TermQuery::Word(w)))
+        .collect();
+    match terms.len() {
+        0 => Err(format!("ERR syntax error in search query near '{}'", token)),
+        1 => Ok(terms.remove(0)),
+        _ => Ok(SearchNode::And(terms)),
+    }
+}
+
+struct SearchParser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl SearchParser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|t| t.as_str())
+    }
+
+    fn parse_or(&mut self) -> Result<SearchNode, String> {
+        let mut nodes = vec![self.parse_and()?];
+        while let Some(token) = self.peek() {
+            if token != "|" && token != "OR" {
+                break;
+            }
+            self.position += 1;
+            nodes.push(self.parse_and()?);
+        }
+        Ok(if nodes.len() == 1 { nodes.remove(0) } else { SearchNode::Or(nodes) })
+    }
+
+    fn parse_and(&mut self) -> Result<SearchNode, String> {
+        let mut nodes = vec![];
+        while let Some(token) = self.peek() {
+            match token {
+                "|" | "OR" | ")" => break,
+                "AND" => { self.position += 1; }
+                _ => nodes.push(self.parse_unary()?),
+            }
+        }
+        match nodes.len() {
+            0 => Err("ERR empty search expression".to_owned()),
+            1 => Ok(nodes.remove(0)),
+            _ => Ok(SearchNode::And(nodes)),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<SearchNode, String> {
+        let token = self.peek().ok_or("ERR unexpected end of search query")?.to_owned();
+        self.position += 1;
+        if token == "NOT" || token == "-" {
+            return Ok(SearchNode::Not(Box::new(self.parse_unary()?)));
+        }
+        if token.starts_with('-') {
+            return Ok(SearchNode::Not(Box::new(term_node(&token[1..])?)));
+        }
+        if token == "(" {
+            let node = self.parse_or()?;
+            if self.peek() != Some(")") {
+                return Err("ERR missing ')' in search query".to_owned());
+            }
+            self.position += 1;
+            return Ok(node);
+        }
+        if token == ")" {
+            return Err("ERR unexpected ')' in search query".to_owned());
+        }
+        term_node(&token)
+    }
+}
+
+/// Parses `wireless (headphone | earbud*) -refurbished %%blutooth%%`, terms next to each other are
+/// ANDed, `|` or OR between them is a union and `-` or NOT a negation
+pub fn parse_search(query: &str) -> Result<SearchNode, String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    for c in query.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' || c == '|' {
+            if !current.is_empty() {
+                tokens.push(std::mem::replace(&mut current, String::new()));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    let mut parser = SearchParser { tokens, position: 0 };
+    let node = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        return Err(format!("ERR syntax error in search query near '{}'", token));
+    }
+    Ok(node)
+}
+
+/// Keys of the documents matching `node` in any of `indexes`, `all` lists every document for negations
+pub fn evaluate<F: Fn() -> HashSet<String>>(node: &SearchNode, indexes: &[&FullTextIndex], all: &F) -> HashSet<String> {
+    match node {
+        SearchNode::Term(term) => {
+            let mut keys = HashSet::new();
+            for idx in indexes {
+                for stem in idx.expand(term) {
+                    keys.extend(idx.docs_with(&stem).cloned());
+                }
+            }
+            keys
+        }
+        SearchNode::And(nodes) => {
+            let mut result: Option<HashSet<String>> = None;
+            for n in nodes {
+                let keys = evaluate(n, indexes, all);
+                result = Some(match result {
+                    None => keys,
+                    Some(r) => r.intersection(&keys).cloned().collect(),
+                });
+            }
+            result.unwrap_or_default()
+        }
+        SearchNode::Or(nodes) => nodes.iter().flat_map(|n| evaluate(n, indexes, all)).collect(),
+        SearchNode::Not(n) => {
+            let excluded = evaluate(n, indexes, all);
+            all().into_iter().filter(|k| !excluded.contains(k)).collect()
+        }
+    }
+}
+
+/// Window of words around the first word of `text` whose stem is in `stems`, matches wrapped in <b></b>
+pub fn highlight(text: &str, stems: &HashSet<String>) -> Option<String> {
+    let words = words(text);
+    let matched: Vec<bool> = words.iter().map(|(w, _, _)| stems.contains(&stem(w))).collect();
+    let first = matched.iter().position(|m| *m)?;
+    let start = first.saturating_sub(SNIPPET_BEFORE);
+    let end = (start + SNIPPET_WORDS).min(words.len());
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push_str("...");
+    }
+    let mut offset = words[start].1;
+    for i in start..end {
+        let (_, word_start, word_end) = words[i];
+        snippet.push_str(&text[offset..word_start]);
+        if matched[i] {
+            snippet.push_str("<b>");
+            snippet.push_str(&text[word_start..word_end]);
+            snippet.push_str("</b>");
+        } else {
+            snippet.push_str(&text[word_start..word_end]);
+        }
+        offset = word_end;
+    }
+    if end < words.len() {
+        snippet.push_str("...");
+    }
+    Some(snippet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn articles() -> FullTextIndex {
+        let mut idx = FullTextIndex::default();
+        idx.add("post:1", &["Running shoes for trail runners"]);
+        idx.add("post:2", &["The best wireless headphones, running or not"]);
+        idx.add("post:3", &["Wireless earbuds review"]);
+        idx
+    }
+
+    fn search(idx: &FullTextIndex, query: &str) -> Vec<String> {
+        let all = || idx.docs.keys().cloned().collect::<HashSet<String>>();
+        let mut keys: Vec<String> = evaluate(&parse_search(query).unwrap(), &[idx], &all).into_iter().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_search() {
+        let mut idx = articles();
+        assert_eq!(search(&idx, "run"), vec!["post:1", "post:2"]);
+        assert_eq!(search(&idx, "WIRELESS -running"), vec!["post:3"]);
+        assert_eq!(search(&idx, "shoes | earbud"), vec!["post:1", "post:3"]);
+        assert_eq!(search(&idx, "wireless (headphone OR shoe)"), vec!["post:2"]);
+        assert_eq!(search(&idx, "earb*"), vec!["post:3"]);
+        assert_eq!(search(&idx, "%%wirless%%"), vec!["post:2", "post:3"]);
+        assert!(search(&idx, "%wirles%").is_empty());
+
+        // rarer terms weigh more
+        assert!(idx.score("shoe", "post:1") > idx.score("run", "post:1"));
+
+        idx.remove("post:3");
+        assert!(search(&idx, "earb*").is_empty());
+        assert!(idx.vocabulary.get("earbuds").is_none());
+
+        assert!(parse_search("(wireless").is_err());
+        assert!(parse_search("a )").is_err());
+    }
+
+    #[test]
+    fn test_highlight() {
+        let stems: HashSet<String> = vec![stem("running")].into_iter().collect();
+        assert_eq!(highlight("The best wireless headphones, running or not", &stems).unwrap(),
+                   "The best wireless headphones, <b>running</b> or not");
+        let long = "one two three four five six seven eight runs nine ten eleven twelve thirteen fourteen fifteen sixteen";
+        assert_eq!(highlight(long, &stems).unwrap(),
+                   "...four five six seven eight <b>runs</b> nine ten eleven twelve thirteen fourteen fifteen sixteen");
+        assert!(highlight("nothing here", &stems).is_none());
+        assert_eq!(levenshtein("kitten", "sitting", 3), Some(3));
+    }
+}
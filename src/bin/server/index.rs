@@ -0,0 +1,697 @@
+//! Secondary indexes over the fields of JSON documents, created with JINDEX CREATE and
+//! searched with JQUERY.
+//!
+//! An index covers the JSON keys matching its pattern, every write to such a key goes through
+//! `update` so the index always reflects the latest value of the document. FULLTEXT fields are
+//! searched with JSEARCH, see `fulltext`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::RwLock;
+
+use glob::Pattern;
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::command::*;
+use crate::db;
+use crate::fulltext::{self, FullTextIndex};
+use crate::network::Context;
+use crate::printer::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FieldType {
+    Text,
+    Numeric,
+    FullText,
+}
+
+impl FieldType {
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::Text => "TEXT",
+            FieldType::Numeric => "NUMERIC",
+            FieldType::FullText => "FULLTEXT",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldDef {
+    /// JSONPath selecting the value, e.g `$.email`
+    pub path: String,
+    /// name used in queries, the last segment of the path unless given with AS
+    pub name: String,
+    pub field_type: FieldType,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub name: String,
+    /// glob pattern of the keys covered by the index, e.g `user:*`
+    pub pattern: String,
+    pub fields: Vec<FieldDef>,
+}
+
+impl FieldDef {
+    pub fn name_from_path(path: &str) -> String {
+        let name = path.rsplit(|c| c == '.' || c == '[').next().unwrap_or(path);
+        let name = name.trim_matches(|c| c == ']' || c == '\'' || c == '"');
+        if name.is_empty() || name == "$" { path.to_owned() } else { name.to_owned() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum IndexedValue {
+    Text(String),
+    Numeric(f64),
+}
+
+/// Maps a float to an integer with the same ordering so numbers can key a BTreeMap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct NumKey(u64);
+
+impl NumKey {
+    fn new(n: f64) -> NumKey {
+        // -0.0 and 0.0 are the same number
+        let n = if n == 0.0 { 0.0 } else { n };
+        let bits = n.to_bits();
+        if n.is_sign_negative() { NumKey(!bits) } else { NumKey(bits | 1 << 63) }
+    }
+}
+
+struct FieldIndex {
+    def: FieldDef,
+    text: BTreeMap<String, BTreeSet<String>>,
+    numeric: BTreeMap<NumKey, BTreeSet<String>>,
+    /// inverted index of FULLTEXT fields
+    fulltext: Option<FullTextIndex>,
+}
+
+impl FieldIndex {
+    fn insert(&mut self, value: &IndexedValue, key: &str) {
+        let keys = match value {
+            IndexedValue::Text(t) => self.text.entry(t.to_owned()).or_default(),
+            IndexedValue::Numeric(n) => self.numeric.entry(NumKey::new(*n)).or_default(),
+        };
+        keys.insert(key.to_owned());
+    }
+
+    fn remove(&mut self, value: &IndexedValue, key: &str) {
+        match value {
+            IndexedValue::Text(t) => {
+                if let Some(keys) = self.text.get_mut(t) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.text.remove(t);
+                    }
+                }
+            }
+            IndexedValue::Numeric(n) => {
+                let num_key = NumKey::new(*n);
+                if let Some(keys) = self.numeric.get_mut(&num_key) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.numeric.remove(&num_key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Values of the field in `doc`, the elements when the path selects an array
+    fn extract(&self, doc: &Value) -> Vec<IndexedValue> {
+        let selected = match jsonpath_lib::select(doc, &self.def.path) {
+            Ok(t) => t,
+            Err(_) => { return vec![]; }
+        };
+        let mut values = vec![];
+        for value in selected {
+            match value {
+                Value::Array(items) => values.extend(items.iter().filter_map(|i| self.to_indexed(i))),
+                v => values.extend(self.to_indexed(v)),
+            }
+        }
+        values
+    }
+
+    fn to_indexed(&self, value: &Value) -> Option<IndexedValue> {
+        match (self.def.field_type, value) {
+            (FieldType::Numeric, Value::Number(n)) => n.as_f64().filter(|f| !f.is_nan()).map(IndexedValue::Numeric),
+            (FieldType::Text, Value::String(s)) => Some(IndexedValue::Text(s.to_lowercase())),
+            (FieldType::Text, Value::Number(_)) | (FieldType::Text, Value::Bool(_)) => Some(IndexedValue::Text(value.to_string())),
+            (FieldType::FullText, Value::String(s)) => Some(IndexedValue::Text(s.to_owned())),
+            _ => None
+        }
+    }
+}
+
+pub struct JsonIndex {
+    def: IndexDef,
+    pattern: Pattern,
+    fields: Vec<FieldIndex>,
+    /// indexed values of every document, per field, so they can be removed on the next write
+    docs: HashMap<String, Vec<Vec<IndexedValue>>>,
+}
+
+impl JsonIndex {
+    pub fn new(def: IndexDef) -> Result<JsonIndex, String> {
+        let pattern = Pattern::new(&def.pattern).map_err(|e| format!("ERR invalid key pattern: {}", e))?;
+        if def.fields.is_empty() {
+            return Err("ERR an index needs at least one field".to_owned());
+        }
+        let fields = def.fields.iter().map(|f| FieldIndex {
+            def: f.clone(),
+            text: BTreeMap::new(),
+            numeric: BTreeMap::new(),
+            fulltext: if f.field_type == FieldType::FullText { Some(FullTextIndex::default()) } else { None },
+        }).collect();
+        Ok(JsonIndex { def, pattern, fields, docs: HashMap::new() })
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        self.pattern.matches(key)
+    }
+
+    fn remove_document(&mut self, key: &str) {
+        if let Some(values) = self.docs.remove(key) {
+            for (field, field_values) in self.fields.iter_mut().zip(values.iter()) {
+                field_values.iter().for_each(|v| field.remove(v, key));
+                if let Some(ft) = field.fulltext.as_mut() {
+                    ft.remove(key);
+                }
+            }
+        }
+    }
+
+    fn add_document(&mut self, key: &str, doc: &Value) {
+        let mut values = vec![];
+        for field in self.fields.iter_mut() {
+            let field_values = field.extract(doc);
+            if field.fulltext.is_some() {
+                // the inverted index keeps what it needs, the text itself is not kept twice
+                let texts: Vec<&str> = field_values.iter().filter_map(|v| match v {
+                    IndexedValue::Text(t) => Some(t.as_str()),
+                    _ => None
+                }).collect();
+                field.fulltext.as_mut().unwrap().add(key, &texts);
+                values.push(vec![]);
+            } else {
+                field_values.iter().for_each(|v| field.insert(v, key));
+                values.push(field_values);
+            }
+        }
+        self.docs.insert(key.to_owned(), values);
+    }
+
+    fn fulltext_indexes(&self) -> Vec<&FullTextIndex> {
+        self.fields.iter().filter_map(|f| f.fulltext.as_ref()).collect()
+    }
+
+    fn all_keys(&self) -> HashSet<String> {
+        self.docs.keys().cloned().collect()
+    }
+
+    fn field_position(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.def.name == name)
+    }
+
+    /// Keys of the documents matching every term of `query`
+    fn search(&self, query: &Query) -> Result<BTreeSet<String>, String> {
+        let mut result: Option<BTreeSet<String>> = None;
+        for term in &query.terms {
+            let matches = self.match_term(term)?;
+            result = Some(match result {
+                None => matches,
+                Some(r) => r.intersection(&matches).cloned().collect(),
+            });
+        }
+        Ok(result.unwrap_or_else(|| self.docs.keys().cloned().collect()))
+    }
+
+    fn match_term(&self, term: &Term) -> Result<BTreeSet<String>, String> {
+        let position = self.field_position(&term.field)
+            .ok_or(format!("ERR unknown field '{}' in index '{}'", term.field, self.def.name))?;
+        let field = &self.fields[position];
+        let mut keys = BTreeSet::new();
+        match (&term.condition, field.def.field_type) {
+            (Condition::Range(min, max), FieldType::Numeric) => {
+                let (low, high) = (NumKey::new(min.value), NumKey::new(max.value));
+                // BTreeMap::range panics on an empty range
+                if low > high || (low == high && (min.exclusive || max.exclusive)) {
+                    return Ok(keys);
+                }
+                let lower = if min.exclusive { Bound::Excluded(low) } else { Bound::Included(low) };
+                let upper = if max.exclusive { Bound::Excluded(high) } else { Bound::Included(high) };
+                for (_, k) in field.numeric.range((lower, upper)) {
+                    keys.extend(k.iter().cloned());
+                }
+            }
+            (Condition::Value(v), FieldType::Numeric) => {
+                let n = v.parse::<f64>().map_err(|_| format!("ERR '{}' is not a number for numeric field '{}'", v, term.field))?;
+                if let Some(k) = field.numeric.get(&NumKey::new(n)) {
+                    keys.extend(k.iter().cloned());
+                }
+            }
+            (Condition::Value(v), FieldType::Text) => {
+                let v = v.to_lowercase();
+                if v.contains(|c| c == '*' || c == '?' || c == '[') {
+                    let pattern = Pattern::new(&v).map_err(|_| format!("ERR invalid pattern '{}'", v))?;
+                    // values sharing the literal prefix of the pattern are next to each other
+                    let prefix: String = v.chars().take_while(|c| !['*', '?', '['].contains(c)).collect();
+                    for (value, k) in field.text.range(prefix.clone()..) {
+                        if !value.starts_with(&prefix) {
+                            break;
+                        }
+                        if pattern.matches(value) {
+                            keys.extend(k.iter().cloned());
+                        }
+                    }
+                } else if let Some(k) = field.text.get(&v) {
+                    keys.extend(k.iter().cloned());
+                }
+            }
+            (Condition::Value(v), FieldType::FullText) => {
+                let node = fulltext::parse_search(v)?;
+                let ft = field.fulltext.as_ref().unwrap();
+                keys.extend(fulltext::evaluate(&node, &[ft], &|| self.all_keys()));
+            }
+            (Condition::Range(_, _), field_type) => {
+                return Err(format!("ERR ranges can only be used on numeric fields, '{}' is {}",
+                                   term.field, field_type.name().to_lowercase()));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Orders `keys` by the first value of the field at `position`, documents without one go last
+    fn sort(&self, keys: &mut Vec<String>, position: usize, descending: bool) {
+        let first_value = |key: &String| self.docs.get(key).and_then(|v| v[position].first().cloned());
+        keys.sort_by(|a, b| {
+            let ordering = match (first_value(a), first_value(b)) {
+                (Some(IndexedValue::Numeric(x)), Some(IndexedValue::Numeric(y))) => NumKey::new(x).cmp(&NumKey::new(y)),
+                (Some(IndexedValue::Text(x)), Some(IndexedValue::Text(y))) => x.cmp(&y),
+                (Some(_), None) => { return std::cmp::Ordering::Less; }
+                (None, Some(_)) => { return std::cmp::Ordering::Greater; }
+                _ => std::cmp::Ordering::Equal,
+            };
+            let ordering = if descending { ordering.reverse() } else { ordering };
+            ordering.then_with(|| a.cmp(b))
+        });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct RangeBound {
+    value: f64,
+    exclusive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    /// `[min max]`, a bound starting with `(` is exclusive
+    Range(RangeBound, RangeBound),
+    /// exact value, text values may contain glob wildcards
+    Value(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Term {
+    field: String,
+    condition: Condition,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    terms: Vec<Term>,
+}
+
+fn parse_bound(s: &str) -> Result<RangeBound, String> {
+    let (exclusive, number) = if s.starts_with('(') { (true, &s[1..]) } else { (false, s) };
+    let value = match number.to_lowercase().as_str() {
+        "-inf" => std::f64::NEG_INFINITY,
+        "inf" | "+inf" => std::f64::INFINITY,
+        n => n.parse::<f64>().map_err(|_| format!("ERR invalid range bound '{}'", s))?,
+    };
+    Ok(RangeBound { value, exclusive })
+}
+
+/// Parses `@age:[18 30] @email:*@acme.com`, terms are ANDed and `*` alone matches every document
+fn parse_query(query: &str) -> Result<Query, String> {
+    let mut terms = vec![];
+    let mut rest = query.trim();
+    while !rest.is_empty() {
+        if rest.starts_with('*') && rest[1..].chars().next().map_or(true, |c| c.is_whitespace()) {
+            rest = rest[1..].trim_start();
+            continue;
+        }
+        if !rest.starts_with('@') {
+            return Err(format!("ERR syntax error in query near '{}'", rest));
+        }
+        let colon = rest.find(':').ok_or(format!("ERR missing ':' after field in '{}'", rest))?;
+        let field = rest[1..colon].to_owned();
+        rest = &rest[colon + 1..];
+        let condition = if rest.starts_with('[') {
+            let end = rest.find(']').ok_or("ERR unterminated range in query")?;
+            let bounds: Vec<&str> = rest[1..end].split_whitespace().collect();
+            if bounds.len() != 2 {
+                return Err("ERR a range needs a min and a max".to_owned());
+            }
+            let condition = Condition::Range(parse_bound(bounds[0])?, parse_bound(bounds[1])?);
+            rest = &rest[end + 1..];
+            condition
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("ERR missing value for field '{}'", field));
+            }
+            let condition = Condition::Value(rest[..end].to_owned());
+            rest = &rest[end..];
+            condition
+        };
+        terms.push(Term { field, condition });
+        rest = rest.trim_start();
+    }
+    Ok(Query { terms })
+}
+
+lazy_static! {
+    static ref INDEXES : RwLock<BTreeMap<String, JsonIndex>> = RwLock::new(BTreeMap::new());
+}
+
+/// Brings the indexes covering `key` up to date, `current` returns the document as stored now.
+///
+/// The document is read under the index lock so concurrent writes to the same key are applied
+/// to the index in the order they hit the store. Callers must not hold a lock on the JSON store.
+pub fn update<F: FnOnce() -> Option<Value>>(key: &str, current: F) {
+    if !INDEXES.read().unwrap().values().any(|idx| idx.covers(key)) {
+        return;
+    }
+    let mut indexes = INDEXES.write().unwrap();
+    let doc = current();
+    for idx in indexes.values_mut().filter(|idx| idx.covers(key)) {
+        idx.remove_document(key);
+        if let Some(doc) = &doc {
+            idx.add_document(key, doc);
+        }
+    }
+}
+
+/// Empties every index, keeping the definitions, for FLUSHDB and full reloads
+pub fn clear_documents() {
+    let mut indexes = INDEXES.write().unwrap();
+    for idx in indexes.values_mut() {
+        for field in idx.fields.iter_mut() {
+            field.text.clear();
+            field.numeric.clear();
+            if field.fulltext.is_some() {
+                field.fulltext = Some(FullTextIndex::default());
+            }
+        }
+        idx.docs.clear();
+    }
+}
+
+/// Re-indexes every JSON document, after a load or an import
+pub fn rebuild() {
+    clear_documents();
+    let mut indexes = INDEXES.write().unwrap();
+    if indexes.is_empty() {
+        return;
+    }
+    db::for_each_json(|key, doc| {
+        for idx in indexes.values_mut().filter(|idx| idx.covers(key)) {
+            idx.add_document(key, doc);
+        }
+    });
+}
+
+/// Drops every index and creates the ones of `defs` over the documents now stored
+pub fn replace_all(defs: Vec<IndexDef>) {
+    INDEXES.write().unwrap().clear();
+    for def in defs {
+        let name = def.name.to_owned();
+        if let Err(e) = create_index(def) {
+            warn!("Could not create index {}: {}", name, e);
+        }
+    }
+}
+
+pub fn definitions() -> Vec<IndexDef> {
+    INDEXES.read().unwrap().values().map(|idx| idx.def.clone()).collect()
+}
+
+/// Creates an index and fills it with the JSON documents it covers
+pub fn create_index(def: IndexDef) -> Result<(), String> {
+    let mut idx = JsonIndex::new(def)?;
+    let mut indexes = INDEXES.write().unwrap();
+    if indexes.contains_key(&idx.def.name) {
+        return Err("ERR Index already exists".to_owned());
+    }
+    db::for_each_json(|key, doc| {
+        if idx.covers(key) {
+            idx.add_document(key, doc);
+        }
+    });
+    info!("Index {} created over {} documents", idx.def.name, idx.docs.len());
+    indexes.insert(idx.def.name.to_owned(), idx);
+    Ok(())
+}
+
+pub fn jindex_create(cmd: &JIndexCreateCmd) -> String {
+    return match create_index(cmd.arg_def.clone()) {
+        Ok(_) => print_ok(),
+        Err(e) => print_err(&e)
+    };
+}
+
+pub fn jindex_drop(cmd: &JIndexDropCmd) -> String {
+    return match INDEXES.write().unwrap().remove(&cmd.arg_name) {
+        Some(_) => print_ok(),
+        None => print_err("ERR Unknown index name")
+    };
+}
+
+pub fn jindex_list(_cmd: &JIndexListCmd) -> String {
+    let indexes = INDEXES.read().unwrap();
+    print_arr(indexes.keys().cloned().collect())
+}
+
+pub fn jindex_info(cmd: &JIndexInfoCmd) -> String {
+    let indexes = INDEXES.read().unwrap();
+    let idx = match indexes.get(&cmd.arg_name) {
+        Some(t) => t,
+        None => { return print_err("ERR Unknown index name"); }
+    };
+    let fields: Vec<Value> = idx.def.fields.iter().map(|f| json!({
+        "path": f.path,
+        "name": f.name,
+        "type": f.field_type.name(),
+    })).collect();
+    let info = json!({
+        "name": idx.def.name,
+        "pattern": idx.def.pattern,
+        "fields": fields,
+        "num_docs": idx.docs.len(),
+    });
+    print_string(&info.to_string())
+}
+
+/// Keys matching the query, or `[key, document]` pairs when paths to return are given,
+/// `$` returning the whole document. Keys the user may not access are left out.
+pub fn jquery(context: &mut Context, cmd: &JQueryCmd) -> String {
+    let query = match parse_query(&cmd.arg_query) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    let keys: Vec<String> = {
+        let indexes = INDEXES.read().unwrap();
+        let idx = match indexes.get(&cmd.arg_index) {
+            Some(t) => t,
+            None => { return print_err("ERR Unknown index name"); }
+        };
+        let key_filter = key_filter(context);
+        let mut keys: Vec<String> = match idx.search(&query) {
+            Ok(t) => t.into_iter().filter(|key| key_filter.allows(key)).collect(),
+            Err(e) => { return print_err(&e); }
+        };
+        if let Some((field, order)) = &cmd.arg_sort_by {
+            let position = match idx.field_position(field) {
+                Some(t) => t,
+                None => { return print_err(&format!("ERR unknown field '{}' in index '{}'", field, idx.def.name)); }
+            };
+            let descending = match order {
+                ArgOrder::DESC => true,
+                _ => false
+            };
+            idx.sort(&mut keys, position, descending);
+        }
+        keys.into_iter().skip(cmd.arg_offset).take(cmd.arg_count).collect()
+    };
+
+    if cmd.arg_return.is_empty() {
+        return print_arr(keys);
+    }
+    // documents are read after releasing the index, keys deleted since then are skipped
+    let rows: Vec<Vec<String>> = keys.into_iter().filter_map(|key| {
+        let doc = db::json_document(&key)?;
+        let projected = if cmd.arg_return.iter().any(|p| p == "$") {
+            doc
+        } else {
+            let mut object = serde_json::Map::new();
+            for path in &cmd.arg_return {
+                let value = jsonpath_lib::select(&doc, path).ok()
+                    .and_then(|v| v.first().map(|v| (*v).clone()))
+                    .unwrap_or(Value::Null);
+                object.insert(path.to_owned(), value);
+            }
+            Value::Object(object)
+        };
+        Some(vec![key, projected.to_string()])
+    }).collect();
+    print_nested_arr(rows)
+}
+
+/// Keys of the documents of `index` matching `query`, an empty query matches every document
+pub fn query_keys(index: &str, query: &str) -> Result<Vec<String>, String> {
+    let query = parse_query(query)?;
+    let indexes = INDEXES.read().unwrap();
+    let idx = indexes.get(index).ok_or("ERR Unknown index name".to_owned())?;
+    Ok(idx.search(&query)?.into_iter().collect())
+}
+
+/// `[key, score, snippet]` rows of the documents matching a full-text query, best first.
+/// The snippet is taken from the first FULLTEXT field containing a match, keys the user may not
+/// access are left out before scoring.
+pub fn jsearch(context: &mut Context, cmd: &JSearchCmd) -> String {
+    let query = match fulltext::parse_search(&cmd.arg_query) {
+        Ok(t) => t,
+        Err(e) => { return print_err(&e); }
+    };
+    let (results, paths, stems) = {
+        let indexes = INDEXES.read().unwrap();
+        let idx = match indexes.get(&cmd.arg_index) {
+            Some(t) => t,
+            None => { return print_err("ERR Unknown index name"); }
+        };
+        let fulltext_indexes = idx.fulltext_indexes();
+        if fulltext_indexes.is_empty() {
+            return print_err(&format!("ERR index '{}' has no FULLTEXT field", idx.def.name));
+        }
+        let keys = fulltext::evaluate(&query, &fulltext_indexes, &|| idx.all_keys());
+        let mut stems: HashSet<String> = HashSet::new();
+        let key_filter = key_filter(context);
+        let mut scores: HashMap<String, f64> = keys.into_iter()
+            .filter(|key| key_filter.allows(key))
+            .map(|k| (k, 0.0))
+            .collect();
+        for term in query.positive_terms() {
+            for ft in &fulltext_indexes {
+                for stem in ft.expand(term) {
+                    for (key, score) in scores.iter_mut() {
+                        *score += ft.score(&stem, key);
+                    }
+                    stems.insert(stem);
+                }
+            }
+        }
+        let mut results: Vec<(String, f64)> = scores.into_iter().collect();
+        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+        let results: Vec<(String, f64)> = results.into_iter().skip(cmd.arg_offset).take(cmd.arg_count).collect();
+        let paths: Vec<String> = idx.def.fields.iter()
+            .filter(|f| f.field_type == FieldType::FullText)
+            .map(|f| f.path.to_owned())
+            .collect();
+        (results, paths, stems)
+    };
+
+    // documents are read after releasing the index, keys deleted since then are skipped
+    let rows: Vec<Vec<String>> = results.into_iter().filter_map(|(key, score)| {
+        let doc = db::json_document(&key)?;
+        let snippet = paths.iter()
+            .filter_map(|path| jsonpath_lib::select(&doc, path).ok())
+            .flatten()
+            .flat_map(|v| match v {
+                Value::Array(items) => items.iter().filter_map(|i| i.as_str()).collect(),
+                v => v.as_str().into_iter().collect::<Vec<&str>>(),
+            })
+            .find_map(|text| fulltext::highlight(text, &stems))
+            .unwrap_or_default();
+        Some(vec![key, format!("{:.4}", score), snippet])
+    }).collect();
+    print_nested_arr(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users_index() -> JsonIndex {
+        let mut idx = JsonIndex::new(IndexDef {
+            name: "users".to_owned(),
+            pattern: "user:*".to_owned(),
+            fields: vec![
+                FieldDef { path: "$.email".to_owned(), name: "email".to_owned(), field_type: FieldType::Text },
+                FieldDef { path: "$.age".to_owned(), name: "age".to_owned(), field_type: FieldType::Numeric },
+            ],
+        }).unwrap();
+        idx.add_document("user:1", &json!({"email": "ama@acme.com", "age": 25}));
+        idx.add_document("user:2", &json!({"email": "kofi@acme.com", "age": 41}));
+        idx.add_document("user:3", &json!({"email": "esi@example.com", "age": 19}));
+        idx
+    }
+
+    fn search(idx: &JsonIndex, query: &str) -> Vec<String> {
+        idx.search(&parse_query(query).unwrap()).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn test_query() {
+        let mut idx = users_index();
+        assert_eq!(search(&idx, "@age:[18 30] @email:*@acme.com"), vec!["user:1"]);
+        assert_eq!(search(&idx, "@age:[(19 +inf]"), vec!["user:1", "user:2"]);
+        assert_eq!(search(&idx, "@email:KOFI@acme.com"), vec!["user:2"]);
+        assert_eq!(search(&idx, "*").len(), 3);
+
+        // a new value replaces the old one in the index
+        idx.remove_document("user:1");
+        idx.add_document("user:1", &json!({"email": "ama@acme.com", "age": 31}));
+        assert!(search(&idx, "@age:[18 30] @email:*@acme.com").is_empty());
+
+        let mut keys = search(&idx, "*");
+        idx.sort(&mut keys, 1, true);
+        assert_eq!(keys, vec!["user:2", "user:1", "user:3"]);
+
+        assert!(idx.search(&parse_query("@name:x").unwrap()).is_err());
+        assert!(parse_query("@age:[1]").is_err());
+        assert!(!idx.covers("account:1"));
+    }
+
+    #[test]
+    fn test_fulltext_field() {
+        let mut idx = JsonIndex::new(IndexDef {
+            name: "posts".to_owned(),
+            pattern: "post:*".to_owned(),
+            fields: vec![
+                FieldDef { path: "$.body".to_owned(), name: "body".to_owned(), field_type: FieldType::FullText },
+            ],
+        }).unwrap();
+        idx.add_document("post:1", &json!({"body": "Caching strategies for busy servers"}));
+        idx.add_document("post:2", &json!({"body": "A server in every pocket"}));
+        assert_eq!(search(&idx, "@body:server"), vec!["post:1", "post:2"]);
+
+        idx.remove_document("post:2");
+        assert_eq!(search(&idx, "@body:server"), vec!["post:1"]);
+        assert!(idx.search(&parse_query("@body:[1 2]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_num_key_order() {
+        let values = [std::f64::NEG_INFINITY, -10.5, -1.0, 0.0, 0.5, 3.0, 1e10, std::f64::INFINITY];
+        for pair in values.windows(2) {
+            assert!(NumKey::new(pair[0]) < NumKey::new(pair[1]));
+        }
+        assert_eq!(FieldDef::name_from_path("$.address.city"), "city");
+    }
+}
@@ -0,0 +1,574 @@
+//! Writes addressed by JSONPath selectors, JSON Patch (RFC 6902) and JSON Merge Patch (RFC 7386).
+//!
+//! `jsonpath_lib` only hands out references to the selected nodes, they are mapped back to
+//! JSON pointers (RFC 6901) so the nodes can be modified in place. Nodes of the wrong type for
+//! an operation are left untouched and are not counted as modified.
+
+use std::collections::HashSet;
+
+use serde_json::{Map, Value};
+
+fn escape_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn collect_pointers(value: &Value, pointer: String, selected: &HashSet<*const Value>, pointers: &mut Vec<String>) {
+    if selected.contains(&(value as *const Value)) {
+        pointers.push(pointer.clone());
+    }
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                collect_pointers(v, format!("{}/{}", pointer, escape_token(k)), selected, pointers);
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                collect_pointers(v, format!("{}/{}", pointer, i), selected, pointers);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// JSON pointers of the nodes of `doc` selected by `selector`, in document order
+pub fn select_pointers(doc: &Value, selector: &str) -> Result<Vec<String>, String> {
+    let selected: HashSet<*const Value> = jsonpath_lib::select(doc, selector)
+        .map_err(|_| format!("ERR invalid JSONPath selector '{}'", selector))?
+        .into_iter()
+        .map(|v| v as *const Value)
+        .collect();
+    let mut pointers = vec![];
+    if !selected.is_empty() {
+        collect_pointers(doc, String::new(), &selected, &mut pointers);
+    }
+    Ok(pointers)
+}
+
+/// Calls `f` on every selected node, last first so removing array elements doesn't move the
+/// nodes left to visit. `f` returns whether it modified the node.
+pub fn update_selected<F>(doc: &mut Value, selector: &str, mut f: F) -> Result<usize, String>
+    where F: FnMut(&mut Value) -> Result<bool, String> {
+    let mut modified = 0;
+    for pointer in select_pointers(doc, selector)?.iter().rev() {
+        if let Some(node) = doc.pointer_mut(pointer) {
+            if f(node)? {
+                modified += 1;
+            }
+        }
+    }
+    Ok(modified)
+}
+
+/// Splits `$.a.b` or `$.a['b']` into the selector of the parent and the member name
+fn split_member(selector: &str) -> Option<(&str, String)> {
+    let selector = selector.trim();
+    if selector.ends_with("']") || selector.ends_with("\"]") {
+        let quote = &selector[selector.len() - 2..selector.len() - 1];
+        let open = selector[..selector.len() - 2].rfind(&format!("[{}", quote))?;
+        return Some((&selector[..open], selector[open + 2..selector.len() - 2].to_owned()));
+    }
+    let dot = selector.rfind('.')?;
+    let name = &selector[dot + 1..];
+    let is_identifier = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    if !is_identifier || selector[..dot].ends_with('.') {
+        return None;
+    }
+    Some((&selector[..dot], name.to_owned()))
+}
+
+/// Replaces the selected nodes with `value`. When nothing is selected and the selector ends
+/// with a member name, the member is added to the objects selected by the rest of the selector.
+pub fn set_selected(doc: &mut Value, selector: &str, value: &Value) -> Result<usize, String> {
+    let modified = update_selected(doc, selector, |node| {
+        *node = value.clone();
+        Ok(true)
+    })?;
+    if modified > 0 {
+        return Ok(modified);
+    }
+    match split_member(selector) {
+        Some((parent, name)) => update_selected(doc, parent, |node| match node {
+            Value::Object(map) => {
+                map.insert(name.to_owned(), value.clone());
+                Ok(true)
+            }
+            _ => Ok(false)
+        }),
+        None => Ok(0)
+    }
+}
+
+/// Removes the selected nodes from their parent, the root itself can't be removed this way
+pub fn delete_selected(doc: &mut Value, selector: &str) -> Result<usize, String> {
+    let mut modified = 0;
+    for pointer in select_pointers(doc, selector)?.iter().rev() {
+        let split = match pointer.rfind('/') {
+            Some(t) => t,
+            None => { continue; }
+        };
+        let token = pointer[split + 1..].replace("~1", "/").replace("~0", "~");
+        let removed = match doc.pointer_mut(&pointer[..split]) {
+            Some(Value::Object(map)) => map.remove(&token).is_some(),
+            Some(Value::Array(items)) => match token.parse::<usize>() {
+                Ok(i) if i < items.len() => {
+                    items.remove(i);
+                    true
+                }
+                _ => false
+            },
+            _ => false
+        };
+        if removed {
+            modified += 1;
+        }
+    }
+    Ok(modified)
+}
+
+/// Adds `increment` to a number, integers stay integers when the increment is whole
+pub fn increment_number(node: &mut Value, increment: f64) -> Result<bool, String> {
+    let whole = increment.fract() == 0.0 && increment.abs() < 9.0e15;
+    let new_value = match node.as_i64() {
+        Some(i) if whole => match i.checked_add(increment as i64) {
+            Some(n) => Value::from(n),
+            None => { return Err("ERR increment would overflow".to_owned()); }
+        },
+        _ => match node.as_f64() {
+            Some(f) => {
+                let n = f + increment;
+                if !n.is_finite() {
+                    return Err("ERR increment would produce a non finite number".to_owned());
+                }
+                Value::from(n)
+            }
+            None => { return Ok(false); }
+        }
+    };
+    *node = new_value;
+    Ok(true)
+}
+
+pub fn append_string(node: &mut Value, suffix: &str) -> bool {
+    match node {
+        Value::String(s) => {
+            s.push_str(suffix);
+            true
+        }
+        _ => false
+    }
+}
+
+/// Position in an array of `len` items, negative indexes count from the end
+fn array_position(index: i64, len: usize) -> Option<usize> {
+    let position = if index < 0 { len as i64 + index } else { index };
+    if position < 0 || position > len as i64 { None } else { Some(position as usize) }
+}
+
+/// Inserts `values` before `index`, which may be the length of the array to append
+pub fn array_insert(node: &mut Value, index: Option<i64>, values: &[Value]) -> bool {
+    let items = match node {
+        Value::Array(items) => items,
+        _ => { return false; }
+    };
+    let position = match index {
+        None => items.len(),
+        Some(i) => match array_position(i, items.len()) {
+            Some(p) => p,
+            None => { return false; }
+        }
+    };
+    for (offset, value) in values.iter().enumerate() {
+        items.insert(position + offset, value.clone());
+    }
+    true
+}
+
+pub fn array_pop(node: &mut Value, index: i64) -> bool {
+    match node {
+        Value::Array(items) => match array_position(index, items.len()) {
+            Some(p) if p < items.len() => {
+                items.remove(p);
+                true
+            }
+            _ => false
+        },
+        _ => false
+    }
+}
+
+/// Keeps the items from `start` to `stop` included, like LTRIM
+pub fn array_trim(node: &mut Value, start: i64, stop: i64) -> bool {
+    let items = match node {
+        Value::Array(items) => items,
+        _ => { return false; }
+    };
+    let len = items.len() as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start.min(len) };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop {
+        items.clear();
+    } else {
+        items.truncate(stop as usize + 1);
+        items.drain(..start as usize);
+    }
+    true
+}
+
+/// Value of an untyped JSET argument: JSON numbers, `true`, `false` and `null` keep their type,
+/// anything else, including numbers with leading zeros like `00501`, is stored as a string
+pub fn guess_value(token: &str) -> Value {
+    match serde_json::from_str::<Value>(token) {
+        Ok(v) if v.is_number() || v.is_boolean() || v.is_null() => v,
+        _ => Value::String(token.to_owned())
+    }
+}
+
+/// Converts a RedisJSON path to a JSONPath selector, the flag is set for legacy paths like
+/// `.a.b` or `a[0]`, which address a single value
+pub fn redis_path(path: &str) -> (String, bool) {
+    let path = path.trim();
+    if path.starts_with('$') {
+        (path.to_owned(), false)
+    } else if path.is_empty() || path == "." {
+        ("$".to_owned(), true)
+    } else if path.starts_with('.') || path.starts_with('[') {
+        (format!("${}", path), true)
+    } else {
+        (format!("$.{}", path), true)
+    }
+}
+
+/// Type names used by JTYPE, integers are told apart from other numbers
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Whitespace of a JGET reply, set with its INDENT, NEWLINE and SPACE options
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JsonFormat {
+    pub indent: String,
+    pub newline: String,
+    pub space: String,
+}
+
+impl JsonFormat {
+    pub fn is_compact(&self) -> bool {
+        self.indent.is_empty() && self.newline.is_empty() && self.space.is_empty()
+    }
+}
+
+/// Serializes `value` with `newline` and `indent` repeated for the depth before every array
+/// item, object member and closing bracket, and `space` after every colon. Empty containers
+/// stay `[]` and `{}`.
+pub fn format_json(value: &Value, format: &JsonFormat) -> String {
+    let mut out = String::new();
+    write_formatted(value, format, 0, &mut out);
+    out
+}
+
+fn new_line(format: &JsonFormat, depth: usize, out: &mut String) {
+    out.push_str(&format.newline);
+    for _ in 0..depth {
+        out.push_str(&format.indent);
+    }
+}
+
+fn write_formatted(value: &Value, format: &JsonFormat, depth: usize, out: &mut String) {
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                new_line(format, depth + 1, out);
+                write_formatted(item, format, depth + 1, out);
+            }
+            new_line(format, depth, out);
+            out.push(']');
+        }
+        Value::Object(map) if !map.is_empty() => {
+            out.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                new_line(format, depth + 1, out);
+                out.push_str(&Value::String(k.to_owned()).to_string());
+                out.push(':');
+                out.push_str(&format.space);
+                write_formatted(v, format, depth + 1, out);
+            }
+            new_line(format, depth, out);
+            out.push('}');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// Reference tokens of a JSON pointer, `""` being the whole document
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    if !pointer.starts_with('/') {
+        return Err(format!("invalid JSON pointer '{}'", pointer));
+    }
+    Ok(pointer[1..].split('/').map(|t| t.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+/// Array index of a pointer token, leading zeros are not allowed
+fn parse_index(token: &str) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) || !token.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn pointer_get_mut<'a>(doc: &'a mut Value, tokens: &[String]) -> Option<&'a mut Value> {
+    let mut node = doc;
+    for token in tokens {
+        node = match node {
+            Value::Object(map) => map.get_mut(token)?,
+            Value::Array(items) => items.get_mut(parse_index(token)?)?,
+            _ => { return None; }
+        };
+    }
+    Some(node)
+}
+
+fn patch_add(doc: &mut Value, tokens: &[String], value: Value) -> Result<(), String> {
+    let (last, parent) = match tokens.split_last() {
+        Some(t) => t,
+        None => {
+            *doc = value;
+            return Ok(());
+        }
+    };
+    match pointer_get_mut(doc, parent) {
+        Some(Value::Object(map)) => {
+            map.insert(last.to_owned(), value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = if last == "-" { Some(items.len()) } else { parse_index(last) };
+            match index {
+                Some(i) if i <= items.len() => {
+                    items.insert(i, value);
+                    Ok(())
+                }
+                _ => Err(format!("index '{}' is out of bounds", last))
+            }
+        }
+        _ => Err("parent does not exist".to_owned())
+    }
+}
+
+fn patch_remove(doc: &mut Value, tokens: &[String]) -> Result<Value, String> {
+    let (last, parent) = tokens.split_last().ok_or("the whole document can't be removed")?;
+    let removed = match pointer_get_mut(doc, parent) {
+        Some(Value::Object(map)) => map.remove(last),
+        Some(Value::Array(items)) => match parse_index(last) {
+            Some(i) if i < items.len() => Some(items.remove(i)),
+            _ => None
+        },
+        _ => None
+    };
+    removed.ok_or("path does not exist".to_owned())
+}
+
+/// Equality where numbers compare by value, `1` being equal to `1.0`
+pub fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x == y || x.as_f64() == y.as_f64(),
+        (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(a, b)| json_equal(a, b)),
+        (Value::Object(x), Value::Object(y)) => x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).map_or(false, |w| json_equal(v, w))),
+        _ => a == b
+    }
+}
+
+fn apply_operation(doc: &mut Value, operation: &Value) -> Result<(), String> {
+    let field = |name: &str| operation.get(name).ok_or(format!("missing '{}'", name));
+    let pointer = |name: &str| -> Result<Vec<String>, String> {
+        parse_pointer(field(name)?.as_str().ok_or(format!("'{}' must be a string", name))?)
+    };
+    let op = field("op")?.as_str().ok_or("'op' must be a string")?;
+    let path = pointer("path")?;
+    match op {
+        "add" => patch_add(doc, &path, field("value")?.clone()),
+        "remove" => patch_remove(doc, &path).map(|_| ()),
+        "replace" => {
+            let node = pointer_get_mut(doc, &path).ok_or("path does not exist")?;
+            *node = field("value")?.clone();
+            Ok(())
+        }
+        "move" => {
+            let from = pointer("from")?;
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err("a value can't be moved into one of its children".to_owned());
+            }
+            let value = patch_remove(doc, &from)?;
+            patch_add(doc, &path, value)
+        }
+        "copy" => {
+            let from = pointer("from")?;
+            let value = pointer_get_mut(doc, &from).ok_or("'from' does not exist")?.clone();
+            patch_add(doc, &path, value)
+        }
+        "test" => {
+            let expected = field("value")?;
+            match pointer_get_mut(doc, &path) {
+                Some(actual) if json_equal(actual, expected) => Ok(()),
+                Some(_) => Err("test failed".to_owned()),
+                None => Err("path does not exist".to_owned()),
+            }
+        }
+        op => Err(format!("unknown op '{}'", op))
+    }
+}
+
+/// Applies a JSON Patch (RFC 6902). Operations run in order on `doc`, callers keep the original
+/// document to roll back to when one of them fails.
+pub fn apply_patch(doc: &mut Value, patch: &Value) -> Result<(), String> {
+    let operations = patch.as_array().ok_or("ERR a JSON patch must be an array of operations")?;
+    for (i, operation) in operations.iter().enumerate() {
+        apply_operation(doc, operation)
+            .map_err(|e| format!("ERR patch operation {} failed: {}", i, e))?;
+    }
+    Ok(())
+}
+
+/// Applies a JSON Merge Patch (RFC 7386), null members of the patch remove the member
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(t) => t,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let map = target.as_object_mut().unwrap();
+    for (k, v) in patch {
+        if v.is_null() {
+            map.remove(k);
+        } else {
+            merge_patch(map.entry(k.to_owned()).or_insert(Value::Null), v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todos() -> Value {
+        json!({"todos": [
+            {"item": "milk", "completed": false, "priority": 1},
+            {"item": "bread", "completed": true, "priority": 2},
+            {"item": "eggs", "completed": false, "priority": 3},
+        ]})
+    }
+
+    #[test]
+    fn test_jsonpath_writes() {
+        let mut doc = todos();
+        assert_eq!(select_pointers(&doc, "$.todos[?(@.completed == false)]").unwrap(), vec!["/todos/0", "/todos/2"]);
+        assert_eq!(set_selected(&mut doc, "$.todos[?(@.completed == false)].completed", &json!(true)).unwrap(), 2);
+        assert_eq!(doc["todos"][2]["completed"], json!(true));
+
+        assert_eq!(set_selected(&mut doc, "$.todos[*].tag", &json!("home")).unwrap(), 3);
+        assert_eq!(doc["todos"][1]["tag"], json!("home"));
+
+        assert_eq!(update_selected(&mut doc, "$..priority", |n| increment_number(n, 10.0)).unwrap(), 3);
+        assert_eq!(doc["todos"][0]["priority"], json!(11));
+        assert_eq!(update_selected(&mut doc, "$..item", |n| Ok(append_string(n, "!"))).unwrap(), 3);
+        assert_eq!(doc["todos"][1]["item"], json!("bread!"));
+
+        assert_eq!(delete_selected(&mut doc, "$.todos[?(@.priority > 11)]").unwrap(), 2);
+        assert_eq!(doc["todos"].as_array().unwrap().len(), 1);
+        assert!(select_pointers(&doc, "$[").is_err());
+    }
+
+    #[test]
+    fn test_array_ops() {
+        let mut items = json!([1, 2, 3, 4, 5]);
+        assert!(array_insert(&mut items, Some(-1), &[json!("a")]));
+        assert_eq!(items, json!([1, 2, 3, 4, "a", 5]));
+        assert!(array_pop(&mut items, -1));
+        assert!(array_trim(&mut items, 1, -2));
+        assert_eq!(items, json!([2, 3, 4]));
+        assert!(!array_insert(&mut items, Some(10), &[json!(0)]));
+        assert!(!array_pop(&mut json!("text"), 0));
+        assert_eq!(split_member("$.a['b c']"), Some(("$.a", "b c".to_owned())));
+        assert_eq!(split_member("$..b"), None);
+        assert_eq!(redis_path("."), ("$".to_owned(), true));
+        assert_eq!(redis_path("todos[0].item"), ("$.todos[0].item".to_owned(), true));
+        assert_eq!(redis_path("$..item"), ("$..item".to_owned(), false));
+        assert_eq!(type_name(&json!(1.5)), "number");
+        assert_eq!(type_name(&json!(-3)), "integer");
+        assert_eq!(guess_value("00501"), json!("00501"));
+        assert_eq!(guess_value("false"), json!(false));
+        assert_eq!(guess_value("9007199254740993"), json!(9007199254740993u64));
+        assert_eq!(guess_value("-2.5"), json!(-2.5));
+        assert_eq!(guess_value("1 2"), json!("1 2"));
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let mut doc = json!({"name": "ama", "tags": ["a", "b"], "version": 1});
+        let patch = json!([
+            {"op": "test", "path": "/version", "value": 1.0},
+            {"op": "replace", "path": "/version", "value": 2},
+            {"op": "add", "path": "/tags/-", "value": "c"},
+            {"op": "remove", "path": "/tags/0"},
+            {"op": "move", "from": "/name", "path": "/profile~1name"},
+            {"op": "copy", "from": "/tags", "path": "/old_tags"},
+        ]);
+        apply_patch(&mut doc, &patch).unwrap();
+        assert_eq!(doc, json!({"profile/name": "ama", "tags": ["b", "c"], "old_tags": ["b", "c"], "version": 2}));
+
+        let err = apply_patch(&mut doc, &json!([{"op": "test", "path": "/version", "value": 1}])).unwrap_err();
+        assert_eq!(err, "ERR patch operation 0 failed: test failed");
+        assert!(apply_patch(&mut doc, &json!([{"op": "add", "path": "/tags/01", "value": 0}])).is_err());
+        assert!(apply_patch(&mut doc, &json!([{"op": "move", "from": "/tags", "path": "/tags/0"}])).is_err());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        // examples from RFC 7386 appendix A
+        let cases = vec![
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": {"b": "c"}}), json!({"a": {"b": "d", "c": null}}), json!({"a": {"b": "d"}})),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+            (json!({}), json!({"a": {"bb": {"ccc": null}}}), json!({"a": {"bb": {}}})),
+        ];
+        for (mut target, patch, expected) in cases {
+            merge_patch(&mut target, &patch);
+            assert_eq!(target, expected);
+        }
+    }
+
+    #[test]
+    fn test_format_json() {
+        let doc = json!({"empty": {}, "name": "ama", "tags": ["a", 1]});
+        assert_eq!(format_json(&doc, &JsonFormat::default()), doc.to_string());
+        let format = JsonFormat { indent: "  ".to_owned(), newline: "\n".to_owned(), space: " ".to_owned() };
+        assert_eq!(format_json(&doc, &format), "{\n  \"empty\": {},\n  \"name\": \"ama\",\n  \"tags\": [\n    \"a\",\n    1\n  ]\n}");
+        assert_eq!(format_json(&json!("a\"b"), &format), "\"a\\\"b\"");
+    }
+}
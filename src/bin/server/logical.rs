@@ -0,0 +1,92 @@
+//! Human readable, version independent representation of keys, written one JSON
+//! document per line by the NDJSON dump and returned by DUMP.
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::db::ESValue;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeoMember {
+    pub member: String,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LogicalRecord {
+    String {
+        key: String,
+        /// a JSON string, or a number for integer values
+        value: Value,
+        /// remaining time to live in seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<i64>,
+    },
+    Json {
+        key: String,
+        value: Value,
+    },
+    Geo {
+        key: String,
+        value: Vec<GeoMember>,
+    },
+}
+
+impl LogicalRecord {
+    pub fn key(&self) -> &str {
+        match self {
+            LogicalRecord::String { key, .. } => key,
+            LogicalRecord::Json { key, .. } => key,
+            LogicalRecord::Geo { key, .. } => key,
+        }
+    }
+
+    pub fn set_key(&mut self, new_key: String) {
+        match self {
+            LogicalRecord::String { key, .. } => *key = new_key,
+            LogicalRecord::Json { key, .. } => *key = new_key,
+            LogicalRecord::Geo { key, .. } => *key = new_key,
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        serde_json::from_str(line).map_err(|e| format!("Invalid dump record: {}", e))
+    }
+}
+
+pub fn es_value_to_json(value: &ESValue) -> Value {
+    match value {
+        ESValue::String(s) => Value::String(s.to_owned()),
+        ESValue::Int(i) => json!(i),
+    }
+}
+
+pub fn json_to_es_value(value: &Value) -> Result<ESValue, String> {
+    match value {
+        Value::String(s) => Ok(ESValue::String(s.to_owned())),
+        Value::Number(n) if n.is_i64() => Ok(ESValue::Int(n.as_i64().unwrap())),
+        _ => Err("string values must be a JSON string or integer".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_lines() {
+        let record = LogicalRecord::String { key: "count".to_owned(), value: json!(7), ttl: None };
+        assert_eq!(record.to_line(), r#"{"type":"string","key":"count","value":7}"#);
+        assert_eq!(LogicalRecord::from_line(&record.to_line()).unwrap(), record);
+
+        let geo = LogicalRecord::from_line(r#"{"type":"geo","key":"places","value":[{"member":"accra","lat":5.6,"lng":-0.18}]}"#).unwrap();
+        assert_eq!(geo.key(), "places");
+        assert!(LogicalRecord::from_line(r#"{"type":"list","key":"l","value":[]}"#).is_err());
+    }
+}
@@ -24,17 +24,33 @@ mod syntax_analyzer;
 mod file_dirs;
 mod codec;
 mod json;
+mod client;
+mod shutdown;
+mod snapshot;
+mod rdb;
+mod logical;
+mod replication;
+mod tls;
+mod acl;
+mod index;
+mod fulltext;
+mod schema;
+mod aggregate;
+mod compact;
 
 use clap::{App, Arg};
 
 use console::style;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 const APP_NAME: &str = "Escanor";
 const APP_VERSION: &str = "0.1.5";
 const APP_AUTHORS: &str = "Mambisi Zempare <mambisizempare@gmail.com>";
 const APP_HOMEPAGE: &str = "https://github.com/mambisi/escanor";
 const APP_ABOUT: &str = "Escanor is key value in memory database with disk store developed by ByteQuery Ltd.";
+const SHUTDOWN_DRAIN_TIMEOUT_SECS: u64 = 5;
 
 extern crate app_dirs2;
 
@@ -63,8 +79,62 @@ async fn main() {
         .arg(Arg::with_name("PORT")
             .short("p")
             .long("port")
-            .help("sets the tcp port for the server")
-            .default_value("6379")
+            .help("sets the tcp port for the server, overrides network.port")
+            .takes_value(true))
+        .arg(Arg::with_name("BIND")
+            .short("b")
+            .long("bind")
+            .help("sets the addresses the server binds to, overrides network.bind")
+            .multiple(true)
+            .takes_value(true))
+        .arg(Arg::with_name("MAX_PACKET")
+            .long("max-packet")
+            .help("sets the maximum request size in MB, overrides network.max_packet")
+            .takes_value(true))
+        .arg(Arg::with_name("MAX_CONNECTIONS")
+            .long("max-connections")
+            .help("sets the maximum number of clients, overrides network.max_connections")
+            .takes_value(true))
+        .arg(Arg::with_name("CONFIG")
+            .short("c")
+            .long("config")
+            .help("sets the config file path")
+            .takes_value(true))
+        .arg(Arg::with_name("DIR")
+            .long("dir")
+            .help("sets the directory where the database is stored, overrides database.dir")
+            .takes_value(true))
+        .arg(Arg::with_name("DBFILENAME")
+            .long("dbfilename")
+            .help("sets the database file name, overrides database.dbfilename")
+            .takes_value(true))
+        .arg(Arg::with_name("WATCH_CONFIG")
+            .long("watch-config")
+            .help("reloads the config file whenever it changes on disk")
+            .takes_value(false))
+        .arg(Arg::with_name("IMPORT_RDB")
+            .long("import-rdb")
+            .help("imports a redis RDB file into the database before serving")
+            .takes_value(true))
+        .arg(Arg::with_name("EXPORT_RDB")
+            .long("export-rdb")
+            .help("writes the database to a redis RDB file and exits")
+            .takes_value(true))
+        .arg(Arg::with_name("IMPORT_NDJSON")
+            .long("import-ndjson")
+            .help("restores an NDJSON dump into the database before serving")
+            .takes_value(true))
+        .arg(Arg::with_name("EXPORT_NDJSON")
+            .long("export-ndjson")
+            .help("writes every key to an NDJSON dump and exits")
+            .takes_value(true))
+        .arg(Arg::with_name("REPLICAOF")
+            .long("replicaof")
+            .help("starts as a replica of the master at <host:port>")
+            .takes_value(true))
+        .arg(Arg::with_name("MASTERAUTH")
+            .long("masterauth")
+            .help("sets the password used to authenticate with the master")
             .takes_value(true))
         .arg(Arg::with_name("RESET")
             .long("reset")
@@ -72,17 +142,193 @@ async fn main() {
             .takes_value(false))
         .get_matches();
 
-    let host = "127.0.0.1";
-    let port = matches.value_of("PORT").unwrap();
+    if let Some(path) = matches.value_of("CONFIG") {
+        file_dirs::set_config_file_path(PathBuf::from(path));
+    }
 
     if matches.is_present("RESET") {
-        config::write_default_config_file().await;
+        if let Err(e) = config::write_default_config_file().await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     }
 
-    let addrs = &format!("{}:{}", host, port);
-
     info!("PID: {}", std::process::id());
-    config::load_conf(true).await;
+    if let Err(e) = config::load_conf(true).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // precedence is command line flags, then ESCANOR_* environment variables, then the config file
+    if let Err(e) = config::apply_env_overrides() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let cli_overrides = [
+        ("PORT", "network.port"),
+        ("MAX_PACKET", "network.max_packet"),
+        ("MAX_CONNECTIONS", "network.max_connections"),
+        ("DIR", "database.dir"),
+        ("DBFILENAME", "database.dbfilename"),
+    ];
+    for (arg, key) in cli_overrides.iter() {
+        if let Some(value) = matches.value_of(arg) {
+            if let Err(e) = config::validate_conf_value(key, value) {
+                eprintln!("Invalid value for --{}: {}", arg.to_lowercase().replace('_', "-"), e);
+                std::process::exit(1);
+            }
+            config::set_conf_override(key, value);
+        }
+    }
+    if let Some(binds) = matches.values_of("BIND") {
+        let binds: Vec<&str> = binds.collect();
+        config::set_conf_override("network.bind", &binds.join(" "));
+    }
+
+    let net_conf = config::conf().network;
+    if net_conf.port > u16::max_value() as usize {
+        eprintln!("Invalid port: {}", net_conf.port);
+        std::process::exit(1);
+    }
+
+    match file_dirs::db_dir_path() {
+        None => {
+            eprintln!("Could not resolve the database directory, set it with --dir");
+            std::process::exit(1);
+        }
+        Some(dir) => {
+            if let Err(e) = file_dirs::ensure_dir_writable(&dir) {
+                eprintln!("{}, set another directory with --dir", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
     db::init_db().await;
-    network::start_up(addrs).await;
+
+    if let Some(path) = matches.value_of("IMPORT_RDB") {
+        match db::import_rdb_file(Path::new(path)) {
+            Ok(stats) => info!("Imported {}: {:?}", path, stats),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = matches.value_of("IMPORT_NDJSON") {
+        match db::import_ndjson_file(Path::new(path)) {
+            Ok(count) => info!("Restored {} keys from {}", count, path),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = matches.value_of("EXPORT_NDJSON") {
+        match db::export_ndjson_file(Path::new(path)) {
+            Ok(count) => {
+                info!("Exported {} keys to {}", count, path);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = matches.value_of("EXPORT_RDB") {
+        match db::export_rdb_file(Path::new(path)) {
+            Ok(count) => {
+                info!("Exported {} keys to {}", count, path);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Could not listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while let Some(_) = hangup.recv().await {
+            info!("SIGHUP received, reloading configuration");
+            if let Err(e) = config::reload_conf().await {
+                error!("{}", e);
+            }
+        }
+    });
+
+    if matches.is_present("WATCH_CONFIG") {
+        tokio::spawn(config::watch_conf_file());
+    }
+
+    replication::set_master_auth(matches.value_of("MASTERAUTH").map(|p| p.to_owned()));
+    if let Some(master) = matches.value_of("REPLICAOF") {
+        let (host, port) = match master.rfind(':').map(|i| (&master[..i], master[i + 1..].parse::<u16>())) {
+            Some((host, Ok(port))) if !host.is_empty() => (host.to_owned(), port),
+            _ => {
+                eprintln!("Invalid value for --replicaof: expected <host:port>, got {}", master);
+                std::process::exit(1);
+            }
+        };
+        replication::start_replication(host, port);
+    }
+
+    shutdown::listen_for_signals();
+
+    let tls = match config::conf().tls {
+        None => None,
+        Some(tls_conf) => {
+            if tls_conf.port == 0 || tls_conf.port > u16::max_value() as usize {
+                eprintln!("Invalid tls.port: {}", tls_conf.port);
+                std::process::exit(1);
+            }
+            match tls::acceptor(&tls_conf) {
+                Ok(acceptor) => Some((tls_conf.port as u16, acceptor)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    // returns once a shutdown is requested and the listeners are closed
+    let unix = net_conf.unixsocket.clone().map(|path| (path, net_conf.unix_socket_mode()));
+    if let Err(e) = network::start_up(net_conf.bind_addresses(), net_conf.port as u16, tls, unix).await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+
+    let mode = shutdown::wait_for_shutdown().await;
+    network::drain_connections(Duration::from_secs(SHUTDOWN_DRAIN_TIMEOUT_SECS)).await;
+
+    let exit_code = if mode == shutdown::ShutdownMode::NoSave {
+        info!("Exiting without saving");
+        0
+    } else {
+        info!("Saving the final snapshot before exiting");
+        db::wait_for_save().await;
+        match db::save_db().await {
+            Ok(_) => 0,
+            Err(e) => {
+                error!("Error trying to save the DB, can't exit: {}", e);
+                1
+            }
+        }
+    };
+    info!("Escanor is now ready to exit, bye bye...");
+    std::process::exit(exit_code);
 }
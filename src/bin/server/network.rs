@@ -4,10 +4,17 @@ extern crate tokio;
 extern crate tokio_util;
 
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 //use tokio::prelude::*;
+use crate::acl;
 use crate::command;
+use crate::client;
+use crate::shutdown;
+use crate::replication;
 use crate::printer;
-use crate::printer::{print_from_error};
+use crate::tokenizer;
+use crate::printer::{print_from_error, print_err};
 
 use futures::SinkExt;
 use tokio::stream::StreamExt;
@@ -24,71 +31,103 @@ use crate::command::Command;
 
 #[derive(Clone,Debug)]
 pub struct Context{
-    pub client_addr : SocketAddr,
-    pub auth_is_required : bool,
-    pub auth_key : Option<String>,
+    pub client_id : u64,
+    /// peer address, `<socket path>:0` for unix socket clients
+    pub client_addr : String,
     pub client_authenticated : bool,
-    pub client_auth_key : Option<String>
+    /// hash of the password given to AUTH for the default user
+    pub client_auth_key : Option<String>,
+    /// ACL user set by AUTH <user> <pass>, `None` is the default user
+    pub user : Option<String>,
+    /// lowercased name of the command being executed, for ACL checks
+    pub command_name : String,
+    /// internal connections such as the master link skip auth and ACL checks
+    pub trusted : bool,
 }
 
 use std::net::{SocketAddr,Shutdown};
 use futures::io::Error;
 use serde_yaml::Value;
 use crate::config::ServerConf;
+use crate::config;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsAcceptor;
+use tokio::time::{self, Instant};
+use std::time::Duration;
+use std::sync::Arc;
+use tokio::sync::Notify;
 
-fn process_socket(socket: TcpStream){
+/// Serves a client connection, TCP, TLS or unix socket, whose peer address is `addrs`. The client
+/// is registered by the accept loop, so it counts towards `network.max_connections` straight away.
+fn process_socket<S>(socket: S, (client_id, kill_signal): (u64, Arc<Notify>), addrs: String, max_packet: usize)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
     // do work with socket here
     tokio::spawn(async move {
 
-        let addrs : SocketAddr = socket.peer_addr().unwrap();
-
-        use crate::config;
-
-        let conf_file = config::conf();
-
-        let require_auth = match &conf_file.server{
-            None => { None},
-            Some(server) => {
-                match &server.require_auth {
-                    None => {
-                        None
-                    },
-                    Some(t) => {
-                        Some(t)
-                    },
-                }
-            },
-        };
-
-        let auth_key =  match require_auth {
-            None => {
-                String::new()
-            },
-            Some(t) => {
-                t.to_owned()
-            },
-        };
-
         let mut context = Context {
+            client_id,
             client_addr: addrs,
-            auth_is_required : !auth_key.is_empty(),
-            auth_key: if auth_key.is_empty() {None}else { Some(auth_key) },
             client_authenticated : false,
-            client_auth_key : None
+            client_auth_key : None,
+            user : None,
+            command_name : String::new(),
+            trusted : false,
         };
 
-        let mut lines = RespCodec.framed(socket);
-        while let Some(message) = lines.next().await {
+        let mut lines = RespCodec::new(max_packet).framed(socket);
+        loop {
+            let message = tokio::select! {
+                message = lines.next() => message,
+                _ = kill_signal.notified() => {
+                    debug!("Client killed: {:?}", context);
+                    break;
+                }
+                // checked between requests so a command being executed always gets its reply
+                _ = shutdown::wait_for_shutdown() => {
+                    debug!("Closing connection for shutdown: {:?}", context);
+                    break;
+                }
+            };
+            let message = match message {
+                Some(message) => message,
+                None => break
+            };
             match message {
                 Ok(frame) => {
-                   let response_message = match command::compile_frame(frame) {
-                        Ok(cmd) => {
-                            let res = cmd.execute(&mut context).to_owned();
-                            res
-                        },
-                        Err(err) => {
-                            print_from_error(&err)
-                        },
+                    client::wait_if_paused().await;
+                    let cmd_name = tokenizer::command_name_from_frame(&frame).to_lowercase();
+                    client::touch(client_id, &cmd_name);
+                    context.command_name = acl::permission_name(&cmd_name, &tokenizer::arg_from_frame(&frame, 1));
+                    if cmd_name == "psync" {
+                        if let Err(e) = command::check_auth(&mut context) {
+                            let _ = lines.send(Frame::Error(e.trim_start_matches('-').trim_end().to_owned())).await;
+                            continue;
+                        }
+                        if let Err(e) = command::check_permission(&context, &[]) {
+                            let _ = lines.send(Frame::Error(e.trim_start_matches('-').trim_end().to_owned())).await;
+                            continue;
+                        }
+                        // the connection becomes a replication link until the replica goes away
+                        let args = tokenizer::generate_token_from_frame(frame);
+                        replication::serve_replica(lines, args, context.client_addr.clone()).await;
+                        break;
+                    }
+                    let is_write = replication::is_write_command(&cmd_name, &frame);
+                    let response_message = if is_write && replication::is_replica() {
+                        print_err("READONLY You can't write against a read only replica.")
+                    } else {
+                        match command::compile_frame(frame.clone()) {
+                            Ok(cmd) => {
+                                if is_write {
+                                    replication::execute_write(cmd, &frame, &mut context)
+                                } else {
+                                    cmd.execute(&mut context).to_owned()
+                                }
+                            },
+                            Err(err) => {
+                                print_from_error(&err)
+                            },
+                        }
                     };
                     let buf: BytesMut = BytesMut::from(response_message.as_bytes());
                     let (f,_) = decode_bytes(&buf).unwrap();
@@ -96,26 +135,210 @@ fn process_socket(socket: TcpStream){
                 }
                 Err(err) => {
                     debug!("Disconnected Context: {:?}", context);
-                    println!("Socket closed with error: {:?}", err); }
+                    println!("Socket closed with error: {:?}", err);
+                    let _ = lines.send(Frame::Error(format!("ERR Protocol error: {}", err))).await;
+                    break;
+                }
             };
         };
+        client::unregister(client_id);
     });
 }
 
-pub async fn start_up(addr: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut listener = TcpListener::bind(addr).await?;
+/// Turns the connection away when `network.max_connections` is reached
+async fn reject_if_full<S: AsyncWrite + Unpin>(socket: &mut S) -> bool {
+    let max_connections = config::conf().network.max_connections;
+    if max_connections > 0 && client::connected_clients() >= max_connections {
+        client::increment_rejected_connections();
+        let _ = socket.write_all(print_err("ERR max number of clients reached").as_bytes()).await;
+        return true;
+    }
+    false
+}
 
-    printer::print_app_info();
+async fn accept_connections(mut listener: TcpListener, tls: Option<TlsAcceptor>) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::wait_for_shutdown() => {
+                return;
+            }
+        };
+        match accepted {
+            Ok((mut socket, _addr)) => {
+                if reject_if_full(&mut socket).await {
+                    continue;
+                }
+                let addrs: SocketAddr = match socket.peer_addr() {
+                    Ok(t) => t,
+                    Err(e) => {
+                        error!("couldn't get client address: {:?}", e);
+                        continue;
+                    }
+                };
+                let max_packet = config::conf().network.max_packet;
+                let client = client::register(addrs.to_string());
+                match &tls {
+                    None => process_socket(socket, client, addrs.to_string(), max_packet),
+                    Some(acceptor) => {
+                        // the handshake runs in its own task so a slow client can't hold up the accept loop,
+                        // the client is already registered so pending handshakes count as connections
+                        let acceptor = acceptor.clone();
+                        tokio::spawn(async move {
+                            match acceptor.accept(socket).await {
+                                Ok(stream) => process_socket(stream, client, addrs.to_string(), max_packet),
+                                Err(e) => {
+                                    debug!("TLS handshake with {} failed: {}", addrs, e);
+                                    client::unregister(client.0);
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+            Err(e) => error!("couldn't get client: {:?}", e),
+        };
+    }
+}
 
-    info!("{}", style("Server initialized").green());
-    info!("Ready to accept connections");
+/// Binds the unix socket at `path`, replacing a socket left behind by a previous run
+#[cfg(unix)]
+fn bind_unix(path: &str, perm: Option<u32>) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(perm) = perm {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(perm))?;
+    }
+    Ok(listener)
+}
 
+#[cfg(unix)]
+async fn accept_unix_connections(mut listener: UnixListener, path: String) {
     loop {
-        match listener.accept().await {
-            Ok((socket, _addr)) => {
-                process_socket(socket);
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::wait_for_shutdown() => {
+                break;
+            }
+        };
+        match accepted {
+            Ok((mut socket, _addr)) => {
+                if reject_if_full(&mut socket).await {
+                    continue;
+                }
+                // unix clients have no peer address, like redis they are listed by the socket path
+                let addrs = format!("{}:0", path);
+                process_socket(socket, client::register(addrs.clone()), addrs, config::conf().network.max_packet);
             }
             Err(e) => error!("couldn't get client: {:?}", e),
         };
     }
-}
\ No newline at end of file
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Could not remove unix socket {}: {}", path, e);
+    }
+}
+
+/// Listens on `port`, with TLS on its port when `tls` is set and on the unix socket `unix` (path
+/// and octal file mode) when set. A port of 0 disables the plain TCP listeners.
+pub async fn start_up(binds: Vec<String>, port: u16, tls: Option<(u16, TlsAcceptor)>, unix: Option<(String, Option<u32>)>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ports: Vec<(u16, Option<TlsAcceptor>)> = vec![];
+    if port > 0 {
+        ports.push((port, None));
+    }
+    if let Some((tls_port, acceptor)) = tls {
+        ports.push((tls_port, Some(acceptor)));
+    }
+
+    let mut listeners: Vec<(TcpListener, Option<TlsAcceptor>)> = vec![];
+    for (port, acceptor) in ports {
+        for bind in &binds {
+            let listener = match TcpListener::bind((bind.as_str(), port)).await {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Could not bind {}:{} : {}", bind, port, e);
+                    return Err(Box::new(e));
+                }
+            };
+            info!("Listening on {}{}", listener.local_addr()?, if acceptor.is_some() { " (TLS)" } else { "" });
+            listeners.push((listener, acceptor.clone()));
+        }
+    }
+
+    #[cfg(unix)]
+    let unix_listener = match unix {
+        None => None,
+        Some((path, perm)) => {
+            let listener = match bind_unix(&path, perm) {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Could not bind unix socket {} : {}", path, e);
+                    return Err(Box::new(e));
+                }
+            };
+            info!("Listening on unix socket {}", path);
+            Some((listener, path))
+        }
+    };
+    #[cfg(not(unix))]
+    {
+        if let Some((path, _)) = unix {
+            warn!("Unix sockets are not supported on this platform, not listening on {}", path);
+        }
+    }
+
+    printer::print_app_info();
+
+    info!("{}", style("Server initialized").green());
+    info!("Ready to accept connections");
+
+    #[allow(unused_mut)]
+    let mut accept_tasks: Vec<_> = listeners.into_iter().map(|(listener, acceptor)| {
+        tokio::spawn(accept_connections(listener, acceptor))
+    }).collect();
+    #[cfg(unix)]
+    {
+        if let Some((listener, path)) = unix_listener {
+            accept_tasks.push(tokio::spawn(accept_unix_connections(listener, path)));
+        }
+    }
+    futures::future::join_all(accept_tasks).await;
+    info!("Stopped accepting connections");
+    Ok(())
+}
+
+/// Waits for open connections to finish their current command and close, up to `timeout`
+pub async fn drain_connections(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while client::connected_clients() > 0 {
+        if Instant::now() >= deadline {
+            warn!("{} connections still open after {} secs, closing anyway", client::connected_clients(), timeout.as_secs());
+            return;
+        }
+        time::delay_for(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_bind_unix_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("escanor-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+
+        let listener = bind_unix(&path, Some(0o700)).unwrap();
+        drop(listener);
+        // the socket file outlives the listener, binding again must not fail
+        let _listener = bind_unix(&path, Some(0o770)).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o770);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
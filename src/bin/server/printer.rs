@@ -65,10 +65,23 @@ pub fn print_nested_arr<T: ToString>(arr: Vec<Vec<T>>) -> String {
     str
 }
 
+/// Array of replies that are already encoded, e.g integers mixed with nils
+pub fn print_replies(replies: Vec<String>) -> String {
+    let mut str = format!("{}{}{}", ARRAY_PREFIX, replies.len(), CRLF);
+    for reply in replies {
+        str += &reply
+    };
+    str
+}
+
 pub fn print_string(str: &String) -> String {
     format!("{}{}{}{}{}", STRING_PREFIX, str.len(), CRLF, str, CRLF)
 }
 
+pub fn print_nil() -> String {
+    format!("{}-1{}", STRING_PREFIX, CRLF)
+}
+
 pub fn print_integer(int: i64) -> String {
     format!("{}{}{}", INT_PREFIX, int, CRLF)
 }
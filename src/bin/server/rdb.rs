@@ -0,0 +1,671 @@
+//! Reader and writer for the Redis RDB dump format, used to migrate datasets between
+//! Redis and escanor.
+
+use std::io::{Read, Write};
+
+use lazy_static::lazy_static;
+
+const RDB_MAGIC: &[u8] = b"REDIS";
+const RDB_MAX_VERSION: u32 = 12;
+const RDB_EXPORT_VERSION: u32 = 9;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+const RDB_OPCODE_FUNCTION_2: u8 = 0xF5;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_ENC_INT8: u64 = 0;
+const RDB_ENC_INT16: u64 = 1;
+const RDB_ENC_INT32: u64 = 2;
+const RDB_ENC_LZF: u64 = 3;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+
+// redis geo scores are 52 bit interleaved geohashes over these bounds
+const GEO_STEP: u32 = 26;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LNG_MIN: f64 = -180.0;
+const GEO_LNG_MAX: f64 = 180.0;
+
+lazy_static! {
+    static ref CRC64_TABLE : [u64; 256] = crc64_table();
+}
+
+/// Value of a single key as stored in the dump, members are kept as raw bytes
+#[derive(Debug, PartialEq)]
+pub enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+    ZSet(Vec<(Vec<u8>, f64)>),
+}
+
+#[derive(Debug)]
+pub struct RdbEntry {
+    pub db: u64,
+    pub key: Vec<u8>,
+    /// unix timestamp in milliseconds
+    pub expire_at: Option<i64>,
+    pub value: RdbValue,
+}
+
+fn crc64_table() -> [u64; 256] {
+    // reflected Jones polynomial, the checksum redis appends to its dumps
+    let mut table = [0u64; 256];
+    for i in 0..256 {
+        let mut crc = i as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x95ac9329ac4bc9b5 } else { crc >> 1 };
+        }
+        table[i] = crc;
+    }
+    table
+}
+
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for b in data {
+        crc = CRC64_TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Decodes a redis geo score into `(lat, lng)`, `None` when it can't be a geohash
+pub fn geohash_decode(score: f64) -> Option<(f64, f64)> {
+    if score < 0.0 || score.fract() != 0.0 || score >= (1u64 << (GEO_STEP * 2)) as f64 {
+        return None;
+    }
+    let bits = score as u64;
+    let mut lat_bits: u64 = 0;
+    let mut lng_bits: u64 = 0;
+    for i in 0..GEO_STEP {
+        lat_bits |= ((bits >> (2 * i)) & 1) << i;
+        lng_bits |= ((bits >> (2 * i + 1)) & 1) << i;
+    }
+    let cells = (1u64 << GEO_STEP) as f64;
+    let lat = GEO_LAT_MIN + (lat_bits as f64 + 0.5) / cells * (GEO_LAT_MAX - GEO_LAT_MIN);
+    let lng = GEO_LNG_MIN + (lng_bits as f64 + 0.5) / cells * (GEO_LNG_MAX - GEO_LNG_MIN);
+    Some((lat.max(GEO_LAT_MIN).min(GEO_LAT_MAX), lng.max(GEO_LNG_MIN).min(GEO_LNG_MAX)))
+}
+
+/// Encodes a point the way GEOADD does, `None` when it is outside the range redis accepts
+pub fn geohash_encode(lat: f64, lng: f64) -> Option<f64> {
+    if !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat) || !(GEO_LNG_MIN..=GEO_LNG_MAX).contains(&lng) {
+        return None;
+    }
+    let cells = (1u64 << GEO_STEP) as f64;
+    let lat_bits = (((lat - GEO_LAT_MIN) / (GEO_LAT_MAX - GEO_LAT_MIN)) * cells) as u64;
+    let lng_bits = (((lng - GEO_LNG_MIN) / (GEO_LNG_MAX - GEO_LNG_MIN)) * cells) as u64;
+    let max_bits = (1u64 << GEO_STEP) - 1;
+    let (lat_bits, lng_bits) = (lat_bits.min(max_bits), lng_bits.min(max_bits));
+    let mut bits: u64 = 0;
+    for i in 0..GEO_STEP {
+        bits |= ((lat_bits >> i) & 1) << (2 * i);
+        bits |= ((lng_bits >> i) & 1) << (2 * i + 1);
+    }
+    Some(bits as f64)
+}
+
+fn lzf_decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, String> {
+    let corrupted = || "Corrupted LZF string in RDB file".to_owned();
+    let mut output: Vec<u8> = Vec::with_capacity(expected_len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let end = i + ctrl + 1;
+            if end > input.len() { return Err(corrupted()); }
+            output.extend_from_slice(&input[i..end]);
+            i = end;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or_else(corrupted)? as usize;
+                i += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(corrupted)? as usize + 1;
+            i += 1;
+            if back > output.len() { return Err(corrupted()); }
+            let start = output.len() - back;
+            // the reference may overlap the bytes being written, so copy one at a time
+            for j in 0..len + 2 {
+                let b = output[start + j];
+                output.push(b);
+            }
+        }
+    }
+    if output.len() != expected_len {
+        return Err(corrupted());
+    }
+    Ok(output)
+}
+
+fn int_to_bytes(i: i64) -> Vec<u8> {
+    i.to_string().into_bytes()
+}
+
+fn parse_ziplist(data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let corrupted = || "Corrupted ziplist in RDB file".to_owned();
+    let read_le = |bytes: &[u8]| -> u64 {
+        bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+    };
+    let mut items = vec![];
+    // zlbytes, zltail and zllen
+    let mut i = 10;
+    loop {
+        let prev_len = *data.get(i).ok_or_else(corrupted)?;
+        if prev_len == 0xFF {
+            break;
+        }
+        i += if prev_len == 0xFE { 5 } else { 1 };
+        let enc = *data.get(i).ok_or_else(corrupted)?;
+        let (header, len): (usize, Option<usize>) = match enc >> 6 {
+            0 => (1, Some((enc & 0x3f) as usize)),
+            1 => (2, Some((((enc & 0x3f) as usize) << 8) | *data.get(i + 1).ok_or_else(corrupted)? as usize)),
+            2 => {
+                let bytes = data.get(i + 1..i + 5).ok_or_else(corrupted)?;
+                (5, Some(bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize)))
+            }
+            _ => (1, None),
+        };
+        i += header;
+        match len {
+            Some(len) => {
+                items.push(data.get(i..i + len).ok_or_else(corrupted)?.to_vec());
+                i += len;
+            }
+            None => {
+                let (size, value) = match enc {
+                    0xC0 => (2, read_le(data.get(i..i + 2).ok_or_else(corrupted)?) as i16 as i64),
+                    0xD0 => (4, read_le(data.get(i..i + 4).ok_or_else(corrupted)?) as i32 as i64),
+                    0xE0 => (8, read_le(data.get(i..i + 8).ok_or_else(corrupted)?) as i64),
+                    0xF0 => (3, ((read_le(data.get(i..i + 3).ok_or_else(corrupted)?) << 40) as i64) >> 40),
+                    0xFE => (1, *data.get(i).ok_or_else(corrupted)? as i8 as i64),
+                    0xF1..=0xFD => (0, (enc & 0x0f) as i64 - 1),
+                    _ => { return Err(corrupted()); }
+                };
+                items.push(int_to_bytes(value));
+                i += size;
+            }
+        }
+    }
+    Ok(items)
+}
+
+fn parse_listpack(data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let corrupted = || "Corrupted listpack in RDB file".to_owned();
+    let read_le = |bytes: &[u8]| -> u64 {
+        bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+    };
+    let mut items = vec![];
+    // total bytes and element count
+    let mut i = 6;
+    loop {
+        let enc = *data.get(i).ok_or_else(corrupted)?;
+        if enc == 0xFF {
+            break;
+        }
+        let start = i;
+        if enc & 0x80 == 0 {
+            items.push(int_to_bytes((enc & 0x7f) as i64));
+            i += 1;
+        } else if enc & 0xC0 == 0x80 {
+            let len = (enc & 0x3f) as usize;
+            items.push(data.get(i + 1..i + 1 + len).ok_or_else(corrupted)?.to_vec());
+            i += 1 + len;
+        } else if enc & 0xE0 == 0xC0 {
+            let raw = (((enc & 0x1f) as u64) << 8) | *data.get(i + 1).ok_or_else(corrupted)? as u64;
+            items.push(int_to_bytes(((raw << 51) as i64) >> 51));
+            i += 2;
+        } else if enc & 0xF0 == 0xE0 {
+            let len = (((enc & 0x0f) as usize) << 8) | *data.get(i + 1).ok_or_else(corrupted)? as usize;
+            items.push(data.get(i + 2..i + 2 + len).ok_or_else(corrupted)?.to_vec());
+            i += 2 + len;
+        } else {
+            let (size, value) = match enc {
+                0xF0 => {
+                    let len = read_le(data.get(i + 1..i + 5).ok_or_else(corrupted)?) as usize;
+                    items.push(data.get(i + 5..i + 5 + len).ok_or_else(corrupted)?.to_vec());
+                    i += 5 + len;
+                    (0, None)
+                }
+                0xF1 => (2, Some(read_le(data.get(i + 1..i + 3).ok_or_else(corrupted)?) as i16 as i64)),
+                0xF2 => (3, Some(((read_le(data.get(i + 1..i + 4).ok_or_else(corrupted)?) << 40) as i64) >> 40)),
+                0xF3 => (4, Some(read_le(data.get(i + 1..i + 5).ok_or_else(corrupted)?) as i32 as i64)),
+                0xF4 => (8, Some(read_le(data.get(i + 1..i + 9).ok_or_else(corrupted)?) as i64)),
+                _ => { return Err(corrupted()); }
+            };
+            if let Some(value) = value {
+                items.push(int_to_bytes(value));
+                i += 1 + size;
+            }
+        }
+        // skip the backlen, which stores the size of the entry in 7 bit groups
+        let entry_len = i - start;
+        i += match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+    }
+    Ok(items)
+}
+
+fn parse_intset(data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let corrupted = || "Corrupted intset in RDB file".to_owned();
+    let read_le = |bytes: &[u8]| -> u64 {
+        bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+    };
+    let width = read_le(data.get(0..4).ok_or_else(corrupted)?) as usize;
+    let len = read_le(data.get(4..8).ok_or_else(corrupted)?) as usize;
+    let mut items = vec![];
+    for n in 0..len {
+        let bytes = data.get(8 + n * width..8 + (n + 1) * width).ok_or_else(corrupted)?;
+        let value = match width {
+            2 => read_le(bytes) as i16 as i64,
+            4 => read_le(bytes) as i32 as i64,
+            8 => read_le(bytes) as i64,
+            _ => { return Err(corrupted()); }
+        };
+        items.push(int_to_bytes(value));
+    }
+    Ok(items)
+}
+
+fn pairs<T>(items: Vec<T>) -> Result<Vec<(T, T)>, String> {
+    if items.len() % 2 != 0 {
+        return Err("Odd number of elements in RDB pair encoding".to_owned());
+    }
+    let mut pairs = vec![];
+    let mut itr = items.into_iter();
+    while let (Some(a), Some(b)) = (itr.next(), itr.next()) {
+        pairs.push((a, b));
+    }
+    Ok(pairs)
+}
+
+fn parse_score(bytes: &[u8]) -> Result<f64, String> {
+    String::from_utf8_lossy(bytes).parse::<f64>()
+        .map_err(|_| "Invalid sorted set score in RDB file".to_owned())
+}
+
+pub struct RdbReader<R> {
+    reader: R,
+    version: u32,
+    crc: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    pub fn new(reader: R) -> Result<Self, String> {
+        let mut rdb = RdbReader { reader, version: 0, crc: 0 };
+        let header = rdb.read_bytes(9)?;
+        if !header.starts_with(RDB_MAGIC) {
+            return Err("Not an RDB file".to_owned());
+        }
+        rdb.version = match String::from_utf8_lossy(&header[5..]).parse::<u32>() {
+            Ok(v) if v >= 1 && v <= RDB_MAX_VERSION => v,
+            _ => { return Err(format!("Unsupported RDB version {}", String::from_utf8_lossy(&header[5..]))); }
+        };
+        Ok(rdb)
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, String> {
+        let mut buf = vec![0u8; n];
+        if let Err(e) = self.reader.read_exact(&mut buf) {
+            return Err(format!("Error reading RDB file: {}", e));
+        }
+        self.crc = crc64(self.crc, &buf);
+        Ok(buf)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64, String> {
+        let bytes = self.read_bytes(8)?;
+        Ok(bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// Returns the length and whether it is a special string encoding instead
+    fn read_length_with_encoding(&mut self) -> Result<(u64, bool), String> {
+        let b = self.read_u8()?;
+        return match b >> 6 {
+            0 => Ok(((b & 0x3f) as u64, false)),
+            1 => Ok(((((b & 0x3f) as u64) << 8) | self.read_u8()? as u64, false)),
+            3 => Ok(((b & 0x3f) as u64, true)),
+            _ => {
+                let size = match b {
+                    0x80 => 4,
+                    0x81 => 8,
+                    _ => { return Err("Invalid length encoding in RDB file".to_owned()); }
+                };
+                let bytes = self.read_bytes(size)?;
+                Ok((bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64), false))
+            }
+        };
+    }
+
+    fn read_length(&mut self) -> Result<u64, String> {
+        match self.read_length_with_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err("Unexpected string encoding in RDB file".to_owned())
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, String> {
+        let (len, encoded) = self.read_length_with_encoding()?;
+        if !encoded {
+            return self.read_bytes(len as usize);
+        }
+        return match len {
+            RDB_ENC_INT8 => Ok(int_to_bytes(self.read_u8()? as i8 as i64)),
+            RDB_ENC_INT16 => {
+                let b = self.read_bytes(2)?;
+                Ok(int_to_bytes(i16::from_le_bytes([b[0], b[1]]) as i64))
+            }
+            RDB_ENC_INT32 => {
+                let b = self.read_bytes(4)?;
+                Ok(int_to_bytes(i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64))
+            }
+            RDB_ENC_LZF => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            _ => Err("Unknown string encoding in RDB file".to_owned())
+        };
+    }
+
+    fn read_double_string(&mut self) -> Result<f64, String> {
+        return match self.read_u8()? {
+            253 => Ok(std::f64::NAN),
+            254 => Ok(std::f64::INFINITY),
+            255 => Ok(std::f64::NEG_INFINITY),
+            len => {
+                let bytes = self.read_bytes(len as usize)?;
+                parse_score(&bytes)
+            }
+        };
+    }
+
+    fn read_strings(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        let len = self.read_length()?;
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(self.read_string()?);
+        }
+        Ok(items)
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<RdbValue, String> {
+        let value = match value_type {
+            RDB_TYPE_STRING => RdbValue::String(self.read_string()?),
+            RDB_TYPE_LIST => RdbValue::List(self.read_strings()?),
+            RDB_TYPE_SET => RdbValue::Set(self.read_strings()?),
+            RDB_TYPE_HASH => RdbValue::Hash(pairs(self.read_strings()?)?),
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut members = vec![];
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == RDB_TYPE_ZSET_2 {
+                        f64::from_bits(self.read_u64_le()?)
+                    } else {
+                        self.read_double_string()?
+                    };
+                    members.push((member, score));
+                }
+                RdbValue::ZSet(members)
+            }
+            RDB_TYPE_LIST_ZIPLIST => RdbValue::List(parse_ziplist(&self.read_string()?)?),
+            RDB_TYPE_SET_INTSET => RdbValue::Set(parse_intset(&self.read_string()?)?),
+            RDB_TYPE_SET_LISTPACK => RdbValue::Set(parse_listpack(&self.read_string()?)?),
+            RDB_TYPE_HASH_ZIPLIST => RdbValue::Hash(pairs(parse_ziplist(&self.read_string()?)?)?),
+            RDB_TYPE_HASH_LISTPACK => RdbValue::Hash(pairs(parse_listpack(&self.read_string()?)?)?),
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let items = if value_type == RDB_TYPE_ZSET_ZIPLIST {
+                    parse_ziplist(&self.read_string()?)?
+                } else {
+                    parse_listpack(&self.read_string()?)?
+                };
+                let mut members = vec![];
+                for (member, score) in pairs(items)? {
+                    members.push((member, parse_score(&score)?));
+                }
+                RdbValue::ZSet(members)
+            }
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut items = vec![];
+                for _ in 0..nodes {
+                    let container = if value_type == RDB_TYPE_LIST_QUICKLIST_2 { self.read_length()? } else { 0 };
+                    let node = self.read_string()?;
+                    if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                        items.push(node);
+                    } else if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                        items.extend(parse_listpack(&node)?);
+                    } else {
+                        items.extend(parse_ziplist(&node)?);
+                    }
+                }
+                RdbValue::List(items)
+            }
+            t => {
+                return Err(format!("Unsupported RDB value type {}", t));
+            }
+        };
+        Ok(value)
+    }
+
+    /// Calls `on_entry` for every key of the dump and verifies the trailing checksum
+    pub fn read_entries<F: FnMut(RdbEntry)>(&mut self, mut on_entry: F) -> Result<(), String> {
+        let mut db: u64 = 0;
+        let mut expire_at: Option<i64> = None;
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                RDB_OPCODE_EOF => { break; }
+                RDB_OPCODE_SELECTDB => { db = self.read_length()?; }
+                RDB_OPCODE_RESIZEDB => {
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                RDB_OPCODE_AUX => {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+                RDB_OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        self.read_length()?;
+                    }
+                }
+                RDB_OPCODE_FUNCTION_2 => { self.read_string()?; }
+                RDB_OPCODE_IDLE => { self.read_length()?; }
+                RDB_OPCODE_FREQ => { self.read_u8()?; }
+                RDB_OPCODE_EXPIRETIME_MS => { expire_at = Some(self.read_u64_le()? as i64); }
+                RDB_OPCODE_EXPIRETIME => {
+                    let b = self.read_bytes(4)?;
+                    expire_at = Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64 * 1000);
+                }
+                value_type => {
+                    let key = self.read_string()?;
+                    let value = self.read_value(value_type)?;
+                    on_entry(RdbEntry { db, key, expire_at: expire_at.take(), value });
+                }
+            }
+        }
+
+        if self.version >= 5 {
+            let expected = self.crc;
+            let checksum = self.read_u64_le()?;
+            // a zero checksum means the dump was saved with rdbchecksum disabled
+            if checksum != 0 && checksum != expected {
+                return Err("RDB file checksum mismatch".to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct RdbWriter<W> {
+    writer: W,
+    crc: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    /// Writes the header and selects db 0, the only keyspace escanor has
+    pub fn new(writer: W, keys: u64, expires: u64) -> Result<Self, String> {
+        let mut rdb = RdbWriter { writer, crc: 0 };
+        rdb.write_bytes(format!("REDIS{:04}", RDB_EXPORT_VERSION).as_bytes())?;
+        rdb.write_bytes(&[RDB_OPCODE_AUX])?;
+        rdb.write_string(b"redis-bits")?;
+        rdb.write_string(b"64")?;
+        rdb.write_bytes(&[RDB_OPCODE_SELECTDB])?;
+        rdb.write_length(0)?;
+        rdb.write_bytes(&[RDB_OPCODE_RESIZEDB])?;
+        rdb.write_length(keys)?;
+        rdb.write_length(expires)?;
+        Ok(rdb)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.crc = crc64(self.crc, bytes);
+        self.writer.write_all(bytes).map_err(|e| format!("Error writing RDB file: {}", e))
+    }
+
+    fn write_length(&mut self, len: u64) -> Result<(), String> {
+        if len < 1 << 6 {
+            self.write_bytes(&[len as u8])
+        } else if len < 1 << 14 {
+            self.write_bytes(&[((len >> 8) as u8) | 0x40, len as u8])
+        } else if len <= std::u32::MAX as u64 {
+            self.write_bytes(&[0x80])?;
+            self.write_bytes(&(len as u32).to_be_bytes())
+        } else {
+            self.write_bytes(&[0x81])?;
+            self.write_bytes(&len.to_be_bytes())
+        }
+    }
+
+    fn write_string(&mut self, s: &[u8]) -> Result<(), String> {
+        self.write_length(s.len() as u64)?;
+        self.write_bytes(s)
+    }
+
+    fn write_key(&mut self, value_type: u8, key: &[u8], expire_at: Option<i64>) -> Result<(), String> {
+        if let Some(expire_at) = expire_at {
+            self.write_bytes(&[RDB_OPCODE_EXPIRETIME_MS])?;
+            self.write_bytes(&(expire_at as u64).to_le_bytes())?;
+        }
+        self.write_bytes(&[value_type])?;
+        self.write_string(key)
+    }
+
+    pub fn write_string_entry(&mut self, key: &[u8], value: &[u8], expire_at: Option<i64>) -> Result<(), String> {
+        self.write_key(RDB_TYPE_STRING, key, expire_at)?;
+        self.write_string(value)
+    }
+
+    pub fn write_zset_entry(&mut self, key: &[u8], members: &[(Vec<u8>, f64)], expire_at: Option<i64>) -> Result<(), String> {
+        self.write_key(RDB_TYPE_ZSET_2, key, expire_at)?;
+        self.write_length(members.len() as u64)?;
+        for (member, score) in members {
+            self.write_string(member)?;
+            self.write_bytes(&score.to_bits().to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of file marker and the checksum, then returns the inner writer
+    pub fn finish(mut self) -> Result<W, String> {
+        self.write_bytes(&[RDB_OPCODE_EOF])?;
+        let checksum = self.crc.to_le_bytes();
+        if let Err(e) = self.writer.write_all(&checksum).and_then(|_| self.writer.flush()) {
+            return Err(format!("Error writing RDB file: {}", e));
+        }
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_geohash_round_trip() {
+        let score = geohash_encode(5.6037, -0.1870).unwrap();
+        let (lat, lng) = geohash_decode(score).unwrap();
+        assert!((lat - 5.6037).abs() < 0.0001);
+        assert!((lng - -0.1870).abs() < 0.0001);
+        assert_eq!(geohash_encode(89.0, 0.0), None);
+        assert_eq!(geohash_decode(1.5), None);
+    }
+
+    #[test]
+    fn test_rdb_round_trip() {
+        let mut rdb = RdbWriter::new(vec![], 2, 1).unwrap();
+        rdb.write_string_entry(b"name", b"escanor", Some(1600000000000)).unwrap();
+        rdb.write_zset_entry(b"places", &[(b"accra".to_vec(), 3471986187393723.0)], None).unwrap();
+        let content = rdb.finish().unwrap();
+
+        let mut entries = vec![];
+        RdbReader::new(&content[..]).unwrap().read_entries(|entry| entries.push(entry)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, b"name".to_vec());
+        assert_eq!(entries[0].expire_at, Some(1600000000000));
+        assert_eq!(entries[0].value, RdbValue::String(b"escanor".to_vec()));
+        assert_eq!(entries[1].value, RdbValue::ZSet(vec![(b"accra".to_vec(), 3471986187393723.0)]));
+
+        let mut corrupted = content.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert!(RdbReader::new(&corrupted[..]).unwrap().read_entries(|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_parse_listpack() {
+        // "a", 7, -2 as written by redis 7 for a small list
+        let lp: Vec<u8> = vec![16, 0, 0, 0, 3, 0, 0x81, b'a', 2, 0x07, 1, 0xDF, 0xFE, 2, 0xFF];
+        assert_eq!(parse_listpack(&lp).unwrap(), vec![b"a".to_vec(), b"7".to_vec(), b"-2".to_vec()]);
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // literal "ab" followed by a back reference repeating it twice
+        let compressed: Vec<u8> = vec![1, b'a', b'b', 0x40, 1];
+        assert_eq!(lzf_decompress(&compressed, 6).unwrap(), b"ababab".to_vec());
+    }
+}
@@ -0,0 +1,505 @@
+//! Master to replica replication.
+//!
+//! A replica sends `PSYNC <replid> <offset>`. When the id matches and the offset is still in
+//! the backlog the master answers `+CONTINUE` and resends the missing bytes, otherwise it
+//! answers `+FULLRESYNC <replid> <offset>` followed by the dataset as a bulk string. After
+//! that every successful write is streamed to the replica as the RESP command the client sent,
+//! offsets count the bytes of that stream.
+//!
+//! The dataset is a snapshot written to a file while every write stripe is held, so it holds
+//! exactly the writes made before the offset the stream starts at. Writes wait for the
+//! snapshot to be written, reads go on.
+
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::SinkExt;
+use lazy_static::lazy_static;
+use nanoid::nanoid;
+use redis_protocol::prelude::*;
+use redis_protocol::types::Frame;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::stream::StreamExt;
+use tokio::sync::{broadcast, Notify};
+use tokio::time;
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::codec::RespCodec;
+use crate::command::{self, Command, ReplicaOfCmd};
+use crate::network::Context;
+use crate::printer::*;
+use crate::{db, shutdown, tokenizer, util};
+
+const REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+const REPL_FEED_CAPACITY: usize = 10_000;
+const REPL_RECONNECT_DELAY_SECS: u64 = 1;
+const WRITE_LOCK_STRIPES: usize = 64;
+
+/// Commands that change the dataset, they are rejected on replicas and streamed by masters
+const WRITE_COMMANDS: &[&str] = &[
+    "set", "getset", "del", "persist", "expire", "expire_at", "restore", "flushdb",
+    "geoadd", "geodel", "georem",
+    "jsetr", "jset", "jmerge", "jdel", "jrem", "jincrby", "jincrbyfloat",
+    "jpatch", "jpathset", "jpathdel", "jpathincrby", "jpathstrappend", "jpatharrappend", "jpatharrinsert",
+    "jpatharrpop", "jpatharrtrim",
+    "jarrappend", "jarrinsert", "jarrpop", "jarrtrim", "jstrappend",
+];
+
+/// Commands that only write with some of their subcommands, `JINDEX LIST` or `JSCHEMA GET` are reads
+const WRITE_SUBCOMMANDS: &[(&str, &[&str])] = &[
+    ("jindex", &["create", "drop"]),
+    ("jschema", &["set", "del"]),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Master,
+    Replica { host: String, port: u16 },
+}
+
+struct Backlog {
+    buf: VecDeque<u8>,
+    /// replication offset of the first byte in `buf`
+    start_offset: u64,
+}
+
+lazy_static! {
+    static ref ROLE : RwLock<Role> = RwLock::new(Role::Master);
+    static ref REPL_ID : RwLock<String> = RwLock::new(new_repl_id());
+    static ref REPL_OFFSET : AtomicU64 = AtomicU64::new(0);
+    static ref BACKLOG : Mutex<Backlog> = Mutex::new(Backlog { buf: VecDeque::new(), start_offset: 0 });
+    // writes to a key execute and propagate holding the stripe of the key, so the stream has the
+    // order they were applied in without writes to other keys waiting on them
+    static ref WRITE_LOCKS : Vec<Mutex<()>> = (0..WRITE_LOCK_STRIPES).map(|_| Mutex::new(())).collect();
+    static ref REPL_FEED : broadcast::Sender<Arc<Vec<u8>>> = broadcast::channel(REPL_FEED_CAPACITY).0;
+    static ref CONNECTED_REPLICAS : AtomicUsize = AtomicUsize::new(0);
+    static ref MASTER_LINK_UP : AtomicBool = AtomicBool::new(false);
+    static ref MASTER_AUTH : RwLock<Option<String>> = RwLock::new(None);
+    // bumped whenever the replica task must stop, a running task exits when it sees a new value
+    static ref REPLICA_GENERATION : AtomicU64 = AtomicU64::new(0);
+    static ref REPLICA_STOP : Arc<Notify> = Arc::new(Notify::new());
+}
+
+fn new_repl_id() -> String {
+    nanoid!(40, &util::ALPHA_NUMERIC)
+}
+
+/// Whether the request in `frame` changes the dataset, `name` being its command name
+pub fn is_write_command(name: &str, frame: &Frame) -> bool {
+    let name = name.to_lowercase();
+    if WRITE_COMMANDS.contains(&name.as_str()) {
+        return true;
+    }
+    match WRITE_SUBCOMMANDS.iter().find(|(command, _)| *command == name) {
+        Some((_, subcommands)) => subcommands.contains(&tokenizer::arg_from_frame(frame, 1).to_lowercase().as_str()),
+        None => false
+    }
+}
+
+pub fn is_replica() -> bool {
+    *ROLE.read().unwrap() != Role::Master
+}
+
+/// Password sent with AUTH when connecting to a master, set from `--masterauth`
+pub fn set_master_auth(password: Option<String>) {
+    *MASTER_AUTH.write().unwrap() = password;
+}
+
+fn append_to_backlog(backlog: &mut Backlog, frame: &Frame) {
+    let mut buf = BytesMut::new();
+    let _ = encode_bytes(&mut buf, frame);
+    backlog.buf.extend(buf.iter());
+    while backlog.buf.len() > REPL_BACKLOG_SIZE {
+        backlog.buf.pop_front();
+        backlog.start_offset += 1;
+    }
+    REPL_OFFSET.fetch_add(buf.len() as u64, Ordering::SeqCst);
+    // no receivers just means no replica is connected
+    let _ = REPL_FEED.send(Arc::new(buf.to_vec()));
+}
+
+/// Write lock stripes of `keys` in ascending order, so writers taking several never wait on each
+/// other in a cycle. Writes without keys, FLUSHDB or JINDEX CREATE, take every stripe.
+fn write_stripes(keys: &[&str]) -> Vec<usize> {
+    if keys.is_empty() {
+        return (0..WRITE_LOCK_STRIPES).collect();
+    }
+    let mut stripes: Vec<usize> = keys.iter().map(|key| {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % WRITE_LOCK_STRIPES
+    }).collect();
+    stripes.sort_unstable();
+    stripes.dedup();
+    stripes
+}
+
+/// Takes the write lock stripes of `keys`, see `write_stripes`. A stripe held for a while, by a
+/// full sync writing its snapshot, is waited for off the async worker.
+fn lock_stripes(keys: &[&str]) -> Vec<MutexGuard<'static, ()>> {
+    // the stripes guard no data, a panicked write leaves nothing to recover
+    write_stripes(keys).into_iter().map(|i| match WRITE_LOCKS[i].try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => tokio::task::block_in_place(|| {
+            WRITE_LOCKS[i].lock().unwrap_or_else(PoisonError::into_inner)
+        }),
+    }).collect()
+}
+
+/// Runs a write issued by a client and streams it to the replicas when it succeeded
+pub fn execute_write(cmd: Box<dyn Command>, frame: &Frame, context: &mut Context) -> String {
+    let _stripes = lock_stripes(&cmd.keys());
+    let res = cmd.execute(context);
+    if !res.starts_with('-') {
+        append_to_backlog(&mut BACKLOG.lock().unwrap(), frame);
+    }
+    res
+}
+
+/// Applies a write received from the master, it is kept in the backlog whatever the outcome
+/// so the offset stays in step with the master and chained replicas see the same stream
+fn apply_replicated(frame: Frame, context: &mut Context) {
+    // the stripes keep the write out of a snapshot written for a replica of this replica
+    match command::compile_frame(frame.clone()) {
+        Ok(cmd) => {
+            let _stripes = lock_stripes(&cmd.keys());
+            let mut backlog = BACKLOG.lock().unwrap();
+            let res = cmd.execute(context);
+            if res.starts_with('-') {
+                warn!("Replicated command failed: {}", res.trim_end());
+            }
+            append_to_backlog(&mut backlog, &frame);
+        }
+        Err(_) => {
+            warn!("Could not compile replicated command {:?}", frame);
+            let _stripes = lock_stripes(&[]);
+            append_to_backlog(&mut BACKLOG.lock().unwrap(), &frame);
+        }
+    }
+}
+
+enum SyncStart {
+    Partial(String, Vec<u8>, broadcast::Receiver<Arc<Vec<u8>>>),
+    Full(String),
+}
+
+/// Writes the snapshot of a full sync with every write stripe held and subscribes to the feed
+/// before letting writes go on, so the stream starts right after the last write in the snapshot.
+/// Returns the feed, the offset the stream starts at and the snapshot file.
+fn write_full_sync() -> Result<(broadcast::Receiver<Arc<Vec<u8>>>, u64, PathBuf), String> {
+    // runs on a blocking thread, waiting for the stripes needs no block_in_place
+    let _stripes: Vec<MutexGuard<()>> = (0..WRITE_LOCK_STRIPES)
+        .map(|i| WRITE_LOCKS[i].lock().unwrap_or_else(PoisonError::into_inner))
+        .collect();
+    let (feed, offset) = {
+        let _backlog = BACKLOG.lock().unwrap();
+        (REPL_FEED.subscribe(), REPL_OFFSET.load(Ordering::SeqCst))
+    };
+    let path = db::write_sync_file()?;
+    Ok((feed, offset, path))
+}
+
+/// Sends the snapshot at `path` as a bulk string without reading it into memory
+async fn send_snapshot<S>(link: &mut Framed<S, RespCodec>, path: &Path) -> std::io::Result<u64>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let socket = link.get_mut();
+    socket.write_all(format!("${}\r\n", len).as_bytes()).await?;
+    tokio::io::copy(&mut file, socket).await?;
+    socket.write_all(b"\r\n").await?;
+    Ok(len)
+}
+
+/// Serves a replica that sent PSYNC on `link`, returns once the replica disconnects
+pub async fn serve_replica<S>(mut link: Framed<S, RespCodec>, args: Vec<String>, addr: String)
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let repl_id = args.get(1).cloned().unwrap_or_default();
+    let offset = args.get(2).and_then(|o| o.parse::<i64>().ok()).unwrap_or(-1);
+
+    // subscribing under the backlog lock keeps writes from slipping between the offset and the feed
+    let start = {
+        let backlog = BACKLOG.lock().unwrap();
+        let master_id = REPL_ID.read().unwrap().to_owned();
+        let master_offset = REPL_OFFSET.load(Ordering::SeqCst);
+        if repl_id == master_id && offset >= backlog.start_offset as i64 && offset as u64 <= master_offset {
+            let missing: Vec<u8> = backlog.buf.iter().skip((offset as u64 - backlog.start_offset) as usize).cloned().collect();
+            SyncStart::Partial(master_id, missing, REPL_FEED.subscribe())
+        } else {
+            SyncStart::Full(master_id)
+        }
+    };
+
+    let (sent, mut feed) = match start {
+        SyncStart::Partial(id, missing, feed) => {
+            info!("Partial resync with replica {}, {} bytes from the backlog", addr, missing.len());
+            let sent = match link.send(Frame::SimpleString(format!("CONTINUE {}", id))).await {
+                Ok(_) => link.get_mut().write_all(&missing).await,
+                Err(e) => Err(e)
+            };
+            (sent, feed)
+        }
+        SyncStart::Full(id) => {
+            let written = tokio::task::spawn_blocking(write_full_sync).await
+                .unwrap_or_else(|e| Err(format!("Sync task failed: {}", e)));
+            let (feed, offset, path) = match written {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Full resync with replica {} failed: {}", addr, e);
+                    let _ = link.send(Frame::Error(format!("ERR {}", e))).await;
+                    return;
+                }
+            };
+            let sent = match link.send(Frame::SimpleString(format!("FULLRESYNC {} {}", id, offset))).await {
+                Ok(_) => send_snapshot(&mut link, &path).await,
+                Err(e) => Err(e)
+            };
+            let _ = tokio::fs::remove_file(&path).await;
+            (sent.map(|len| info!("Full resync with replica {}, {} bytes", addr, len)), feed)
+        }
+    };
+    if let Err(e) = sent {
+        warn!("Lost replica {}: {}", addr, e);
+        return;
+    }
+
+    CONNECTED_REPLICAS.fetch_add(1, Ordering::SeqCst);
+    let (mut reader, mut writer) = tokio::io::split(link.into_inner());
+    let mut discard = [0u8; 512];
+    loop {
+        tokio::select! {
+            bytes = feed.recv() => {
+                let bytes = match bytes {
+                    Ok(bytes) => bytes,
+                    Err(broadcast::RecvError::Lagged(n)) => {
+                        warn!("Replica {} fell {} writes behind, dropping it so it resyncs", addr, n);
+                        break;
+                    }
+                    Err(broadcast::RecvError::Closed) => break,
+                };
+                if let Err(e) = writer.write_all(&bytes).await {
+                    warn!("Lost replica {}: {}", addr, e);
+                    break;
+                }
+            }
+            // replicas don't send anything after PSYNC, reading only detects the disconnect
+            read = reader.read(&mut discard) => {
+                match read {
+                    Ok(0) | Err(_) => {
+                        info!("Replica {} disconnected", addr);
+                        break;
+                    }
+                    Ok(_) => {}
+                }
+            }
+            _ = shutdown::wait_for_shutdown() => break,
+        }
+    }
+    CONNECTED_REPLICAS.fetch_sub(1, Ordering::SeqCst);
+}
+
+async fn send_command(link: &mut Framed<TcpStream, RespCodec>, args: &[&str]) -> Result<Frame, String> {
+    let frame = Frame::Array(args.iter().map(|a| Frame::BulkString(a.as_bytes().to_vec())).collect());
+    if let Err(e) = link.send(frame).await {
+        return Err(e.to_string());
+    }
+    return match link.next().await {
+        Some(Ok(Frame::Error(e))) => Err(e),
+        Some(Ok(frame)) => Ok(frame),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Err("connection closed by master".to_owned()),
+    };
+}
+
+/// Syncs with the master then applies its stream, returns `Ok` once the task was stopped
+async fn sync_with_master(generation: u64, host: &str, port: u16) -> Result<(), String> {
+    let stream = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+    let master_addr = stream.peer_addr().map_err(|e| e.to_string())?;
+    // no size limit, the full sync payload is the whole dataset
+    let mut link = RespCodec::new(0).framed(stream);
+
+    let master_auth = MASTER_AUTH.read().unwrap().to_owned();
+    if let Some(password) = master_auth {
+        send_command(&mut link, &["AUTH", &password]).await?;
+    }
+
+    let repl_id = REPL_ID.read().unwrap().to_owned();
+    let offset = REPL_OFFSET.load(Ordering::SeqCst).to_string();
+    let reply = match send_command(&mut link, &["PSYNC", &repl_id, &offset]).await? {
+        Frame::SimpleString(s) => s,
+        f => { return Err(format!("unexpected PSYNC reply {:?}", f)); }
+    };
+
+    let parts: Vec<&str> = reply.split(' ').collect();
+    match parts.as_slice() {
+        ["FULLRESYNC", id, offset] => {
+            let offset = offset.parse::<u64>().map_err(|_| "invalid FULLRESYNC offset".to_owned())?;
+            let content = match link.next().await {
+                Some(Ok(Frame::BulkString(content))) => content,
+                Some(Err(e)) => { return Err(e.to_string()); }
+                _ => { return Err("missing full resync payload".to_owned()); }
+            };
+            let mut backlog = BACKLOG.lock().unwrap();
+            db::replace_database(&content)?;
+            backlog.buf.clear();
+            backlog.start_offset = offset;
+            REPL_OFFSET.store(offset, Ordering::SeqCst);
+            *REPL_ID.write().unwrap() = id.to_string();
+            info!("Full resync with master {} done, {} bytes", master_addr, content.len());
+        }
+        ["CONTINUE", ..] => {
+            info!("Partial resync with master {} from offset {}", master_addr, offset);
+        }
+        _ => { return Err(format!("unexpected PSYNC reply {}", reply)); }
+    }
+
+    MASTER_LINK_UP.store(true, Ordering::SeqCst);
+    let mut context = Context {
+        client_id: 0,
+        client_addr: master_addr.to_string(),
+        client_authenticated: true,
+        client_auth_key: None,
+        user: None,
+        command_name: String::new(),
+        trusted: true,
+    };
+    loop {
+        let frame = tokio::select! {
+            frame = link.next() => frame,
+            _ = REPLICA_STOP.notified() => {
+                if REPLICA_GENERATION.load(Ordering::SeqCst) != generation {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        match frame {
+            Some(Ok(frame)) => apply_replicated(frame, &mut context),
+            Some(Err(e)) => { return Err(e.to_string()); }
+            None => { return Err("connection closed by master".to_owned()); }
+        }
+    }
+}
+
+async fn run_replica(generation: u64, host: String, port: u16) {
+    while REPLICA_GENERATION.load(Ordering::SeqCst) == generation {
+        info!("Connecting to master {}:{}", host, port);
+        let result = sync_with_master(generation, &host, port).await;
+        MASTER_LINK_UP.store(false, Ordering::SeqCst);
+        match result {
+            Ok(_) => { return; }
+            Err(e) => warn!("Replication link with {}:{} lost: {}", host, port, e),
+        }
+        time::delay_for(Duration::from_secs(REPL_RECONNECT_DELAY_SECS)).await;
+    }
+}
+
+/// Makes this server a replica of `host:port`, a running replication link is dropped first
+pub fn start_replication(host: String, port: u16) {
+    *ROLE.write().unwrap() = Role::Replica { host: host.to_owned(), port };
+    let generation = REPLICA_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    REPLICA_STOP.notify();
+    tokio::spawn(run_replica(generation, host, port));
+}
+
+/// Turns a replica back into a master, the data it already has is kept
+pub fn stop_replication() {
+    *ROLE.write().unwrap() = Role::Master;
+    REPLICA_GENERATION.fetch_add(1, Ordering::SeqCst);
+    REPLICA_STOP.notify();
+    // a new history starts here, replicas of the old master must full sync
+    *REPL_ID.write().unwrap() = new_repl_id();
+    let mut backlog = BACKLOG.lock().unwrap();
+    backlog.buf.clear();
+    backlog.start_offset = REPL_OFFSET.load(Ordering::SeqCst);
+}
+
+pub fn info() -> String {
+    let mut info = String::from("# Replication\r\n");
+    let role = ROLE.read().unwrap().to_owned();
+    match role {
+        Role::Master => {
+            info += "role:master\r\n";
+        }
+        Role::Replica { host, port } => {
+            info += "role:slave\r\n";
+            info += &format!("master_host:{}\r\n", host);
+            info += &format!("master_port:{}\r\n", port);
+            info += &format!("master_link_status:{}\r\n", if MASTER_LINK_UP.load(Ordering::SeqCst) { "up" } else { "down" });
+            info += &format!("slave_repl_offset:{}\r\n", REPL_OFFSET.load(Ordering::SeqCst));
+        }
+    }
+    info += &format!("connected_slaves:{}\r\n", CONNECTED_REPLICAS.load(Ordering::SeqCst));
+    info += &format!("master_replid:{}\r\n", REPL_ID.read().unwrap());
+    info += &format!("master_repl_offset:{}\r\n", REPL_OFFSET.load(Ordering::SeqCst));
+    let backlog = BACKLOG.lock().unwrap();
+    info += &format!("repl_backlog_size:{}\r\n", REPL_BACKLOG_SIZE);
+    info += &format!("repl_backlog_first_byte_offset:{}\r\n", backlog.start_offset);
+    info += &format!("repl_backlog_histlen:{}\r\n", backlog.buf.len());
+    info
+}
+
+pub fn replica_of(cmd: &ReplicaOfCmd) -> String {
+    match &cmd.arg_master {
+        None => {
+            if is_replica() {
+                info!("Replication stopped, this server is now a master");
+                stop_replication();
+            }
+        }
+        Some((host, port)) => {
+            let target = Role::Replica { host: host.to_owned(), port: *port };
+            if *ROLE.read().unwrap() == target {
+                return print_str("OK Already connected to specified master");
+            }
+            start_replication(host.to_owned(), *port);
+        }
+    }
+    print_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog_keeps_offsets() {
+        let mut backlog = Backlog { buf: VecDeque::new(), start_offset: 0 };
+        let start = REPL_OFFSET.load(Ordering::SeqCst);
+        let frame = Frame::Array(vec![Frame::BulkString(b"DEL".to_vec()), Frame::BulkString(b"k".to_vec())]);
+        append_to_backlog(&mut backlog, &frame);
+        // *2\r\n$3\r\nDEL\r\n$1\r\nk\r\n
+        assert_eq!(backlog.buf.len(), 20);
+        assert!(REPL_OFFSET.load(Ordering::SeqCst) >= start + 20);
+    }
+
+    fn request(args: &[&str]) -> Frame {
+        Frame::Array(args.iter().map(|a| Frame::BulkString(a.as_bytes().to_vec())).collect())
+    }
+
+    #[test]
+    fn test_write_commands() {
+        assert!(is_write_command("JSET", &request(&["JSET", "k", "a", "1"])));
+        assert!(!is_write_command("get", &request(&["get", "k"])));
+        assert!(is_write_command("jindex", &request(&["jindex", "CREATE", "idx"])));
+        assert!(is_write_command("jschema", &request(&["jschema", "del", "users"])));
+        assert!(!is_write_command("jindex", &request(&["jindex", "list"])));
+        assert!(!is_write_command("jschema", &request(&["jschema", "validate", "user:1"])));
+    }
+
+    #[test]
+    fn test_write_stripes() {
+        assert_eq!(write_stripes(&[]).len(), WRITE_LOCK_STRIPES);
+        assert_eq!(write_stripes(&["user:1", "user:1"]), write_stripes(&["user:1"]));
+        let stripes = write_stripes(&["user:3", "user:2", "user:1"]);
+        assert!(stripes.windows(2).all(|w| w[0] < w[1]));
+    }
+}
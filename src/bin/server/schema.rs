@@ -0,0 +1,463 @@
+//! JSON Schemas attached to key patterns with JSCHEMA SET, every JSON write to a matching key is
+//! validated before it is stored.
+//!
+//! A subset of draft 2020-12 is supported: type, enum, const, the numeric, string, array and
+//! object assertions, the allOf/anyOf/oneOf/not/if combinators and `$ref` to the same document.
+//! Annotations like title or format are ignored.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use glob::Pattern;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::command::*;
+use crate::db;
+use crate::json::json_equal;
+use crate::printer::*;
+
+/// `$ref` chains deeper than this are reported as an error instead of recursing forever
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaDef {
+    pub name: String,
+    /// glob pattern of the keys the schema applies to, e.g `user:*`
+    pub pattern: String,
+    /// the schema as JSON text
+    pub schema: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ValidationError {
+    schema_path: String,
+    instance_path: String,
+    message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = if self.instance_path.is_empty() { "the root" } else { self.instance_path.as_str() };
+        write!(f, "{} at {}: {}", self.schema_path, at, self.message)
+    }
+}
+
+struct JsonSchema {
+    def: SchemaDef,
+    pattern: Pattern,
+    schema: Value,
+    /// compiled `pattern` and `patternProperties` regexes, by source
+    regexes: HashMap<String, Regex>,
+}
+
+fn collect_regexes(schema: &Value, regexes: &mut HashMap<String, Regex>) -> Result<(), String> {
+    match schema {
+        Value::Object(map) => {
+            let mut sources: Vec<&str> = vec![];
+            if let Some(Value::String(p)) = map.get("pattern") {
+                sources.push(p);
+            }
+            if let Some(Value::Object(properties)) = map.get("patternProperties") {
+                sources.extend(properties.keys().map(|k| k.as_str()));
+            }
+            for source in sources {
+                let regex = Regex::new(source).map_err(|_| format!("ERR invalid regex '{}' in schema", source))?;
+                regexes.insert(source.to_owned(), regex);
+            }
+            map.values().try_for_each(|v| collect_regexes(v, regexes))
+        }
+        Value::Array(items) => items.iter().try_for_each(|v| collect_regexes(v, regexes)),
+        _ => Ok(())
+    }
+}
+
+impl JsonSchema {
+    fn new(def: SchemaDef) -> Result<JsonSchema, String> {
+        let pattern = Pattern::new(&def.pattern).map_err(|e| format!("ERR invalid key pattern: {}", e))?;
+        let schema: Value = serde_json::from_str(&def.schema).map_err(|_| "ERR invalid json".to_owned())?;
+        if !schema.is_object() && !schema.is_boolean() {
+            return Err("ERR a schema must be an object or a boolean".to_owned());
+        }
+        let mut regexes = HashMap::new();
+        collect_regexes(&schema, &mut regexes)?;
+        Ok(JsonSchema { def, pattern, schema, regexes })
+    }
+
+    fn validate(&self, doc: &Value) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        Validator { root: &self.schema, regexes: &self.regexes }
+            .check(&self.schema, doc, "#", "", 0, &mut errors);
+        errors
+    }
+}
+
+fn type_matches(name: &str, value: &Value) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().map_or(false, |f| f.fract() == 0.0),
+        _ => false
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    regexes: &'a HashMap<String, Regex>,
+}
+
+impl<'a> Validator<'a> {
+    fn is_valid(&self, schema: &Value, instance: &Value, depth: usize) -> bool {
+        let mut errors = vec![];
+        self.check(schema, instance, "#", "", depth, &mut errors);
+        errors.is_empty()
+    }
+
+    fn check(&self, schema: &Value, instance: &Value, schema_path: &str, instance_path: &str, depth: usize, errors: &mut Vec<ValidationError>) {
+        let at = |keyword: &str, message: String| ValidationError {
+            schema_path: format!("{}/{}", schema_path, keyword),
+            instance_path: instance_path.to_owned(),
+            message,
+        };
+        let map = match schema {
+            Value::Bool(true) => { return; }
+            Value::Object(t) => t,
+            _ => {
+                errors.push(ValidationError {
+                    schema_path: schema_path.to_owned(),
+                    instance_path: instance_path.to_owned(),
+                    message: "no value is allowed here".to_owned(),
+                });
+                return;
+            }
+        };
+        if depth > MAX_DEPTH {
+            errors.push(at("$ref", "schema is nested too deep".to_owned()));
+            return;
+        }
+
+        if let Some(Value::String(reference)) = map.get("$ref") {
+            let target = if reference.starts_with('#') { self.root.pointer(&reference[1..]) } else { None };
+            match target {
+                Some(target) => self.check(target, instance, reference, instance_path, depth + 1, errors),
+                None => errors.push(at("$ref", format!("unresolvable reference '{}'", reference))),
+            }
+        }
+
+        match map.get("type") {
+            Some(Value::String(t)) if !type_matches(t, instance) => errors.push(at("type", format!("expected {}", t))),
+            Some(Value::Array(types)) if !types.iter().any(|t| t.as_str().map_or(false, |t| type_matches(t, instance))) => {
+                errors.push(at("type", format!("expected one of {}", Value::Array(types.clone()))))
+            }
+            _ => {}
+        }
+        if let Some(Value::Array(values)) = map.get("enum") {
+            if !values.iter().any(|v| json_equal(v, instance)) {
+                errors.push(at("enum", "value is not one of the allowed values".to_owned()));
+            }
+        }
+        if let Some(expected) = map.get("const") {
+            if !json_equal(expected, instance) {
+                errors.push(at("const", format!("expected {}", expected)));
+            }
+        }
+
+        if let Some(n) = instance.as_f64() {
+            let limit = |keyword: &str| map.get(keyword).and_then(|v| v.as_f64());
+            if let Some(min) = limit("minimum").filter(|min| n < *min) {
+                errors.push(at("minimum", format!("{} is less than {}", instance, min)));
+            }
+            if let Some(max) = limit("maximum").filter(|max| n > *max) {
+                errors.push(at("maximum", format!("{} is greater than {}", instance, max)));
+            }
+            if let Some(min) = limit("exclusiveMinimum").filter(|min| n <= *min) {
+                errors.push(at("exclusiveMinimum", format!("{} is not greater than {}", instance, min)));
+            }
+            if let Some(max) = limit("exclusiveMaximum").filter(|max| n >= *max) {
+                errors.push(at("exclusiveMaximum", format!("{} is not less than {}", instance, max)));
+            }
+            if let Some(m) = limit("multipleOf").filter(|m| *m > 0.0 && (n / m).fract() != 0.0) {
+                errors.push(at("multipleOf", format!("{} is not a multiple of {}", instance, m)));
+            }
+        }
+
+        if let Value::String(s) = instance {
+            let len = s.chars().count() as u64;
+            if let Some(min) = map.get("minLength").and_then(|v| v.as_u64()).filter(|min| len < *min) {
+                errors.push(at("minLength", format!("shorter than {} characters", min)));
+            }
+            if let Some(max) = map.get("maxLength").and_then(|v| v.as_u64()).filter(|max| len > *max) {
+                errors.push(at("maxLength", format!("longer than {} characters", max)));
+            }
+            if let Some(Value::String(p)) = map.get("pattern") {
+                if !self.regexes.get(p).map_or(true, |r| r.is_match(s)) {
+                    errors.push(at("pattern", format!("does not match '{}'", p)));
+                }
+            }
+        }
+
+        if let Value::Array(items) = instance {
+            let len = items.len() as u64;
+            if let Some(min) = map.get("minItems").and_then(|v| v.as_u64()).filter(|min| len < *min) {
+                errors.push(at("minItems", format!("fewer than {} items", min)));
+            }
+            if let Some(max) = map.get("maxItems").and_then(|v| v.as_u64()).filter(|max| len > *max) {
+                errors.push(at("maxItems", format!("more than {} items", max)));
+            }
+            if map.get("uniqueItems") == Some(&Value::Bool(true)) {
+                let duplicate = items.iter().enumerate().any(|(i, a)| items[i + 1..].iter().any(|b| json_equal(a, b)));
+                if duplicate {
+                    errors.push(at("uniqueItems", "items are not unique".to_owned()));
+                }
+            }
+            if let Some(contains) = map.get("contains") {
+                if !items.iter().any(|item| self.is_valid(contains, item, depth + 1)) {
+                    errors.push(at("contains", "no item matches".to_owned()));
+                }
+            }
+            let prefix = match map.get("prefixItems") {
+                Some(Value::Array(t)) => t.as_slice(),
+                _ => &[],
+            };
+            for (i, item) in items.iter().enumerate() {
+                let item_path = format!("{}/{}", instance_path, i);
+                if i < prefix.len() {
+                    self.check(&prefix[i], item, &format!("{}/prefixItems/{}", schema_path, i), &item_path, depth + 1, errors);
+                } else if let Some(item_schema) = map.get("items") {
+                    self.check(item_schema, item, &format!("{}/items", schema_path), &item_path, depth + 1, errors);
+                }
+            }
+        }
+
+        if let Value::Object(members) = instance {
+            let len = members.len() as u64;
+            if let Some(min) = map.get("minProperties").and_then(|v| v.as_u64()).filter(|min| len < *min) {
+                errors.push(at("minProperties", format!("fewer than {} properties", min)));
+            }
+            if let Some(max) = map.get("maxProperties").and_then(|v| v.as_u64()).filter(|max| len > *max) {
+                errors.push(at("maxProperties", format!("more than {} properties", max)));
+            }
+            if let Some(Value::Array(required)) = map.get("required") {
+                for name in required.iter().filter_map(|r| r.as_str()).filter(|r| !members.contains_key(*r)) {
+                    errors.push(at("required", format!("missing property '{}'", name)));
+                }
+            }
+            let properties = map.get("properties").and_then(|p| p.as_object());
+            let pattern_properties = map.get("patternProperties").and_then(|p| p.as_object());
+            for (name, value) in members {
+                let member_path = format!("{}/{}", instance_path, name.replace('~', "~0").replace('/', "~1"));
+                let mut evaluated = false;
+                if let Some(property_schema) = properties.and_then(|p| p.get(name)) {
+                    self.check(property_schema, value, &format!("{}/properties/{}", schema_path, name), &member_path, depth + 1, errors);
+                    evaluated = true;
+                }
+                for (source, property_schema) in pattern_properties.into_iter().flatten() {
+                    if self.regexes.get(source).map_or(false, |r| r.is_match(name)) {
+                        self.check(property_schema, value, &format!("{}/patternProperties/{}", schema_path, source), &member_path, depth + 1, errors);
+                        evaluated = true;
+                    }
+                }
+                if let (false, Some(additional)) = (evaluated, map.get("additionalProperties")) {
+                    self.check(additional, value, &format!("{}/additionalProperties", schema_path), &member_path, depth + 1, errors);
+                }
+                if let Some(names) = map.get("propertyNames") {
+                    self.check(names, &Value::String(name.to_owned()), &format!("{}/propertyNames", schema_path), &member_path, depth + 1, errors);
+                }
+            }
+        }
+
+        self.check_combinators(map, instance, schema_path, instance_path, depth, errors);
+    }
+
+    fn check_combinators(&self, map: &serde_json::Map<String, Value>, instance: &Value, schema_path: &str, instance_path: &str, depth: usize, errors: &mut Vec<ValidationError>) {
+        let at = |keyword: &str, message: String| ValidationError {
+            schema_path: format!("{}/{}", schema_path, keyword),
+            instance_path: instance_path.to_owned(),
+            message,
+        };
+        let subschemas = |keyword: &str| match map.get(keyword) {
+            Some(Value::Array(t)) => t.as_slice(),
+            _ => &[],
+        };
+        let valid_count = |keyword: &str| subschemas(keyword).iter().filter(|s| self.is_valid(s, instance, depth + 1)).count();
+
+        let all_of = subschemas("allOf");
+        if valid_count("allOf") < all_of.len() {
+            errors.push(at("allOf", "does not match every schema".to_owned()));
+        }
+        if !subschemas("anyOf").is_empty() && valid_count("anyOf") == 0 {
+            errors.push(at("anyOf", "does not match any schema".to_owned()));
+        }
+        if !subschemas("oneOf").is_empty() && valid_count("oneOf") != 1 {
+            errors.push(at("oneOf", "does not match exactly one schema".to_owned()));
+        }
+        if let Some(not) = map.get("not") {
+            if self.is_valid(not, instance, depth + 1) {
+                errors.push(at("not", "matches a schema it must not match".to_owned()));
+            }
+        }
+        if let Some(condition) = map.get("if") {
+            let (keyword, branch) = if self.is_valid(condition, instance, depth + 1) { ("then", map.get("then")) } else { ("else", map.get("else")) };
+            if let Some(branch) = branch {
+                if !self.is_valid(branch, instance, depth + 1) {
+                    errors.push(at(keyword, format!("does not match the '{}' schema", keyword)));
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref SCHEMAS : RwLock<BTreeMap<String, JsonSchema>> = RwLock::new(BTreeMap::new());
+}
+
+/// Checks `doc` against every schema covering `key`, the error lists the failing schema paths.
+///
+/// Called while the JSON store entry is held, so the schemas lock must never be held while
+/// touching the JSON store.
+pub fn validate(key: &str, doc: &Value) -> Result<(), String> {
+    let schemas = SCHEMAS.read().unwrap();
+    for schema in schemas.values().filter(|s| s.pattern.matches(key)) {
+        let errors = schema.validate(doc);
+        if !errors.is_empty() {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            return Err(format!("ERR document does not match schema '{}': {}", schema.def.name, errors.join("; ")));
+        }
+    }
+    Ok(())
+}
+
+/// Whether writes to `key` are validated, so callers can skip building the whole document
+pub fn covers(key: &str) -> bool {
+    SCHEMAS.read().unwrap().values().any(|s| s.pattern.matches(key))
+}
+
+pub fn definitions() -> Vec<SchemaDef> {
+    SCHEMAS.read().unwrap().values().map(|s| s.def.clone()).collect()
+}
+
+/// Replaces every schema with the ones of `defs`
+pub fn replace_all(defs: Vec<SchemaDef>) {
+    let mut schemas = BTreeMap::new();
+    for def in defs {
+        let name = def.name.to_owned();
+        match JsonSchema::new(def) {
+            Ok(schema) => { schemas.insert(name, schema); }
+            Err(e) => warn!("Could not restore schema {}: {}", name, e),
+        }
+    }
+    *SCHEMAS.write().unwrap() = schemas;
+}
+
+/// Registers a schema, replacing the one with the same name. Documents already stored are not
+/// checked, only the writes made from now on.
+pub fn set_schema(def: SchemaDef) -> Result<(), String> {
+    let schema = JsonSchema::new(def)?;
+    SCHEMAS.write().unwrap().insert(schema.def.name.to_owned(), schema);
+    Ok(())
+}
+
+pub fn jschema_set(cmd: &JSchemaSetCmd) -> String {
+    return match set_schema(cmd.arg_def.clone()) {
+        Ok(_) => print_ok(),
+        Err(e) => print_err(&e)
+    };
+}
+
+pub fn jschema_del(cmd: &JSchemaDelCmd) -> String {
+    return match SCHEMAS.write().unwrap().remove(&cmd.arg_name) {
+        Some(_) => print_ok(),
+        None => print_err("ERR Unknown schema name")
+    };
+}
+
+/// The key pattern and the schema
+pub fn jschema_get(cmd: &JSchemaGetCmd) -> String {
+    return match SCHEMAS.read().unwrap().get(&cmd.arg_name) {
+        Some(s) => print_arr(vec![s.def.pattern.to_owned(), s.def.schema.to_owned()]),
+        None => print_err("ERR Unknown schema name")
+    };
+}
+
+pub fn jschema_list(_cmd: &JSchemaListCmd) -> String {
+    print_arr(SCHEMAS.read().unwrap().keys().cloned().collect())
+}
+
+/// Checks a stored document against the schemas covering its key
+pub fn jschema_validate(cmd: &JSchemaValidateCmd) -> String {
+    let doc = match db::json_document(&cmd.arg_key) {
+        Some(t) => t,
+        None => { return print_err("ERR key not found"); }
+    };
+    return match validate(&cmd.arg_key, &doc) {
+        Ok(_) => print_ok(),
+        Err(e) => print_err(&e)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_schema() -> JsonSchema {
+        let schema = json!({
+            "type": "object",
+            "required": ["email", "age"],
+            "properties": {
+                "email": {"type": "string", "pattern": "^[^@]+@[^@]+$"},
+                "age": {"type": "integer", "minimum": 18},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}, "uniqueItems": true},
+            },
+            "additionalProperties": false,
+            "$defs": {"tag": {"type": "string", "enum": ["admin", "staff"]}},
+        });
+        JsonSchema::new(SchemaDef {
+            name: "users".to_owned(),
+            pattern: "user:*".to_owned(),
+            schema: schema.to_string(),
+        }).unwrap()
+    }
+
+    fn failing_paths(schema: &JsonSchema, doc: Value) -> Vec<String> {
+        schema.validate(&doc).into_iter().map(|e| format!("{} {}", e.schema_path, e.instance_path)).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = user_schema();
+        assert!(failing_paths(&schema, json!({"email": "ama@acme.com", "age": 25, "tags": ["admin"]})).is_empty());
+        assert_eq!(failing_paths(&schema, json!({"email": "ama", "age": 17.5})), vec![
+            "#/properties/age/type /age",
+            "#/properties/age/minimum /age",
+            "#/properties/email/pattern /email",
+        ]);
+        assert_eq!(failing_paths(&schema, json!({"age": 30, "tags": ["root", "root"], "x": 1})), vec![
+            "#/required ",
+            "#/properties/tags/uniqueItems /tags",
+            "#/$defs/tag/enum /tags/0",
+            "#/$defs/tag/enum /tags/1",
+            "#/additionalProperties /x",
+        ]);
+        assert_eq!(failing_paths(&schema, json!([])), vec!["#/type "]);
+    }
+
+    #[test]
+    fn test_combinators() {
+        let schema = JsonSchema::new(SchemaDef {
+            name: "ids".to_owned(),
+            pattern: "*".to_owned(),
+            schema: json!({"oneOf": [{"type": "string"}, {"type": "integer", "exclusiveMinimum": 0}], "not": {"const": "root"}}).to_string(),
+        }).unwrap();
+        assert!(schema.validate(&json!("a1")).is_empty());
+        assert!(schema.validate(&json!(7)).is_empty());
+        assert_eq!(schema.validate(&json!(0))[0].schema_path, "#/oneOf");
+        assert_eq!(schema.validate(&json!("root"))[0].schema_path, "#/not");
+        assert!(JsonSchema::new(SchemaDef { name: "x".to_owned(), pattern: "*".to_owned(), schema: "{\"pattern\": \"(\"}".to_owned() }).is_err());
+    }
+}
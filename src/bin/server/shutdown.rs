@@ -0,0 +1,67 @@
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+
+use crate::command::*;
+use crate::printer::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownMode {
+    /// Saves the database before exiting, used for signals and a bare SHUTDOWN
+    Default,
+    Save,
+    NoSave,
+}
+
+lazy_static! {
+    static ref SHUTDOWN_CHANNEL : (watch::Sender<Option<ShutdownMode>>, watch::Receiver<Option<ShutdownMode>>) = watch::channel(None);
+}
+
+pub fn request_shutdown(mode: ShutdownMode) {
+    info!("Shutdown requested: {:?}", mode);
+    let _ = SHUTDOWN_CHANNEL.0.broadcast(Some(mode));
+}
+
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN_CHANNEL.1.borrow().is_some()
+}
+
+/// Resolves once a shutdown has been requested
+pub async fn wait_for_shutdown() -> ShutdownMode {
+    let mut receiver = SHUTDOWN_CHANNEL.1.clone();
+    while let Some(mode) = receiver.recv().await {
+        if let Some(mode) = mode {
+            return mode;
+        }
+    }
+    ShutdownMode::Default
+}
+
+/// Requests a shutdown on Ctrl-C and, on unix, on SIGTERM
+pub fn listen_for_signals() {
+    tokio::spawn(async {
+        match tokio::signal::ctrl_c().await {
+            Ok(_) => request_shutdown(ShutdownMode::Default),
+            Err(e) => error!("Could not listen for Ctrl-C: {}", e),
+        };
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Could not listen for SIGTERM: {}", e);
+                return;
+            }
+        };
+        if let Some(_) = terminate.recv().await {
+            request_shutdown(ShutdownMode::Default);
+        }
+    });
+}
+
+pub fn shutdown(cmd: &ShutdownCmd) -> String {
+    request_shutdown(cmd.arg_mode);
+    print_ok()
+}
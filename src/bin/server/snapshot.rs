@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+use dashmap::DashMap;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::compact::JsonDoc;
+use crate::db::ESValue;
+use crate::geo::GeoPoint2D;
+use crate::index::IndexDef;
+use crate::schema::SchemaDef;
+
+/// Marks a dump written as a stream of records, older dumps are a single msgpack `Database`
+pub const SNAPSHOT_MAGIC: &[u8] = b"ESDB\x02";
+
+/// A snapshot entry borrowed from the live maps while it is being written
+#[derive(Serialize)]
+pub enum RecordRef<'a> {
+    KV(&'a str, &'a ESValue),
+    /// compact documents are written as plain JSON, the dump format does not depend on the encoding
+    Json(&'a str, &'a JsonDoc),
+    Geo(&'a str, &'a HashSet<GeoPoint2D>),
+    End,
+    /// added after `End` so older snapshots keep their variant numbers
+    Index(&'a IndexDef),
+    Schema(&'a SchemaDef),
+}
+
+/// A snapshot entry as read back from disk, mirrors `RecordRef`
+#[derive(Deserialize)]
+pub enum Record {
+    KV(String, ESValue),
+    Json(String, Value),
+    Geo(String, HashSet<GeoPoint2D>),
+    End,
+    Index(IndexDef),
+    Schema(SchemaDef),
+}
+
+pub fn write_header<W: Write>(writer: &mut W) -> Result<(), String> {
+    writer.write_all(SNAPSHOT_MAGIC).map_err(|e| format!("Error writing snapshot: {}", e))
+}
+
+pub fn write_record<W: Write>(writer: &mut W, record: &RecordRef) -> Result<(), String> {
+    rmp_serde::encode::write(writer, record).map_err(|e| format!("Error encoding snapshot: {}", e))
+}
+
+/// Streams every entry of `map` to `writer` one shard at a time.
+///
+/// Only the keys of the current shard are copied, each value is encoded while holding the
+/// read lock of its own entry, so writers are never blocked for more than one entry and the
+/// dataset is never duplicated in memory. Entries changed during the save are written with
+/// whichever value they hold when reached, entries removed in the meantime are skipped.
+pub fn write_map<W, V, F>(writer: &mut W, map: &DashMap<String, V>, to_record: F) -> Result<usize, String>
+    where W: Write,
+          F: for<'a> Fn(&'a str, &'a V) -> RecordRef<'a> {
+    let mut count = 0;
+    for shard in map.shards() {
+        let keys: Vec<String> = shard.read().keys().cloned().collect();
+        for key in keys {
+            if let Some(entry) = map.get(&key) {
+                write_record(writer, &to_record(entry.key(), entry.value()))?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Wraps the dump file and logs loading progress every 10 percent of `total_bytes`
+pub struct ProgressReader<R> {
+    inner: R,
+    total_bytes: u64,
+    bytes_read: u64,
+    last_reported: u64,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, total_bytes: u64) -> Self {
+        ProgressReader { inner, total_bytes, bytes_read: 0, last_reported: 0 }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n as u64;
+        if self.total_bytes > 0 {
+            let percent = self.bytes_read * 100 / self.total_bytes;
+            if percent >= self.last_reported + 10 {
+                self.last_reported = percent - percent % 10;
+                info!("Loading DB: {}% ({}/{} bytes)", self.last_reported, self.bytes_read, self.total_bytes);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Decodes the records following the header until the `End` record
+pub fn read_records<R: Read, F: FnMut(Record)>(reader: R, mut on_record: F) -> Result<(), String> {
+    let mut deserializer = rmp_serde::Deserializer::new(reader);
+    loop {
+        let record: Record = match Deserialize::deserialize(&mut deserializer) {
+            Ok(t) => t,
+            Err(e) => {
+                return Err(format!("Error decoding snapshot: {}", e));
+            }
+        };
+        if let Record::End = record {
+            return Ok(());
+        }
+        on_record(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact::CompactValue;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let kv: DashMap<String, ESValue> = DashMap::new();
+        kv.insert("name".to_owned(), ESValue::String("escanor".to_owned()));
+        kv.insert("count".to_owned(), ESValue::Int(7));
+        let json: DashMap<String, JsonDoc> = DashMap::new();
+        let user = json!({"name" : "escanor", "todos" : [1, 2]});
+        json.insert("user".to_owned(), JsonDoc::Compact(CompactValue::from_value(&user)));
+
+        let mut buf: Vec<u8> = vec![];
+        write_header(&mut buf).unwrap();
+        assert_eq!(write_map(&mut buf, &kv, |k, v| RecordRef::KV(k, v)).unwrap(), 2);
+        assert_eq!(write_map(&mut buf, &json, |k, v| RecordRef::Json(k, v)).unwrap(), 1);
+        write_record(&mut buf, &RecordRef::End).unwrap();
+
+        assert!(buf.starts_with(SNAPSHOT_MAGIC));
+        let mut kv_count = 0;
+        let mut json_value = Value::Null;
+        read_records(&buf[SNAPSHOT_MAGIC.len()..], |record| {
+            match record {
+                Record::KV(_, _) => kv_count += 1,
+                Record::Json(_, v) => json_value = v,
+                Record::Geo(_, _) => {}
+                Record::Index(_) | Record::Schema(_) | Record::End => {}
+            }
+        }).unwrap();
+        assert_eq!(kv_count, 2);
+        assert_eq!(json_value["todos"][1], json!(2));
+    }
+}
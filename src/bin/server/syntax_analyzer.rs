@@ -1,8 +1,12 @@
 use crate::command::*;
-use crate::{error, util, unit_conv};
+use crate::{error, util, unit_conv, json};
 use serde_json::{Value};
 
 use crate::db::ESValue;
+use crate::shutdown::ShutdownMode;
+use crate::index::{FieldDef, FieldType, IndexDef};
+use crate::schema::SchemaDef;
+use crate::aggregate::{Pipeline, Reducer, ReduceFn, Source, Stage};
 
 
 pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, error::SyntaxError> {
@@ -26,16 +30,65 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
     else if cmd == "randomkey" {
         return Ok(Box::new(RandomKeyCmd));
     }
+    else if cmd == "save" {
+        return Ok(Box::new(SaveCmd));
+    }
     else if cmd == "bgsave" {
         return Ok(Box::new(BGSaveCmd));
     } else if cmd == "flushdb" {
         return Ok(Box::new(FlushDBCmd));
-    }else if cmd == "auth" {
-        let arg_password = itr.next().unwrap_or(&empty_string);
-        if arg_password.is_empty() { return Err(error::SyntaxError); }
-        return Ok(Box::new(AuthCmd {
-            arg_password: arg_password.to_owned()
+    } else if cmd == "shutdown" {
+        let arg_mode = itr.next().unwrap_or(&empty_string).to_lowercase();
+        let arg_mode = match arg_mode.as_str() {
+            "" => ShutdownMode::Default,
+            "save" => ShutdownMode::Save,
+            "nosave" => ShutdownMode::NoSave,
+            _ => { return Err(error::SyntaxError); }
+        };
+        return Ok(Box::new(ShutdownCmd {
+            arg_mode
         }));
+    }else if cmd == "auth" {
+        let arg_first = itr.next().unwrap_or(&empty_string);
+        if arg_first.is_empty() { return Err(error::SyntaxError); }
+        // AUTH <password> authenticates the default user, AUTH <user> <password> an ACL user
+        return match itr.next() {
+            None => Ok(Box::new(AuthCmd {
+                arg_user: None,
+                arg_password: arg_first.to_owned()
+            })),
+            Some(arg_password) => Ok(Box::new(AuthCmd {
+                arg_user: Some(arg_first.to_owned()),
+                arg_password: arg_password.to_owned()
+            }))
+        };
+    } else if cmd == "acl" {
+        let sub_cmd = itr.next().unwrap_or(&empty_string).to_lowercase();
+        if sub_cmd == "setuser" {
+            let arg_user = itr.next().unwrap_or(&empty_string);
+            if arg_user.is_empty() { return Err(error::SyntaxError); }
+            return Ok(Box::new(AclSetUserCmd {
+                arg_user: arg_user.to_owned(),
+                arg_rules: itr.map(|r| r.to_owned()).collect()
+            }));
+        } else if sub_cmd == "getuser" {
+            let arg_user = itr.next().unwrap_or(&empty_string);
+            if arg_user.is_empty() { return Err(error::SyntaxError); }
+            return Ok(Box::new(AclGetUserCmd {
+                arg_user: arg_user.to_owned()
+            }));
+        } else if sub_cmd == "deluser" {
+            let arg_users: Vec<String> = itr.map(|u| u.to_owned()).collect();
+            if arg_users.is_empty() { return Err(error::SyntaxError); }
+            return Ok(Box::new(AclDelUserCmd {
+                arg_users
+            }));
+        } else if sub_cmd == "list" {
+            return Ok(Box::new(AclListCmd));
+        } else if sub_cmd == "whoami" {
+            return Ok(Box::new(AclWhoAmICmd));
+        }
+        return Err(error::SyntaxError);
     }
 
     else if cmd == "set" {
@@ -105,6 +158,35 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
         return Ok(Box::new(PersistCmd {
             arg_key: arg_key.to_owned()
         }));
+    }else if cmd == "dump" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(DumpCmd {
+            arg_key: arg_key.to_owned()
+        }));
+    }else if cmd == "restore" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_ttl = match itr.next().unwrap_or(&empty_string).parse::<u64>() {
+            Ok(t) => t,
+            Err(_) => { return Err(error::SyntaxError); }
+        };
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_replace = match itr.next() {
+            None => false,
+            Some(t) if t.to_lowercase() == "replace" => true,
+            Some(_) => { return Err(error::SyntaxError); }
+        };
+        return Ok(Box::new(RestoreCmd {
+            arg_key: arg_key.to_owned(),
+            arg_ttl,
+            arg_value: arg_value.to_owned(),
+            arg_replace,
+        }));
     }else if cmd == "expire" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
@@ -176,6 +258,120 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
         }));
     } else if cmd == "info" {
         return Ok(Box::new(InfoCmd));
+    } else if cmd == "config" {
+        let sub_cmd = itr.next().unwrap_or(&empty_string).to_lowercase();
+        if sub_cmd == "get" {
+            let arg_pattern = itr.next().unwrap_or(&empty_string);
+            if arg_pattern.is_empty() { return Err(error::SyntaxError); }
+            return Ok(Box::new(ConfigGetCmd {
+                arg_pattern: arg_pattern.to_owned()
+            }));
+        } else if sub_cmd == "set" {
+            let arg_param = itr.next().unwrap_or(&empty_string);
+            if arg_param.is_empty() { return Err(error::SyntaxError); }
+            let arg_value = match itr.next() {
+                Some(t) => t,
+                None => { return Err(error::SyntaxError); }
+            };
+            return Ok(Box::new(ConfigSetCmd {
+                arg_param: arg_param.to_owned(),
+                arg_value: arg_value.to_owned(),
+            }));
+        } else if sub_cmd == "resetstat" {
+            return Ok(Box::new(ConfigResetStatCmd));
+        } else if sub_cmd == "rewrite" {
+            return Ok(Box::new(ConfigRewriteCmd));
+        }
+        return Err(error::SyntaxError);
+    } else if cmd == "replicaof" || cmd == "slaveof" {
+        let arg_host = itr.next().unwrap_or(&empty_string);
+        let arg_port = itr.next().unwrap_or(&empty_string);
+        if arg_host.is_empty() || arg_port.is_empty() { return Err(error::SyntaxError); }
+        if arg_host.to_lowercase() == "no" && arg_port.to_lowercase() == "one" {
+            return Ok(Box::new(ReplicaOfCmd {
+                arg_master: None
+            }));
+        }
+        return match arg_port.parse::<u16>() {
+            Ok(port) => Ok(Box::new(ReplicaOfCmd {
+                arg_master: Some((arg_host.to_owned(), port))
+            })),
+            Err(_) => Err(error::SyntaxError)
+        };
+    } else if cmd == "client" {
+        let sub_cmd = itr.next().unwrap_or(&empty_string).to_lowercase();
+        if sub_cmd == "list" {
+            return Ok(Box::new(ClientListCmd));
+        } else if sub_cmd == "id" {
+            return Ok(Box::new(ClientIdCmd));
+        } else if sub_cmd == "info" {
+            return Ok(Box::new(ClientInfoCmd));
+        } else if sub_cmd == "getname" {
+            return Ok(Box::new(ClientGetNameCmd));
+        } else if sub_cmd == "setname" {
+            let arg_name = match itr.next() {
+                Some(t) => t,
+                None => { return Err(error::SyntaxError); }
+            };
+            return Ok(Box::new(ClientSetNameCmd {
+                arg_name: arg_name.to_owned()
+            }));
+        } else if sub_cmd == "pause" {
+            let arg_timeout = itr.next().unwrap_or(&empty_string);
+            return match arg_timeout.parse::<u64>() {
+                Ok(t) => {
+                    Ok(Box::new(ClientPauseCmd {
+                        arg_timeout: t
+                    }))
+                }
+                Err(_) => {
+                    Err(error::SyntaxError)
+                }
+            };
+        } else if sub_cmd == "kill" {
+            let mut items_after_sub_cmd: Vec<&String> = vec![];
+
+            while let Some(i) = itr.next() {
+                items_after_sub_cmd.push(i);
+            }
+            if items_after_sub_cmd.is_empty() {
+                return Err(error::SyntaxError);
+            }
+            // CLIENT KILL addr:port
+            if items_after_sub_cmd.len() == 1 {
+                return Ok(Box::new(ClientKillCmd {
+                    arg_id: None,
+                    arg_addr: Some(items_after_sub_cmd[0].to_owned()),
+                    legacy_syntax: true,
+                }));
+            }
+            if items_after_sub_cmd.len() % 2 != 0 {
+                return Err(error::SyntaxError);
+            }
+            // CLIENT KILL [ID id] [ADDR addr:port]
+            let mut arg_id = None;
+            let mut arg_addr = None;
+            let mut filter_chunks = items_after_sub_cmd.chunks_exact(2);
+            while let Some(c) = filter_chunks.next() {
+                let filter = c[0].to_lowercase();
+                if filter == "id" {
+                    arg_id = match c[1].parse::<u64>() {
+                        Ok(t) => Some(t),
+                        Err(_) => { return Err(error::SyntaxError); }
+                    };
+                } else if filter == "addr" {
+                    arg_addr = Some(c[1].to_owned());
+                } else {
+                    return Err(error::SyntaxError);
+                }
+            }
+            return Ok(Box::new(ClientKillCmd {
+                arg_id,
+                arg_addr,
+                legacy_syntax: false,
+            }));
+        }
+        return Err(error::SyntaxError);
     }
     // GEOADD [key] long lat tag [long lat tag...]
     else if cmd == "geoadd" {
@@ -429,10 +625,15 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
         while let Some(i) = itr.next() {
             items_after_key.push(i);
         }
-        if items_after_key.is_empty() {
-            return Err(error::SyntaxError);
-        }
+        // JSET key [JSON|STRING] path value ..., a mode is told apart from a path by the odd count
+        let mut mode = String::new();
         if items_after_key.len() % 2 != 0 {
+            mode = items_after_key.remove(0).to_lowercase();
+            if mode != "json" && mode != "string" {
+                return Err(error::SyntaxError);
+            }
+        }
+        if items_after_key.is_empty() {
             return Err(error::SyntaxError);
         }
         //split items_after_key in arrays of [3, &String]
@@ -444,17 +645,15 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             let dot_path = c[0];
             let value_string = c[1];
 
-            if util::is_numeric(value_string) {
-                let v = value_string.parse::<f64>().unwrap();
-                if v.fract() == 0.0 {
-                    let vs = v as i64;
-                    items.push((dot_path.to_owned(), json!(vs)));
-                } else {
-                    items.push((dot_path.to_owned(), json!(v)));
-                }
+            let value = if mode == "json" {
+                // every value is a JSON literal: '"00501"', '{"a": 1}', null
+                serde_json::from_str::<Value>(value_string).map_err(|_| error::SyntaxError)?
+            } else if mode == "string" {
+                Value::String(value_string.to_owned())
             } else {
-                items.push((dot_path.to_owned(), Value::String(value_string.to_owned())));
-            }
+                json::guess_value(value_string)
+            };
+            items.push((dot_path.to_owned(), value));
         }
         return Ok(Box::new(JSetCmd {
             arg_key: arg_key.to_owned(),
@@ -473,11 +672,24 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
     } else if cmd == "jget" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
-        let arg_value = itr.next().unwrap_or(&empty_string);
+        // JGET key [INDENT s] [NEWLINE s] [SPACE s] [path ...], the options come before the paths
+        let mut args: Vec<&String> = itr.collect();
+        let mut arg_format = json::JsonFormat::default();
+        while args.len() > 1 {
+            let option = match args[0].to_lowercase().as_str() {
+                "indent" => &mut arg_format.indent,
+                "newline" => &mut arg_format.newline,
+                "space" => &mut arg_format.space,
+                _ => break
+            };
+            *option = args[1].to_owned();
+            args.drain(..2);
+        }
 
         return Ok(Box::new(JGetCmd {
             arg_key: arg_key.to_owned(),
-            arg_dot_path: if arg_value.is_empty() { None } else { Some(arg_value.to_owned()) },
+            arg_paths: args.into_iter().cloned().collect(),
+            arg_format,
         }));
     } else if cmd == "jpath" {
         let arg_key = itr.next().unwrap_or(&empty_string);
@@ -561,6 +773,358 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             arg_path: arg_path.to_owned(),
             arg_increment_value: incr_value,
         }));
+    } else if ["jarrappend", "jarrinsert", "jarrpop", "jarrlen", "jarrtrim", "jstrappend", "jstrlen",
+        "jobjkeys", "jobjlen", "jtype"].contains(&cmd.as_str()) {
+        // RedisJSON style: JARRAPPEND key $.todos '{"item": "milk"}', the path defaults to the root
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let args: Vec<&String> = itr.collect();
+        let arg_key = arg_key.to_owned();
+        let parse_i64 = |s: &String| s.parse::<i64>().map_err(|_| error::SyntaxError);
+        let values = |from: usize| args[from..].iter().map(|v| v.to_string()).collect::<Vec<String>>();
+
+        if cmd == "jarrappend" {
+            if args.len() < 2 { return Err(error::SyntaxError); }
+            return Ok(Box::new(JArrAppendCmd { arg_key, arg_path: args[0].to_owned(), arg_values: values(1) }));
+        } else if cmd == "jarrinsert" {
+            if args.len() < 3 { return Err(error::SyntaxError); }
+            let arg_index = parse_i64(args[1])?;
+            return Ok(Box::new(JArrInsertCmd { arg_key, arg_path: args[0].to_owned(), arg_index, arg_values: values(2) }));
+        } else if cmd == "jarrtrim" {
+            if args.len() != 3 { return Err(error::SyntaxError); }
+            let arg_start = parse_i64(args[1])?;
+            let arg_stop = parse_i64(args[2])?;
+            return Ok(Box::new(JArrTrimCmd { arg_key, arg_path: args[0].to_owned(), arg_start, arg_stop }));
+        } else if cmd == "jarrpop" {
+            if args.len() > 2 { return Err(error::SyntaxError); }
+            let arg_index = match args.get(1) {
+                Some(t) => parse_i64(t)?,
+                None => -1
+            };
+            let arg_path = args.first().map(|p| p.to_string()).unwrap_or_else(|| ".".to_owned());
+            return Ok(Box::new(JArrPopCmd { arg_key, arg_path, arg_index }));
+        } else if cmd == "jstrappend" {
+            let (arg_path, arg_value) = match args.as_slice() {
+                [value] => (".".to_owned(), value.to_string()),
+                [path, value] => (path.to_string(), value.to_string()),
+                _ => { return Err(error::SyntaxError); }
+            };
+            return Ok(Box::new(JStrAppendCmd { arg_key, arg_path, arg_value }));
+        }
+
+        if args.len() > 1 { return Err(error::SyntaxError); }
+        let arg_path = args.first().map(|p| p.to_string()).unwrap_or_else(|| ".".to_owned());
+        if cmd == "jarrlen" {
+            return Ok(Box::new(JArrLenCmd { arg_key, arg_path }));
+        } else if cmd == "jstrlen" {
+            return Ok(Box::new(JStrLenCmd { arg_key, arg_path }));
+        } else if cmd == "jobjkeys" {
+            return Ok(Box::new(JObjKeysCmd { arg_key, arg_path }));
+        } else if cmd == "jobjlen" {
+            return Ok(Box::new(JObjLenCmd { arg_key, arg_path }));
+        }
+        return Ok(Box::new(JTypeCmd { arg_key, arg_path }));
+    } else if cmd == "jschema" {
+        // JSCHEMA SET users user:* '{"type": "object", "required": ["email"]}'
+        let sub_cmd = itr.next().unwrap_or(&empty_string).to_lowercase();
+        if sub_cmd == "list" {
+            return Ok(Box::new(JSchemaListCmd));
+        }
+        let arg_name = itr.next().unwrap_or(&empty_string);
+        if arg_name.is_empty() { return Err(error::SyntaxError); }
+        if sub_cmd == "set" {
+            let arg_pattern = itr.next().unwrap_or(&empty_string);
+            if arg_pattern.is_empty() { return Err(error::SyntaxError); }
+            let arg_schema = itr.next().unwrap_or(&empty_string);
+            if arg_schema.is_empty() { return Err(error::SyntaxError); }
+            return Ok(Box::new(JSchemaSetCmd {
+                arg_def: SchemaDef {
+                    name: arg_name.to_owned(),
+                    pattern: arg_pattern.to_owned(),
+                    schema: arg_schema.to_owned(),
+                }
+            }));
+        } else if sub_cmd == "del" {
+            return Ok(Box::new(JSchemaDelCmd { arg_name: arg_name.to_owned() }));
+        } else if sub_cmd == "get" {
+            return Ok(Box::new(JSchemaGetCmd { arg_name: arg_name.to_owned() }));
+        } else if sub_cmd == "validate" {
+            return Ok(Box::new(JSchemaValidateCmd { arg_key: arg_name.to_owned() }));
+        }
+        return Err(error::SyntaxError);
+    } else if cmd == "jpatch" {
+        // JPATCH key '[{"op": "replace", "path": "/a", "value": 1}]' or JPATCH key MERGE '{"a": null}'
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let args: Vec<&String> = itr.collect();
+        let (arg_merge, arg_patch) = match args.as_slice() {
+            [patch] => (false, patch),
+            [mode, patch] if mode.to_lowercase() == "merge" => (true, patch),
+            _ => { return Err(error::SyntaxError); }
+        };
+        return Ok(Box::new(JPatchCmd {
+            arg_key: arg_key.to_owned(),
+            arg_patch: arg_patch.to_string(),
+            arg_merge,
+        }));
+    } else if cmd.starts_with("jpath") && cmd != "jpath" {
+        // JPATHSET key $.todos[?(@.completed == false)].completed true
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_selector = itr.next().unwrap_or(&empty_string);
+        if arg_selector.is_empty() { return Err(error::SyntaxError); }
+
+        let args: Vec<&String> = itr.collect();
+        let arg_key = arg_key.to_owned();
+        let arg_selector = arg_selector.to_owned();
+        let parse_i64 = |s: &String| s.parse::<i64>().map_err(|_| error::SyntaxError);
+
+        if cmd == "jpathset" || cmd == "jpathstrappend" {
+            if args.len() != 1 { return Err(error::SyntaxError); }
+            let arg_value = args[0].to_owned();
+            if cmd == "jpathset" {
+                return Ok(Box::new(JPathSetCmd { arg_key, arg_selector, arg_value }));
+            }
+            return Ok(Box::new(JPathStrAppendCmd { arg_key, arg_selector, arg_value }));
+        } else if cmd == "jpathdel" {
+            if !args.is_empty() { return Err(error::SyntaxError); }
+            return Ok(Box::new(JPathDelCmd { arg_key, arg_selector }));
+        } else if cmd == "jpathincrby" {
+            if args.len() != 1 || !util::is_numeric(args[0]) { return Err(error::SyntaxError); }
+            let arg_increment_value = args[0].parse::<f64>().map_err(|_| error::SyntaxError)?;
+            return Ok(Box::new(JPathIncrByCmd { arg_key, arg_selector, arg_increment_value }));
+        } else if cmd == "jpatharrappend" {
+            if args.is_empty() { return Err(error::SyntaxError); }
+            let arg_values = args.iter().map(|v| v.to_string()).collect();
+            return Ok(Box::new(JPathArrAppendCmd { arg_key, arg_selector, arg_values }));
+        } else if cmd == "jpatharrinsert" {
+            if args.len() < 2 { return Err(error::SyntaxError); }
+            let arg_index = parse_i64(args[0])?;
+            let arg_values = args[1..].iter().map(|v| v.to_string()).collect();
+            return Ok(Box::new(JPathArrInsertCmd { arg_key, arg_selector, arg_index, arg_values }));
+        } else if cmd == "jpatharrpop" {
+            if args.len() > 1 { return Err(error::SyntaxError); }
+            let arg_index = match args.first() {
+                Some(t) => parse_i64(t)?,
+                None => -1
+            };
+            return Ok(Box::new(JPathArrPopCmd { arg_key, arg_selector, arg_index }));
+        } else if cmd == "jpatharrtrim" {
+            if args.len() != 2 { return Err(error::SyntaxError); }
+            let arg_start = parse_i64(args[0])?;
+            let arg_stop = parse_i64(args[1])?;
+            return Ok(Box::new(JPathArrTrimCmd { arg_key, arg_selector, arg_start, arg_stop }));
+        }
+        return Err(error::SyntaxError);
+    } else if cmd == "jindex" {
+        let sub_cmd = itr.next().unwrap_or(&empty_string).to_lowercase();
+        if sub_cmd == "list" {
+            return Ok(Box::new(JIndexListCmd));
+        }
+        let arg_name = itr.next().unwrap_or(&empty_string);
+        if arg_name.is_empty() { return Err(error::SyntaxError); }
+        if sub_cmd == "drop" {
+            return Ok(Box::new(JIndexDropCmd {
+                arg_name: arg_name.to_owned()
+            }));
+        } else if sub_cmd == "info" {
+            return Ok(Box::new(JIndexInfoCmd {
+                arg_name: arg_name.to_owned()
+            }));
+        } else if sub_cmd != "create" {
+            return Err(error::SyntaxError);
+        }
+
+        // JINDEX CREATE idx ON user:* FIELDS $.email [AS email] TEXT $.age NUMERIC
+        if itr.next().map(|t| t.to_lowercase()) != Some("on".to_owned()) { return Err(error::SyntaxError); }
+        let arg_pattern = itr.next().unwrap_or(&empty_string);
+        if arg_pattern.is_empty() { return Err(error::SyntaxError); }
+        if itr.next().map(|t| t.to_lowercase()) != Some("fields".to_owned()) { return Err(error::SyntaxError); }
+        let args: Vec<&String> = itr.collect();
+        let mut fields: Vec<FieldDef> = vec![];
+        let mut i = 0;
+        while i < args.len() {
+            let path = args[i];
+            let mut name = FieldDef::name_from_path(path);
+            i += 1;
+            if i + 1 < args.len() && args[i].to_lowercase() == "as" {
+                name = args[i + 1].to_owned();
+                i += 2;
+            }
+            let field_type = match args.get(i).map(|t| t.to_lowercase()) {
+                Some(ref t) if t == "text" => FieldType::Text,
+                Some(ref t) if t == "numeric" => FieldType::Numeric,
+                Some(ref t) if t == "fulltext" => FieldType::FullText,
+                _ => { return Err(error::SyntaxError); }
+            };
+            i += 1;
+            fields.push(FieldDef { path: path.to_owned(), name, field_type });
+        }
+        if fields.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JIndexCreateCmd {
+            arg_def: IndexDef {
+                name: arg_name.to_owned(),
+                pattern: arg_pattern.to_owned(),
+                fields,
+            }
+        }));
+    } else if cmd == "jquery" {
+        let arg_index = itr.next().unwrap_or(&empty_string);
+        if arg_index.is_empty() { return Err(error::SyntaxError); }
+        let arg_query = match itr.next() {
+            Some(t) => t,
+            None => { return Err(error::SyntaxError); }
+        };
+        let mut arg_offset = 0;
+        let mut arg_count = 10;
+        let mut arg_sort_by: Option<(String, ArgOrder)> = None;
+        let mut arg_return: Vec<String> = vec![];
+
+        let args: Vec<&String> = itr.collect();
+        let mut i = 0;
+        while i < args.len() {
+            let option = args[i].to_lowercase();
+            if option == "limit" && i + 2 < args.len() {
+                arg_offset = args[i + 1].parse::<usize>().map_err(|_| error::SyntaxError)?;
+                arg_count = args[i + 2].parse::<usize>().map_err(|_| error::SyntaxError)?;
+                i += 3;
+            } else if option == "sortby" && i + 1 < args.len() {
+                let mut arg_order = ArgOrder::ASC;
+                let field = args[i + 1].to_owned();
+                i += 2;
+                if let Some(order) = args.get(i) {
+                    let order = order.to_lowercase();
+                    if order == "asc" || order == "desc" {
+                        check_validate_arg_order(order, &mut arg_order)?;
+                        i += 1;
+                    }
+                }
+                arg_sort_by = Some((field, arg_order));
+            } else if option == "return" && i + 1 < args.len() {
+                let count = args[i + 1].parse::<usize>().map_err(|_| error::SyntaxError)?;
+                if count == 0 || i + 2 + count > args.len() { return Err(error::SyntaxError); }
+                arg_return = args[i + 2..i + 2 + count].iter().map(|p| p.to_string()).collect();
+                i += 2 + count;
+            } else {
+                return Err(error::SyntaxError);
+            }
+        }
+        return Ok(Box::new(JQueryCmd {
+            arg_index: arg_index.to_owned(),
+            arg_query: arg_query.to_owned(),
+            arg_offset,
+            arg_count,
+            arg_sort_by,
+            arg_return,
+        }));
+    } else if cmd == "jsearch" {
+        // JSEARCH idx "wireless (headphone | earbud*)" [LIMIT 0 10]
+        let arg_index = itr.next().unwrap_or(&empty_string);
+        if arg_index.is_empty() { return Err(error::SyntaxError); }
+        let arg_query = match itr.next() {
+            Some(t) => t,
+            None => { return Err(error::SyntaxError); }
+        };
+        let mut arg_offset = 0;
+        let mut arg_count = 10;
+        let args: Vec<&String> = itr.collect();
+        if !args.is_empty() {
+            if args.len() != 3 || args[0].to_lowercase() != "limit" { return Err(error::SyntaxError); }
+            arg_offset = args[1].parse::<usize>().map_err(|_| error::SyntaxError)?;
+            arg_count = args[2].parse::<usize>().map_err(|_| error::SyntaxError)?;
+        }
+        return Ok(Box::new(JSearchCmd {
+            arg_index: arg_index.to_owned(),
+            arg_query: arg_query.to_owned(),
+            arg_offset,
+            arg_count,
+        }));
+    } else if cmd == "jaggregate" {
+        // JAGGREGATE user:* FILTER "@.age >= 18" GROUPBY 1 $.country REDUCE COUNT AS users SORTBY $.users DESC LIMIT 0 10
+        let args: Vec<&String> = itr.collect();
+        if args.is_empty() { return Err(error::SyntaxError); }
+        let (source, mut i) = if args[0].to_lowercase() == "index" && args.len() > 1 {
+            if args.len() > 3 && args[2].to_lowercase() == "query" {
+                (Source::Index(args[1].to_owned(), args[3].to_owned()), 4)
+            } else {
+                (Source::Index(args[1].to_owned(), String::new()), 2)
+            }
+        } else {
+            (Source::Pattern(args[0].to_owned()), 1)
+        };
+        let fields = |paths: &[&String]| -> Vec<(String, String)> {
+            paths.iter().map(|p| (FieldDef::name_from_path(p), p.to_string())).collect()
+        };
+        let mut stages = vec![];
+        while i < args.len() {
+            let stage = args[i].to_lowercase();
+            if stage == "filter" && i + 1 < args.len() {
+                stages.push(Stage::Filter(format!("$[?({})]", args[i + 1])));
+                i += 2;
+            } else if (stage == "project" || stage == "groupby") && i + 1 < args.len() {
+                let count = args[i + 1].parse::<usize>().map_err(|_| error::SyntaxError)?;
+                let end = (i + 2).checked_add(count).ok_or(error::SyntaxError)?;
+                if end > args.len() || (stage == "project" && count == 0) { return Err(error::SyntaxError); }
+                let paths = fields(&args[i + 2..end]);
+                i = end;
+                if stage == "project" {
+                    stages.push(Stage::Project(paths));
+                    continue;
+                }
+                let mut reducers = vec![];
+                while i + 1 < args.len() && args[i].to_lowercase() == "reduce" {
+                    let function = match args[i + 1].to_lowercase().as_str() {
+                        "count" => ReduceFn::Count,
+                        "sum" => ReduceFn::Sum,
+                        "avg" => ReduceFn::Avg,
+                        "min" => ReduceFn::Min,
+                        "max" => ReduceFn::Max,
+                        _ => { return Err(error::SyntaxError); }
+                    };
+                    i += 2;
+                    let path = if function == ReduceFn::Count {
+                        None
+                    } else {
+                        let path = args.get(i).ok_or(error::SyntaxError)?.to_string();
+                        i += 1;
+                        Some(path)
+                    };
+                    let name = if i + 1 < args.len() && args[i].to_lowercase() == "as" {
+                        i += 2;
+                        args[i - 1].to_owned()
+                    } else {
+                        match &path {
+                            Some(p) => format!("{}_{}", function.name(), FieldDef::name_from_path(p)),
+                            None => function.name().to_owned()
+                        }
+                    };
+                    reducers.push(Reducer { function, path, name });
+                }
+                stages.push(Stage::GroupBy(paths, reducers));
+            } else if stage == "sortby" && i + 1 < args.len() {
+                let path = args[i + 1].to_owned();
+                i += 2;
+                let descending = match args.get(i).map(|o| o.to_lowercase()) {
+                    Some(ref o) if o == "desc" => { i += 1; true }
+                    Some(ref o) if o == "asc" => { i += 1; false }
+                    _ => false
+                };
+                stages.push(Stage::SortBy(path, descending));
+            } else if stage == "limit" && i + 2 < args.len() {
+                let offset = args[i + 1].parse::<usize>().map_err(|_| error::SyntaxError)?;
+                let count = args[i + 2].parse::<usize>().map_err(|_| error::SyntaxError)?;
+                stages.push(Stage::Limit(offset, count));
+                i += 3;
+            } else {
+                return Err(error::SyntaxError);
+            }
+        }
+        return Ok(Box::new(JAggregateCmd {
+            arg_pipeline: Pipeline { source, stages }
+        }));
     }
 
     Err(error::SyntaxError)
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+                           NoClientAuth, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+use crate::config::TlsConf;
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    match File::open(path) {
+        Ok(f) => Ok(BufReader::new(f)),
+        Err(e) => Err(format!("Can not open {}: {}", path, e))
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    match certs(&mut open(path)?) {
+        Ok(c) if !c.is_empty() => Ok(c),
+        _ => Err(format!("No certificate found in {}", path))
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    // PKCS#8 as written by `openssl genpkey`, then the older RSA format
+    if let Ok(mut keys) = pkcs8_private_keys(&mut open(path)?) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    match rsa_private_keys(&mut open(path)?) {
+        Ok(mut keys) if !keys.is_empty() => Ok(keys.remove(0)),
+        _ => Err(format!("No private key found in {}", path))
+    }
+}
+
+fn load_root_store(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut open(path)?) {
+        Ok((valid, _)) if valid > 0 => Ok(roots),
+        _ => Err(format!("No CA certificate found in {}", path))
+    }
+}
+
+/// Builds the acceptor for the TLS listeners from the `tls` config section.
+///
+/// Client certificates are verified against `ca_cert_file`, `auth_clients` decides whether
+/// they are required (`yes`, the default when a CA is set), accepted (`optional`) or ignored (`no`).
+pub fn acceptor(conf: &TlsConf) -> Result<TlsAcceptor, String> {
+    let cert_file = conf.cert_file.as_ref().ok_or("tls.cert_file is required to enable TLS")?;
+    let key_file = conf.key_file.as_ref().ok_or("tls.key_file is required to enable TLS")?;
+
+    let default_auth = if conf.ca_cert_file.is_some() { "yes" } else { "no" };
+    let auth_clients = conf.auth_clients.as_ref().map(|a| a.as_str()).unwrap_or(default_auth);
+    let verifier = match (auth_clients, &conf.ca_cert_file) {
+        ("no", _) => NoClientAuth::new(),
+        ("yes", Some(ca)) => AllowAnyAuthenticatedClient::new(load_root_store(ca)?),
+        ("optional", Some(ca)) => AllowAnyAnonymousOrAuthenticatedClient::new(load_root_store(ca)?),
+        (_, None) => { return Err("tls.ca_cert_file is required to verify client certificates".to_owned()); }
+        (a, _) => { return Err(format!("invalid tls.auth_clients '{}', expected yes, no or optional", a)); }
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    if let Err(e) = config.set_single_cert(load_certs(cert_file)?, load_private_key(key_file)?) {
+        return Err(format!("Invalid TLS certificate or key: {}", e));
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::webpki::DNSNameRef;
+
+    fn test_file(name: &str) -> String {
+        format!("{}/assets/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn test_conf(auth_clients: &str) -> TlsConf {
+        TlsConf {
+            port: 0,
+            cert_file: Some(test_file("server.crt")),
+            key_file: Some(test_file("server.key")),
+            ca_cert_file: Some(test_file("ca.crt")),
+            auth_clients: Some(auth_clients.to_owned()),
+        }
+    }
+
+    /// Serves one connection with `acceptor` and returns what the client read back
+    async fn handshake(acceptor: TlsAcceptor, client_cert: bool) -> std::io::Result<Vec<u8>> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(socket).await {
+                let _ = stream.write_all(b"+PONG\r\n").await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        let mut config = ClientConfig::new();
+        config.root_store = load_root_store(&test_file("ca.crt")).unwrap();
+        if client_cert {
+            config.set_single_client_cert(load_certs(&test_file("client.crt")).unwrap(),
+                                          load_private_key(&test_file("client.key")).unwrap()).unwrap();
+        }
+        let connector = TlsConnector::from(Arc::new(config));
+        let socket = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), socket).await?;
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let reply = handshake(acceptor(&test_conf("yes")).unwrap(), true).await.unwrap();
+        assert_eq!(reply, b"+PONG\r\n".to_vec());
+
+        let rejected = handshake(acceptor(&test_conf("yes")).unwrap(), false).await;
+        assert!(rejected.map(|r| r.is_empty()).unwrap_or(true));
+
+        let reply = handshake(acceptor(&test_conf("optional")).unwrap(), false).await.unwrap();
+        assert_eq!(reply, b"+PONG\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_acceptor_requires_ca_for_client_auth() {
+        let mut conf = test_conf("yes");
+        conf.ca_cert_file = None;
+        assert!(acceptor(&conf).is_err());
+        conf.auth_clients = None;
+        assert!(acceptor(&conf).is_ok());
+    }
+}
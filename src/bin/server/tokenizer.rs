@@ -84,6 +84,35 @@ pub fn generate_token_from_frame(frame : Frame) -> Vec<String> {
 }
 
 
+/// Returns the name of the command carried by a request frame without consuming it
+pub fn command_name_from_frame(frame: &Frame) -> String {
+    arg_from_frame(frame, 0)
+}
+
+/// Returns the argument at `index` of a request frame, empty when it has none
+pub fn arg_from_frame(frame: &Frame, index: usize) -> String {
+    let req = match frame {
+        Frame::Array(a) => {
+            a
+        }
+        _ => {
+            return String::new();
+        }
+    };
+
+    return match req.get(index) {
+        Some(Frame::SimpleString(s)) => {
+            s.to_owned()
+        }
+        Some(Frame::BulkString(s)) => {
+            String::from_utf8(s.to_owned()).unwrap_or("".to_owned())
+        }
+        _ => {
+            String::new()
+        }
+    };
+}
+
 pub fn generate_tokens(cmd: &[u8]) -> Vec<String> {
     parser::parse_raw_cmd(cmd)
 }
\ No newline at end of file